@@ -0,0 +1,355 @@
+// Buses routing guest port I/O and MMIO accesses to emulated devices.
+//
+// Devices claim non-overlapping address ranges and see accesses as an
+// offset into their range. Every vCPU thread dispatches its exits through
+// the same bus, so devices sit behind a mutex.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::layout::MemoryLayout;
+
+// An emulated device on a bus. `offset` is relative to the start of the
+// range the device was inserted at and `data` is as wide as the access.
+pub trait BusDevice: Send {
+    fn read(&mut self, offset: u64, data: &mut [u8]);
+    fn write(&mut self, offset: u64, data: &[u8]);
+    // Go back to the power-on state when the VM is rebooted
+    fn reset(&mut self) {}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BusError {
+    // The range is empty or wraps around the address space
+    InvalidRange { base: u64, len: u64 },
+    // The range overlaps one that is already taken
+    Overlap { base: u64, len: u64 },
+    // The range overlaps guest RAM, where KVM never exits for MMIO
+    OverlapsRam { base: u64, len: u64 },
+    // No device was inserted at this base
+    NotFound(u64),
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BusError::InvalidRange { base, len } => {
+                write!(f, "Invalid bus range at 0x{:x}, length 0x{:x}", base, len)
+            }
+            BusError::Overlap { base, len } => write!(
+                f,
+                "Bus range at 0x{:x}, length 0x{:x} overlaps another device",
+                base, len
+            ),
+            BusError::OverlapsRam { base, len } => write!(
+                f,
+                "Bus range at 0x{:x}, length 0x{:x} overlaps guest RAM",
+                base, len
+            ),
+            BusError::NotFound(base) => write!(f, "No device at 0x{:x}", base),
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+struct BusEntry {
+    len: u64,
+    device: Arc<Mutex<dyn BusDevice>>,
+}
+
+// Address ranges mapped to devices, keyed by the start of each range
+#[derive(Default)]
+struct Bus {
+    devices: RwLock<BTreeMap<u64, BusEntry>>,
+}
+
+impl Bus {
+    fn insert(
+        &self,
+        device: Arc<Mutex<dyn BusDevice>>,
+        base: u64,
+        len: u64,
+    ) -> Result<(), BusError> {
+        let end = match base.checked_add(len) {
+            Some(end) if len > 0 => end,
+            _ => return Err(BusError::InvalidRange { base, len }),
+        };
+        let mut devices = self.devices.write().unwrap();
+        // Only the closest range starting below the end can overlap
+        if let Some((other_base, other)) = devices.range(..end).next_back() {
+            if other_base + other.len > base {
+                return Err(BusError::Overlap { base, len });
+            }
+        }
+        devices.insert(base, BusEntry { len, device });
+        Ok(())
+    }
+
+    fn remove(&self, base: u64) -> Result<Arc<Mutex<dyn BusDevice>>, BusError> {
+        self.devices
+            .write()
+            .unwrap()
+            .remove(&base)
+            .map(|entry| entry.device)
+            .ok_or(BusError::NotFound(base))
+    }
+
+    // The device covering all of [addr, addr + len) and the offset of `addr`
+    // into its range. Accesses running past the end of a device hit nothing.
+    fn resolve(&self, addr: u64, len: usize) -> Option<(Arc<Mutex<dyn BusDevice>>, u64)> {
+        let devices = self.devices.read().unwrap();
+        let (base, entry) = devices.range(..=addr).next_back()?;
+        let offset = addr - base;
+        let end = offset.checked_add(len as u64)?;
+        if end <= entry.len {
+            Some((entry.device.clone(), offset))
+        } else {
+            None
+        }
+    }
+
+    fn read(&self, addr: u64, data: &mut [u8]) -> bool {
+        match self.resolve(addr, data.len()) {
+            Some((device, offset)) => {
+                device.lock().unwrap().read(offset, data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn write(&self, addr: u64, data: &[u8]) -> bool {
+        match self.resolve(addr, data.len()) {
+            Some((device, offset)) => {
+                device.lock().unwrap().write(offset, data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn reset(&self) {
+        for entry in self.devices.read().unwrap().values() {
+            entry.device.lock().unwrap().reset();
+        }
+    }
+}
+
+// The x86 I/O port space, 0 to 0xffff
+#[derive(Default)]
+pub struct PioBus {
+    bus: Bus,
+}
+
+impl PioBus {
+    pub fn new() -> Self {
+        PioBus::default()
+    }
+
+    // Give `device` the ports [base, base + len)
+    pub fn insert(
+        &self,
+        device: Arc<Mutex<dyn BusDevice>>,
+        base: u16,
+        len: u16,
+    ) -> Result<(), BusError> {
+        if base as u32 + len as u32 > 0x1_0000 {
+            return Err(BusError::InvalidRange {
+                base: base as u64,
+                len: len as u64,
+            });
+        }
+        self.bus.insert(device, base as u64, len as u64)
+    }
+
+    pub fn remove(&self, base: u16) -> Result<Arc<Mutex<dyn BusDevice>>, BusError> {
+        self.bus.remove(base as u64)
+    }
+
+    // Handle an IN; false if no device claims the port
+    pub fn read(&self, port: u16, data: &mut [u8]) -> bool {
+        self.bus.read(port as u64, data)
+    }
+
+    // Handle an OUT; false if no device claims the port
+    pub fn write(&self, port: u16, data: &[u8]) -> bool {
+        self.bus.write(port as u64, data)
+    }
+
+    // Reset every device on the bus
+    pub fn reset(&self) {
+        self.bus.reset()
+    }
+}
+
+// Guest physical address space outside RAM
+pub struct MmioBus {
+    bus: Bus,
+    // Guest RAM as [start, end) ranges, which devices must stay out of
+    ram: Vec<(u64, u64)>,
+}
+
+impl MmioBus {
+    pub fn new(layout: &MemoryLayout) -> Self {
+        let ram = layout
+            .ram_regions()
+            .iter()
+            .map(|&(base, size)| (base, base + size as u64))
+            .collect();
+        MmioBus {
+            bus: Bus::default(),
+            ram,
+        }
+    }
+
+    // Give `device` the addresses [base, base + len), which must not
+    // overlap guest RAM
+    pub fn insert(
+        &self,
+        device: Arc<Mutex<dyn BusDevice>>,
+        base: u64,
+        len: u64,
+    ) -> Result<(), BusError> {
+        let end = base.saturating_add(len);
+        if self
+            .ram
+            .iter()
+            .any(|&(ram_start, ram_end)| base < ram_end && end > ram_start)
+        {
+            return Err(BusError::OverlapsRam { base, len });
+        }
+        self.bus.insert(device, base, len)
+    }
+
+    pub fn remove(&self, base: u64) -> Result<Arc<Mutex<dyn BusDevice>>, BusError> {
+        self.bus.remove(base)
+    }
+
+    // Handle a load; false if no device claims the address
+    pub fn read(&self, addr: u64, data: &mut [u8]) -> bool {
+        self.bus.read(addr, data)
+    }
+
+    // Handle a store; false if no device claims the address
+    pub fn write(&self, addr: u64, data: &[u8]) -> bool {
+        self.bus.write(addr, data)
+    }
+
+    // Reset every device on the bus
+    pub fn reset(&self) {
+        self.bus.reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::{MemoryLayoutBuilder, MMIO_GAP_START};
+
+    // Remembers the last access and reads back a fixed pattern
+    #[derive(Default)]
+    struct TestDevice {
+        last: Option<(u64, Vec<u8>)>,
+    }
+
+    impl BusDevice for TestDevice {
+        fn read(&mut self, offset: u64, data: &mut [u8]) {
+            data.fill(0xa5);
+            self.last = Some((offset, Vec::new()));
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8]) {
+            self.last = Some((offset, data.to_vec()));
+        }
+    }
+
+    fn device() -> Arc<Mutex<TestDevice>> {
+        Arc::new(Mutex::new(TestDevice::default()))
+    }
+
+    #[test]
+    fn insert_rejects_overlap() {
+        let bus = PioBus::new();
+        bus.insert(device(), 0x10, 0x10).unwrap();
+        // Overlapping the start, the end, contained and containing
+        for (base, len) in [(0x8, 0x9), (0x1f, 0x2), (0x14, 0x4), (0x0, 0x40)] {
+            assert_eq!(
+                bus.insert(device(), base, len),
+                Err(BusError::Overlap {
+                    base: base as u64,
+                    len: len as u64
+                })
+            );
+        }
+        // Touching neighbours on both sides is fine
+        bus.insert(device(), 0x0, 0x10).unwrap();
+        bus.insert(device(), 0x20, 0x10).unwrap();
+    }
+
+    #[test]
+    fn insert_rejects_bad_ranges() {
+        let bus = PioBus::new();
+        assert!(bus.insert(device(), 0x10, 0).is_err());
+        assert!(bus.insert(device(), 0xfff0, 0x11).is_err());
+        bus.insert(device(), 0xfff0, 0x10).unwrap();
+    }
+
+    #[test]
+    fn mmio_insert_rejects_ram() {
+        let layout = MemoryLayoutBuilder::new(MMIO_GAP_START as usize + 0x1000_0000)
+            .build()
+            .unwrap();
+        let bus = MmioBus::new(&layout);
+        // Straddling the start of the gap, inside high RAM, past the top
+        assert!(matches!(
+            bus.insert(device(), MMIO_GAP_START - 0x1000, 0x2000),
+            Err(BusError::OverlapsRam { .. })
+        ));
+        assert!(matches!(
+            bus.insert(device(), layout.ram_end() - 0x1000, 0x1000),
+            Err(BusError::OverlapsRam { .. })
+        ));
+        bus.insert(device(), MMIO_GAP_START, 0x1000).unwrap();
+        bus.insert(device(), layout.ram_end(), 0x1000).unwrap();
+    }
+
+    #[test]
+    fn access_at_offset() {
+        let bus = PioBus::new();
+        let dev = device();
+        bus.insert(dev.clone(), 0x3f8, 8).unwrap();
+        assert!(bus.write(0x3fb, &[0x80]));
+        assert_eq!(dev.lock().unwrap().last, Some((3, vec![0x80])));
+        let mut data = [0u8; 2];
+        assert!(bus.read(0x3fe, &mut data));
+        assert_eq!(data, [0xa5; 2]);
+        assert!(!bus.read(0x3f7, &mut data));
+    }
+
+    #[test]
+    fn access_spanning_device_end_misses() {
+        let bus = PioBus::new();
+        let dev = device();
+        bus.insert(dev.clone(), 0x60, 4).unwrap();
+        bus.insert(device(), 0x64, 4).unwrap();
+        let mut data = [0u8; 4];
+        assert!(!bus.read(0x62, &mut data));
+        assert!(!bus.write(0x63, &[1, 2]));
+        assert_eq!(dev.lock().unwrap().last, None);
+        // Up to the last byte is fine
+        assert!(bus.write(0x62, &[1, 2]));
+        assert_eq!(dev.lock().unwrap().last, Some((2, vec![1, 2])));
+    }
+
+    #[test]
+    fn remove_frees_range() {
+        let bus = PioBus::new();
+        bus.insert(device(), 0x70, 2).unwrap();
+        assert!(bus.remove(0x71).is_err());
+        bus.remove(0x70).unwrap();
+        assert!(!bus.write(0x70, &[0]));
+        bus.insert(device(), 0x70, 2).unwrap();
+    }
+}
@@ -0,0 +1,233 @@
+use std::path::PathBuf;
+
+use crate::devices::virtio::block::SECTOR_SIZE;
+use crate::irq::IrqChipMode;
+use crate::layout::PAGE_SIZE;
+use crate::loader::{CMDLINE_MAX_SIZE, DEFAULT_CMDLINE};
+use crate::memory::MemoryBacking;
+use crate::mptable::{MPTABLE_MAX_SIZE, MPTABLE_START};
+use crate::regs::{BootMode, BOOT_AREAS};
+
+// Upper bound on vCPUs, limited by the space reserved for the MP table
+pub const MAX_VCPUS: u8 = 32;
+// Guests need at least the first MiB: real-mode area, MP table, BIOS area
+pub const MIN_MEM_SIZE: usize = 1 << 20;
+
+// Guest physical address test payloads are loaded at by default
+pub const DEFAULT_PAYLOAD_ADDR: u64 = 0x1000;
+
+// Raw code run instead of a kernel, e.g. to exercise exit handling
+#[derive(Clone, Debug)]
+pub struct Payload {
+    // Machine code for `boot_mode`, entered at its first byte
+    pub code: Vec<u8>,
+    pub load_addr: u64,
+    pub boot_mode: BootMode,
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Payload {
+            // hlt
+            code: vec![0xf4],
+            load_addr: DEFAULT_PAYLOAD_ADDR,
+            boot_mode: BootMode::default(),
+        }
+    }
+}
+
+// Where the guest's serial console output goes
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum SerialOutput {
+    #[default]
+    Stdout,
+    // Appended to, created if missing
+    File(PathBuf),
+}
+
+#[derive(Clone, Debug)]
+pub struct SerialConfig {
+    pub output: SerialOutput,
+    // Forward the VMM's stdin to the guest
+    pub stdin: bool,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        SerialConfig {
+            output: SerialOutput::default(),
+            stdin: true,
+        }
+    }
+}
+
+// A virtio-blk disk backed by a raw image file
+#[derive(Clone, Debug)]
+pub struct BlockConfig {
+    pub path: PathBuf,
+    // Open the file read-only and fail the guest's writes
+    pub read_only: bool,
+    // Logical block size the guest aligns its I/O to: a power of two from
+    // 512 bytes to a page
+    pub block_size: u32,
+    // Bypass the host page cache with O_DIRECT. The block size must be a
+    // multiple of the host's then.
+    pub direct: bool,
+}
+
+impl BlockConfig {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        BlockConfig {
+            path: path.into(),
+            read_only: false,
+            block_size: SECTOR_SIZE as u32,
+            direct: false,
+        }
+    }
+}
+
+// Static configuration for a VM
+#[derive(Clone, Debug)]
+pub struct VmConfig {
+    // Number of vCPUs. vCPU 0 is the bootstrap processor (BSP), the others
+    // are application processors (APs) that wait for INIT/SIPI.
+    pub vcpu_count: u8,
+    // Size of guest RAM in bytes. RAM that would overlap the 32-bit MMIO
+    // gap is placed above 4 GiB.
+    pub mem_size: usize,
+    // Host memory behind guest RAM: anonymous, memfd or file, optionally
+    // huge pages, prefaulted or locked
+    pub memory_backing: MemoryBacking,
+    // Interrupt controller emulation. With any irqchip, HLT no longer ends
+    // the run: the vCPU sleeps in KVM until an interrupt arrives.
+    pub irqchip: IrqChipMode,
+    // Linux bzImage or ELF kernel to boot. Without one the VM runs
+    // `payload` instead.
+    pub kernel: Option<PathBuf>,
+    // Initial RAM disk loaded next to the kernel
+    pub initrd: Option<PathBuf>,
+    // Kernel command line
+    pub cmdline: String,
+    pub payload: Payload,
+    // 16550A UART on COM1
+    pub serial: SerialConfig,
+    // virtio-blk disks, attached over virtio-mmio in this order
+    pub block_devices: Vec<BlockConfig>,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            vcpu_count: 1,
+            mem_size: 16 << 20,
+            memory_backing: MemoryBacking::default(),
+            irqchip: IrqChipMode::default(),
+            kernel: None,
+            initrd: None,
+            cmdline: DEFAULT_CMDLINE.to_string(),
+            payload: Payload::default(),
+            serial: SerialConfig::default(),
+            block_devices: Vec::new(),
+        }
+    }
+}
+
+impl VmConfig {
+    // The irqchip mode with Auto resolved
+    pub fn irqchip_mode(&self) -> IrqChipMode {
+        match self.irqchip {
+            IrqChipMode::Auto if self.vcpu_count > 1 || self.kernel.is_some() => {
+                IrqChipMode::Kernel
+            }
+            IrqChipMode::Auto => IrqChipMode::None,
+            mode => mode,
+        }
+    }
+
+    // Check the configuration for values KVM or the guest can't cope with
+    pub fn validate(&self) -> Result<(), String> {
+        if self.vcpu_count == 0 || self.vcpu_count > MAX_VCPUS {
+            return Err(format!(
+                "vcpu_count must be between 1 and {}, got {}",
+                MAX_VCPUS, self.vcpu_count
+            ));
+        }
+        if self.mem_size < MIN_MEM_SIZE {
+            return Err(format!(
+                "mem_size must be at least 0x{:x} bytes, got 0x{:x}",
+                MIN_MEM_SIZE, self.mem_size
+            ));
+        }
+        if self.cmdline.len() >= CMDLINE_MAX_SIZE {
+            return Err(format!(
+                "cmdline must be shorter than {} bytes, got {}",
+                CMDLINE_MAX_SIZE,
+                self.cmdline.len()
+            ));
+        }
+        if self.cmdline.contains('\0') {
+            return Err("cmdline must not contain NUL bytes".to_string());
+        }
+        if self.vcpu_count > 1 && self.irqchip_mode() == IrqChipMode::None {
+            return Err("Starting APs needs an irqchip".to_string());
+        }
+        if !self.block_devices.is_empty() && self.irqchip_mode() == IrqChipMode::None {
+            return Err("virtio devices need an irqchip".to_string());
+        }
+        for block in &self.block_devices {
+            let size = block.block_size as u64;
+            if !size.is_power_of_two() || !(SECTOR_SIZE..=PAGE_SIZE).contains(&size) {
+                return Err(format!(
+                    "block_size of {} must be a power of two between {} and {}, got {}",
+                    block.path.display(),
+                    SECTOR_SIZE,
+                    PAGE_SIZE,
+                    size
+                ));
+            }
+        }
+        if self.initrd.is_some() && self.kernel.is_none() {
+            return Err("An initrd needs a kernel to go with it".to_string());
+        }
+        if self.kernel.is_none() {
+            self.payload.validate()?;
+        }
+        Ok(())
+    }
+}
+
+impl Payload {
+    // The payload must be reachable from its boot mode's segments and not
+    // be in the way of what the VMM sets up for it
+    pub fn validate(&self) -> Result<(), String> {
+        if self.code.is_empty() {
+            return Err("payload code is empty".to_string());
+        }
+        let limit = self.boot_mode.address_limit();
+        let end = self.load_addr.checked_add(self.code.len() as u64);
+        if end.is_none_or(|end| end > limit) {
+            return Err(format!(
+                "payload at 0x{:x} with 0x{:x} bytes doesn't fit below 0x{:x} in {:?} mode",
+                self.load_addr,
+                self.code.len(),
+                limit,
+                self.boot_mode
+            ));
+        }
+        let end = self.load_addr + self.code.len() as u64;
+        let mptable = (
+            "MP table",
+            MPTABLE_START,
+            MPTABLE_START + MPTABLE_MAX_SIZE as u64,
+        );
+        for (what, start, area_end) in BOOT_AREAS.into_iter().chain([mptable]) {
+            if self.load_addr < area_end && start < end {
+                return Err(format!(
+                    "payload at [0x{:x}, 0x{:x}) overlaps the {} at [0x{:x}, 0x{:x})",
+                    self.load_addr, end, what, start, area_end
+                ));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+// Userspace IOAPIC (82093AA, version 0x11) for split irqchip mode.
+//
+// With KVM_CAP_SPLIT_IRQCHIP the local APICs stay in KVM and the IOAPIC is
+// emulated here. An interrupt on a pin becomes the MSI its redirection
+// entry describes and is injected with KVM_SIGNAL_MSI. Every unmasked pin
+// is also routed to that MSI on the GSI of the same number: KVM only
+// reports the EOI of a level-triggered vector (KVM_EXIT_IOAPIC_EOI) if it
+// finds it in those routes, and irqfds bound to the GSI go straight to the
+// route.
+
+use std::sync::Arc;
+
+use kvm_bindings::kvm_msi;
+use kvm_ioctls::VmFd;
+
+use crate::bus::BusDevice;
+use crate::gsi::{GsiRouter, IrqRoute, MsiMessage, RoutingError};
+use crate::mptable::{APIC_DEFAULT_PHYS_BASE, IOAPIC_ID, IO_APIC_DEFAULT_PHYS_BASE};
+
+pub const IOAPIC_START: u64 = IO_APIC_DEFAULT_PHYS_BASE as u64;
+pub const IOAPIC_SIZE: u64 = 0x1000;
+pub const IOAPIC_NUM_PINS: usize = 24;
+
+const IOAPIC_VERSION: u32 = 0x11;
+
+// MMIO register offsets
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+// Indirect registers, selected through IOREGSEL
+const IOAPICID: u8 = 0x00;
+const IOAPICVER: u8 = 0x01;
+const IOAPICARB: u8 = 0x02;
+const IOREDTBL: u8 = 0x10;
+
+// Redirection entry fields. Vector, delivery mode and trigger mode sit
+// where the MSI data register wants them.
+const RTE_VECTOR: u64 = 0xff;
+const RTE_DELIVERY_MODE: u64 = 0x700;
+const RTE_DEST_LOGICAL: u64 = 1 << 11;
+const RTE_DELIVERY_STATUS: u64 = 1 << 12;
+const RTE_REMOTE_IRR: u64 = 1 << 14;
+const RTE_LEVEL: u64 = 1 << 15;
+const RTE_MASKED: u64 = 1 << 16;
+const RTE_DEST_SHIFT: u64 = 56;
+const RTE_READ_ONLY: u64 = RTE_DELIVERY_STATUS | RTE_REMOTE_IRR;
+
+const MSI_ADDR_DEST_LOGICAL: u32 = 1 << 2;
+
+// The MSI a redirection entry describes
+fn msi_message(entry: u64) -> MsiMessage {
+    let dest = (entry >> RTE_DEST_SHIFT) as u32;
+    let mut address = APIC_DEFAULT_PHYS_BASE | (dest << 12);
+    if entry & RTE_DEST_LOGICAL != 0 {
+        address |= MSI_ADDR_DEST_LOGICAL;
+    }
+    let data = entry & (RTE_VECTOR | RTE_DELIVERY_MODE | RTE_LEVEL);
+    MsiMessage {
+        address: address as u64,
+        data: data as u32,
+    }
+}
+
+pub struct Ioapic {
+    vm: Arc<VmFd>,
+    router: Arc<GsiRouter>,
+    id: u8,
+    ioregsel: u8,
+    redirection: [u64; IOAPIC_NUM_PINS],
+    // One bit per pin: asserted for level-triggered pins, an undelivered
+    // edge for edge-triggered ones
+    irr: u32,
+}
+
+impl Ioapic {
+    // All pins start out masked, as after a hardware reset, and the id is
+    // the one the MP table announces
+    pub fn new(vm: Arc<VmFd>, router: Arc<GsiRouter>) -> Self {
+        Ioapic {
+            vm,
+            router,
+            id: IOAPIC_ID,
+            ioregsel: 0,
+            redirection: [RTE_MASKED; IOAPIC_NUM_PINS],
+            irr: 0,
+        }
+    }
+
+    // Drive the input of `pin`. Edge-triggered pins fire on the rising
+    // edge, level-triggered ones again after each EOI for as long as they
+    // stay asserted.
+    pub fn service_irq(&mut self, pin: u32, asserted: bool) -> Result<(), kvm_ioctls::Error> {
+        let pin = pin as usize;
+        if pin >= IOAPIC_NUM_PINS {
+            return Err(kvm_ioctls::Error::new(libc::EINVAL));
+        }
+        let bit = 1 << pin;
+        if !asserted {
+            self.irr &= !bit;
+            return Ok(());
+        }
+        if self.redirection[pin] & RTE_LEVEL == 0 && self.irr & bit != 0 {
+            return Ok(());
+        }
+        self.irr |= bit;
+        self.deliver(pin)
+    }
+
+    // The guest EOId `vector` at its local APIC, which frees the
+    // level-triggered pins using it to fire again
+    pub fn end_of_interrupt(&mut self, vector: u8) -> Result<(), kvm_ioctls::Error> {
+        for pin in 0..IOAPIC_NUM_PINS {
+            let entry = self.redirection[pin];
+            if entry & RTE_LEVEL != 0
+                && entry & RTE_REMOTE_IRR != 0
+                && entry & RTE_VECTOR == vector as u64
+            {
+                self.redirection[pin] &= !RTE_REMOTE_IRR;
+                self.deliver(pin)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Send `pin`'s interrupt if one is pending and the guest lets it
+    // through. Edges on masked pins wait for the pin to be unmasked.
+    fn deliver(&mut self, pin: usize) -> Result<(), kvm_ioctls::Error> {
+        let entry = self.redirection[pin];
+        if self.irr & (1 << pin) == 0 || entry & RTE_MASKED != 0 {
+            return Ok(());
+        }
+        if entry & RTE_LEVEL != 0 {
+            // The previous interrupt hasn't been EOId yet
+            if entry & RTE_REMOTE_IRR != 0 {
+                return Ok(());
+            }
+            self.redirection[pin] |= RTE_REMOTE_IRR;
+        } else {
+            self.irr &= !(1 << pin);
+        }
+        let msi = msi_message(entry);
+        self.vm.signal_msi(kvm_msi {
+            address_lo: msi.address as u32,
+            address_hi: (msi.address >> 32) as u32,
+            data: msi.data,
+            ..Default::default()
+        })?;
+        Ok(())
+    }
+
+    // Route `pin`'s GSI to its MSI while unmasked, nowhere while masked
+    fn update_route(&self, pin: usize) -> Result<(), RoutingError> {
+        let entry = self.redirection[pin];
+        let routes = if entry & RTE_MASKED == 0 {
+            vec![IrqRoute::Msi(msi_message(entry))]
+        } else {
+            Vec::new()
+        };
+        self.router.update(|table| table.set(pin as u32, routes))
+    }
+
+    // Pin and half (low or high dword) of the redirection entry register
+    // `reg` belongs to
+    fn redirection_index(reg: u8) -> Option<(usize, bool)> {
+        let index = reg.checked_sub(IOREDTBL)? as usize;
+        if index / 2 >= IOAPIC_NUM_PINS {
+            return None;
+        }
+        Some((index / 2, index % 2 == 1))
+    }
+
+    fn read_register(&self) -> u32 {
+        match self.ioregsel {
+            IOAPICID | IOAPICARB => (self.id as u32) << 24,
+            IOAPICVER => ((IOAPIC_NUM_PINS as u32 - 1) << 16) | IOAPIC_VERSION,
+            reg => match Self::redirection_index(reg) {
+                Some((pin, true)) => (self.redirection[pin] >> 32) as u32,
+                Some((pin, false)) => self.redirection[pin] as u32,
+                None => 0,
+            },
+        }
+    }
+
+    fn write_register(&mut self, value: u32) -> Result<(), kvm_ioctls::Error> {
+        match self.ioregsel {
+            IOAPICID => self.id = (value >> 24) as u8 & 0x0f,
+            reg => {
+                let (pin, high) = match Self::redirection_index(reg) {
+                    Some(index) => index,
+                    // Version and arbitration id are read-only
+                    None => return Ok(()),
+                };
+                let old = self.redirection[pin];
+                let mut entry = if high {
+                    (old & 0xffff_ffff) | ((value as u64) << 32)
+                } else {
+                    (old & !0xffff_ffff) | value as u64
+                };
+                entry = (entry & !RTE_READ_ONLY) | (old & RTE_READ_ONLY);
+                // Only level-triggered pins wait for an EOI
+                if entry & RTE_LEVEL == 0 {
+                    entry &= !RTE_REMOTE_IRR;
+                }
+                self.redirection[pin] = entry;
+                if let Err(e) = self.update_route(pin) {
+                    eprintln!("ioapic: pin {}: {}", pin, e);
+                }
+                self.deliver(pin)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Registers are 32 bits wide; narrower accesses see their low bytes
+impl BusDevice for Ioapic {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let value = match offset {
+            IOREGSEL => self.ioregsel as u32,
+            IOWIN => self.read_register(),
+            _ => 0,
+        };
+        let len = data.len().min(4);
+        data[..len].copy_from_slice(&value.to_le_bytes()[..len]);
+        data[len..].fill(0);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let mut bytes = [0u8; 4];
+        let len = data.len().min(4);
+        bytes[..len].copy_from_slice(&data[..len]);
+        let value = u32::from_le_bytes(bytes);
+        match offset {
+            IOREGSEL => self.ioregsel = value as u8,
+            IOWIN => {
+                if let Err(e) = self.write_register(value) {
+                    eprintln!("ioapic: {}", e);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Pins are masked and their routes dropped
+    fn reset(&mut self) {
+        self.id = IOAPIC_ID;
+        self.ioregsel = 0;
+        self.redirection = [RTE_MASKED; IOAPIC_NUM_PINS];
+        self.irr = 0;
+        for pin in 0..IOAPIC_NUM_PINS {
+            if let Err(e) = self.update_route(pin) {
+                eprintln!("ioapic: pin {}: {}", pin, e);
+            }
+        }
+    }
+}
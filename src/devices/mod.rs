@@ -0,0 +1,18 @@
+// Emulated devices attached to the PIO and MMIO buses
+
+pub mod ioapic;
+pub mod serial;
+pub mod virtio;
+
+use std::io;
+
+// A device's interrupt output, usually an irqfd
+pub trait Interrupt: Send {
+    // Signal the interrupt to the guest
+    fn trigger(&self) -> io::Result<()>;
+
+    // Withdraw a level-triggered interrupt once the guest has serviced it
+    fn deassert(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,508 @@
+// 16550A UART, enough of it for a Linux serial console on COM1.
+//
+// Transmitted bytes go straight to the host output, so the transmitter is
+// always empty. Received bytes queue up in the receive FIFO until the guest
+// reads them. Modem lines are hardwired to "connected" unless loopback is
+// enabled, in which case MCR outputs feed back into MSR inputs and THR
+// into RBR, which Linux uses to probe the port.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use super::Interrupt;
+use crate::bus::BusDevice;
+
+// Legacy COM1 ports and ISA IRQ
+pub const COM1_PORT: u16 = 0x3f8;
+pub const COM1_IRQ: u32 = 4;
+pub const SERIAL_PORT_LEN: u16 = 8;
+
+// Register offsets. With LCR.DLAB set, offsets 0 and 1 are the baud rate
+// divisor latch instead.
+const DATA: u64 = 0; // RBR on read, THR on write
+const IER: u64 = 1;
+const IIR: u64 = 2; // FCR on write
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const IER_RDA: u8 = 0x01;
+const IER_THR_EMPTY: u8 = 0x02;
+const IER_MASK: u8 = 0x0f;
+
+// IIR reports the highest priority pending interrupt
+const IIR_NONE: u8 = 0x01;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_FIFO_ENABLE: u8 = 0x01;
+const FCR_CLEAR_RX: u8 = 0x02;
+
+const LCR_DLAB: u8 = 0x80;
+
+const MCR_DTR: u8 = 0x01;
+const MCR_RTS: u8 = 0x02;
+const MCR_OUT1: u8 = 0x04;
+const MCR_OUT2: u8 = 0x08;
+const MCR_LOOP: u8 = 0x10;
+const MCR_MASK: u8 = 0x1f;
+
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY: u8 = 0x20;
+const LSR_IDLE: u8 = 0x40;
+
+const MSR_CTS: u8 = 0x10;
+const MSR_DSR: u8 = 0x20;
+const MSR_RI: u8 = 0x40;
+const MSR_DCD: u8 = 0x80;
+
+// 115200 baud
+const DEFAULT_BAUD_DIVISOR: u16 = 1;
+
+pub struct Serial {
+    baud_divisor: u16,
+    ier: u8,
+    // Pending interrupts as IIR_* bits, IIR_NONE when there are none
+    interrupts: u8,
+    fifo_enabled: bool,
+    lcr: u8,
+    mcr: u8,
+    lsr: u8,
+    msr: u8,
+    scr: u8,
+    rx: VecDeque<u8>,
+    // Without an interrupt the guest has to poll
+    interrupt: Option<Box<dyn Interrupt>>,
+    out: Box<dyn Write + Send>,
+}
+
+impl Serial {
+    pub fn new(interrupt: Option<Box<dyn Interrupt>>, out: Box<dyn Write + Send>) -> Self {
+        Serial {
+            baud_divisor: DEFAULT_BAUD_DIVISOR,
+            ier: 0,
+            interrupts: 0,
+            fifo_enabled: false,
+            lcr: 0x03, // 8N1
+            mcr: MCR_OUT2,
+            lsr: LSR_THR_EMPTY | LSR_IDLE,
+            msr: MSR_DCD | MSR_DSR | MSR_CTS,
+            scr: 0,
+            rx: VecDeque::new(),
+            interrupt,
+            out,
+        }
+    }
+
+    // Queue bytes received from the host for the guest to read
+    pub fn enqueue(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() || self.is_loopback() {
+            return Ok(());
+        }
+        self.rx.extend(data);
+        self.received()
+    }
+
+    fn is_loopback(&self) -> bool {
+        self.mcr & MCR_LOOP != 0
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+
+    fn iir(&self) -> u8 {
+        let pending = if self.interrupts & IIR_RDA != 0 {
+            IIR_RDA
+        } else if self.interrupts & IIR_THR_EMPTY != 0 {
+            IIR_THR_EMPTY
+        } else {
+            IIR_NONE
+        };
+        if self.fifo_enabled {
+            pending | IIR_FIFO_ENABLED
+        } else {
+            pending
+        }
+    }
+
+    // Latch the interrupt `iir` if the guest enabled it through `ier`
+    fn raise(&mut self, ier: u8, iir: u8) -> io::Result<()> {
+        if self.ier & ier == 0 {
+            return Ok(());
+        }
+        self.interrupts |= iir;
+        match &self.interrupt {
+            Some(interrupt) => interrupt.trigger(),
+            None => Ok(()),
+        }
+    }
+
+    fn received(&mut self) -> io::Result<()> {
+        self.lsr |= LSR_DATA_READY;
+        self.raise(IER_RDA, IIR_RDA)
+    }
+
+    fn transmit(&mut self, byte: u8) -> io::Result<()> {
+        if self.is_loopback() {
+            self.rx.push_back(byte);
+            self.received()?;
+        } else {
+            self.out.write_all(&[byte])?;
+            self.out.flush()?;
+        }
+        // The byte left immediately, so THR is empty again
+        self.raise(IER_THR_EMPTY, IIR_THR_EMPTY)
+    }
+
+    // In loopback mode the modem status inputs mirror the control outputs
+    fn loopback_msr(&self) -> u8 {
+        let mut msr = 0;
+        if self.mcr & MCR_DTR != 0 {
+            msr |= MSR_DSR;
+        }
+        if self.mcr & MCR_RTS != 0 {
+            msr |= MSR_CTS;
+        }
+        if self.mcr & MCR_OUT1 != 0 {
+            msr |= MSR_RI;
+        }
+        if self.mcr & MCR_OUT2 != 0 {
+            msr |= MSR_DCD;
+        }
+        msr
+    }
+
+    fn read_register(&mut self, offset: u64) -> u8 {
+        match offset {
+            DATA if self.dlab() => self.baud_divisor as u8,
+            DATA => {
+                let byte = self.rx.pop_front().unwrap_or(0);
+                if self.rx.is_empty() {
+                    self.lsr &= !LSR_DATA_READY;
+                    self.interrupts &= !IIR_RDA;
+                }
+                byte
+            }
+            IER if self.dlab() => (self.baud_divisor >> 8) as u8,
+            IER => self.ier,
+            IIR => {
+                let iir = self.iir();
+                // Reading IIR acknowledges a THR empty interrupt
+                if iir & !IIR_FIFO_ENABLED == IIR_THR_EMPTY {
+                    self.interrupts &= !IIR_THR_EMPTY;
+                }
+                iir
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => self.lsr,
+            MSR if self.is_loopback() => self.loopback_msr(),
+            MSR => self.msr,
+            SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u8) -> io::Result<()> {
+        match offset {
+            DATA if self.dlab() => {
+                self.baud_divisor = (self.baud_divisor & 0xff00) | value as u16;
+            }
+            DATA => {
+                self.interrupts &= !IIR_THR_EMPTY;
+                self.transmit(value)?;
+            }
+            IER if self.dlab() => {
+                self.baud_divisor = (self.baud_divisor & 0x00ff) | ((value as u16) << 8);
+            }
+            IER => {
+                let enabled = !self.ier & value;
+                self.ier = value & IER_MASK;
+                // The transmitter is always empty, so enabling its interrupt
+                // fires it right away. Linux relies on this to start
+                // sending.
+                if enabled & IER_THR_EMPTY != 0 {
+                    self.raise(IER_THR_EMPTY, IIR_THR_EMPTY)?;
+                }
+                if enabled & IER_RDA != 0 && !self.rx.is_empty() {
+                    self.raise(IER_RDA, IIR_RDA)?;
+                }
+                if self.ier & IER_THR_EMPTY == 0 {
+                    self.interrupts &= !IIR_THR_EMPTY;
+                }
+                if self.ier & IER_RDA == 0 {
+                    self.interrupts &= !IIR_RDA;
+                }
+            }
+            IIR => {
+                self.fifo_enabled = value & FCR_FIFO_ENABLE != 0;
+                if value & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                    self.lsr &= !LSR_DATA_READY;
+                    self.interrupts &= !IIR_RDA;
+                }
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & MCR_MASK,
+            SCR => self.scr = value,
+            // LSR and MSR are read-only
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl BusDevice for Serial {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        if data.len() != 1 {
+            data.fill(0xff);
+            return;
+        }
+        data[0] = self.read_register(offset);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if data.len() != 1 {
+            return;
+        }
+        if let Err(e) = self.write_register(offset, data[0]) {
+            eprintln!("serial: {}", e);
+        }
+    }
+
+    // Registers and the receive FIFO go back to their power-on values; the
+    // host side stays connected
+    fn reset(&mut self) {
+        self.baud_divisor = DEFAULT_BAUD_DIVISOR;
+        self.ier = 0;
+        self.interrupts = 0;
+        self.fifo_enabled = false;
+        self.lcr = 0x03;
+        self.mcr = MCR_OUT2;
+        self.lsr = LSR_THR_EMPTY | LSR_IDLE;
+        self.msr = MSR_DCD | MSR_DSR | MSR_CTS;
+        self.scr = 0;
+        self.rx.clear();
+    }
+}
+
+// Feeds the host's stdin into a Serial from a thread of its own, until
+// stdin reaches EOF or the forwarder is dropped
+pub struct StdinForwarder {
+    stop: EventFd,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StdinForwarder {
+    pub fn spawn(serial: Arc<Mutex<Serial>>) -> io::Result<Self> {
+        let stop = EventFd::new(EFD_NONBLOCK)?;
+        let stop_event = stop.try_clone()?;
+        let thread = thread::Builder::new()
+            .name("serial-stdin".to_string())
+            .spawn(move || {
+                if let Err(e) = forward_stdin(&serial, &stop_event) {
+                    eprintln!("serial: reading stdin failed: {}", e);
+                }
+            })?;
+        Ok(StdinForwarder {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for StdinForwarder {
+    fn drop(&mut self) {
+        let _ = self.stop.write(1);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Pass stdin on to `serial` until EOF or until `stop` is signalled. Polling
+// both keeps a blocking read of stdin from outliving the VM.
+fn forward_stdin(serial: &Mutex<Serial>, stop: &EventFd) -> io::Result<()> {
+    let mut stdin = io::stdin();
+    let mut fds = [stop.as_raw_fd(), stdin.as_raw_fd()].map(|fd| libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    });
+    let mut buf = [0u8; 64];
+    loop {
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if fds[0].revents != 0 {
+            return Ok(());
+        }
+        if fds[1].revents == 0 {
+            continue;
+        }
+        match stdin.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(count) => {
+                if let Err(e) = serial.lock().unwrap().enqueue(&buf[..count]) {
+                    eprintln!("serial: {}", e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Counts how often the serial port raised its interrupt
+    struct TestInterrupt(Arc<AtomicUsize>);
+
+    impl Interrupt for TestInterrupt {
+        fn trigger(&self) -> io::Result<()> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    // Collects everything the guest transmits
+    #[derive(Clone, Default)]
+    struct TestOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for TestOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn serial() -> (Serial, Arc<AtomicUsize>, TestOutput) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let out = TestOutput::default();
+        let serial = Serial::new(
+            Some(Box::new(TestInterrupt(count.clone()))),
+            Box::new(out.clone()),
+        );
+        (serial, count, out)
+    }
+
+    fn read(serial: &mut Serial, offset: u64) -> u8 {
+        let mut data = [0u8];
+        serial.read(offset, &mut data);
+        data[0]
+    }
+
+    #[test]
+    fn thr_write_transmits() {
+        let (mut serial, count, out) = serial();
+        serial.write(DATA, b"h");
+        serial.write(DATA, b"i");
+        assert_eq!(*out.0.lock().unwrap(), b"hi");
+        // The transmitter is always empty again and nothing was enabled
+        assert_eq!(read(&mut serial, LSR), LSR_THR_EMPTY | LSR_IDLE);
+        assert_eq!(read(&mut serial, IIR), IIR_NONE);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn dlab_switches_to_divisor_latch() {
+        let (mut serial, _, out) = serial();
+        serial.write(LCR, &[LCR_DLAB | 0x03]);
+        serial.write(DATA, &[0x0c]);
+        serial.write(IER, &[0x00]);
+        assert_eq!(read(&mut serial, DATA), 0x0c);
+        assert_eq!(read(&mut serial, IER), 0x00);
+        assert_eq!(serial.baud_divisor, 12);
+        serial.write(LCR, &[0x03]);
+        // Back to THR and IER; the divisor writes weren't data
+        assert!(out.0.lock().unwrap().is_empty());
+        serial.write(IER, &[IER_RDA]);
+        assert_eq!(read(&mut serial, IER), IER_RDA);
+        assert_eq!(serial.baud_divisor, 12);
+    }
+
+    #[test]
+    fn thr_empty_interrupt() {
+        let (mut serial, count, _) = serial();
+        // Enabling it fires right away, and reading IIR acknowledges it
+        serial.write(IER, &[IER_THR_EMPTY]);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert_eq!(read(&mut serial, IIR), IIR_THR_EMPTY);
+        assert_eq!(read(&mut serial, IIR), IIR_NONE);
+        // Every transmitted byte empties THR again
+        serial.write(DATA, b"x");
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(read(&mut serial, IIR), IIR_THR_EMPTY);
+        // Disabling it drops a pending one
+        serial.write(DATA, b"y");
+        serial.write(IER, &[0]);
+        assert_eq!(read(&mut serial, IIR), IIR_NONE);
+    }
+
+    #[test]
+    fn rx_interrupt_only_when_enabled() {
+        let (mut serial, count, _) = serial();
+        serial.enqueue(b"ab").unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        assert_eq!(read(&mut serial, LSR) & LSR_DATA_READY, LSR_DATA_READY);
+        // Enabling with data waiting raises it; RDA outranks THR empty
+        serial.write(IER, &[IER_RDA | IER_THR_EMPTY]);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(read(&mut serial, IIR), IIR_RDA);
+        assert_eq!(read(&mut serial, DATA), b'a');
+        assert_eq!(read(&mut serial, IIR), IIR_RDA);
+        assert_eq!(read(&mut serial, DATA), b'b');
+        // Draining the FIFO clears RDA, leaving THR empty
+        assert_eq!(read(&mut serial, LSR) & LSR_DATA_READY, 0);
+        assert_eq!(read(&mut serial, IIR), IIR_THR_EMPTY);
+    }
+
+    #[test]
+    fn loopback_echoes_thr() {
+        let (mut serial, _, out) = serial();
+        serial.write(MCR, &[MCR_LOOP | MCR_DTR | MCR_OUT2]);
+        serial.write(DATA, &[0x55]);
+        assert!(out.0.lock().unwrap().is_empty());
+        assert_eq!(read(&mut serial, DATA), 0x55);
+        assert_eq!(read(&mut serial, MSR), MSR_DSR | MSR_DCD);
+    }
+
+    #[test]
+    fn reset_restores_power_on_state() {
+        let (mut serial, _, _) = serial();
+        serial.write(LCR, &[LCR_DLAB]);
+        serial.write(DATA, &[0x30]);
+        serial.write(LCR, &[0x1b]);
+        serial.write(SCR, &[0x42]);
+        serial.write(IER, &[IER_RDA]);
+        serial.enqueue(b"z").unwrap();
+        serial.reset();
+        assert_eq!(read(&mut serial, LCR), 0x03);
+        assert_eq!(read(&mut serial, SCR), 0);
+        assert_eq!(read(&mut serial, IER), 0);
+        assert_eq!(read(&mut serial, LSR), LSR_THR_EMPTY | LSR_IDLE);
+        assert_eq!(read(&mut serial, IIR), IIR_NONE);
+        assert_eq!(serial.baud_divisor, DEFAULT_BAUD_DIVISOR);
+    }
+}
@@ -0,0 +1,999 @@
+// Virtio block device backed by a raw image file (or a host block device).
+//
+// Requests are served synchronously when the driver notifies the queue,
+// through a page aligned bounce buffer so that the same code works with
+// O_DIRECT. Sectors are always 512 bytes; the block size only tells the
+// guest how to align its I/O.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+
+use super::queue::{self, Descriptor, Queue, QueueError};
+use super::{ActivateError, QueueConfig, VirtioDevice, VirtioInterrupt, VIRTIO_ID_BLOCK};
+use crate::config::BlockConfig;
+use crate::memory::{ByteValued, GuestMemory, GuestMemoryError};
+
+pub const SECTOR_SIZE: u64 = 512;
+
+const QUEUE_SIZE: u16 = 256;
+
+// Feature bits
+const VIRTIO_BLK_F_SEG_MAX: u32 = 2;
+const VIRTIO_BLK_F_RO: u32 = 5;
+const VIRTIO_BLK_F_BLK_SIZE: u32 = 6;
+const VIRTIO_BLK_F_FLUSH: u32 = 9;
+const VIRTIO_BLK_F_DISCARD: u32 = 13;
+const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 14;
+
+// Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+// Status byte at the end of every request
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+// Write zeroes segments may deallocate the range instead
+const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 0x1;
+
+// Length of the GET_ID string, not NUL terminated if it fills it all
+const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+// Segments per discard or write zeroes request
+const MAX_SEGMENTS: u32 = 1;
+
+// Bytes moved per pass through the bounce buffer
+const BOUNCE_BLOCKS: usize = 64;
+const BOUNCE_BLOCK_SIZE: usize = 4096;
+
+// Configuration space. Fields for features the device doesn't offer stay
+// zero.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, Default)]
+struct VirtioBlkConfig {
+    // In 512 byte sectors
+    capacity: u64,
+    size_max: u32,
+    seg_max: u32,
+    cylinders: u16,
+    heads: u8,
+    sectors: u8,
+    blk_size: u32,
+    physical_block_exp: u8,
+    alignment_offset: u8,
+    min_io_size: u16,
+    opt_io_size: u32,
+    writeback: u8,
+    unused0: u8,
+    num_queues: u16,
+    max_discard_sectors: u32,
+    max_discard_seg: u32,
+    discard_sector_alignment: u32,
+    max_write_zeroes_sectors: u32,
+    max_write_zeroes_seg: u32,
+    write_zeroes_may_unmap: u8,
+    unused1: [u8; 3],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct RequestHeader {
+    type_: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+// Payload of discard and write zeroes requests
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct DiscardSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+unsafe impl ByteValued for VirtioBlkConfig {}
+unsafe impl ByteValued for RequestHeader {}
+unsafe impl ByteValued for DiscardSegment {}
+
+const HEADER_SIZE: u64 = std::mem::size_of::<RequestHeader>() as u64;
+const SEGMENT_SIZE: u64 = std::mem::size_of::<DiscardSegment>() as u64;
+
+// Aligned for O_DIRECT, whatever the host's logical block size
+#[repr(C, align(4096))]
+#[derive(Clone, Copy)]
+struct BounceBlock([u8; BOUNCE_BLOCK_SIZE]);
+
+fn bounce_bytes(blocks: &mut [BounceBlock]) -> &mut [u8] {
+    unsafe {
+        std::slice::from_raw_parts_mut(
+            blocks.as_mut_ptr() as *mut u8,
+            blocks.len() * BOUNCE_BLOCK_SIZE,
+        )
+    }
+}
+
+type GuestResult<T> = std::result::Result<T, GuestMemoryError>;
+
+// Run `f` for each piece of the `len` bytes that start `offset` bytes into
+// the buffers of `descs`, with the piece's guest address, its offset into
+// the transfer and its length. The buffers must be long enough.
+fn for_each_piece<F>(descs: &[Descriptor], offset: u64, len: usize, mut f: F) -> GuestResult<()>
+where
+    F: FnMut(u64, usize, usize) -> GuestResult<()>,
+{
+    let mut skip = offset;
+    let mut done = 0;
+    for desc in descs {
+        if done == len {
+            break;
+        }
+        let desc_len = desc.len as u64;
+        if skip >= desc_len {
+            skip -= desc_len;
+            continue;
+        }
+        let count = ((desc_len - skip) as usize).min(len - done);
+        f(desc.addr + skip, done, count)?;
+        done += count;
+        skip = 0;
+    }
+    Ok(())
+}
+
+fn read_buffers(
+    mem: &GuestMemory,
+    descs: &[Descriptor],
+    offset: u64,
+    buf: &mut [u8],
+) -> GuestResult<()> {
+    for_each_piece(descs, offset, buf.len(), |addr, done, count| {
+        mem.read_slice(&mut buf[done..done + count], addr)
+    })
+}
+
+fn write_buffers(
+    mem: &GuestMemory,
+    descs: &[Descriptor],
+    offset: u64,
+    buf: &[u8],
+) -> GuestResult<()> {
+    for_each_piece(descs, offset, buf.len(), |addr, done, count| {
+        mem.write_slice(&buf[done..done + count], addr)
+    })
+}
+
+fn total_len(descs: &[Descriptor]) -> u64 {
+    descs.iter().map(|desc| desc.len as u64).sum()
+}
+
+// Why a request failed; all but Unsupported end in VIRTIO_BLK_S_IOERR
+#[derive(Debug)]
+enum RequestError {
+    Unsupported(u32),
+    // Malformed request: missing header, bad lengths or flags
+    Invalid(String),
+    ReadOnly,
+    // Past the end of the disk
+    OutOfRange { sector: u64, len: u64 },
+    Io(io::Error),
+    Memory(GuestMemoryError),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::Unsupported(type_) => write!(f, "Unsupported request type {}", type_),
+            RequestError::Invalid(msg) => write!(f, "Invalid request: {}", msg),
+            RequestError::ReadOnly => write!(f, "Write to a read-only disk"),
+            RequestError::OutOfRange { sector, len } => {
+                write!(
+                    f,
+                    "Access to 0x{:x} bytes at sector {} past the end of the disk",
+                    len, sector
+                )
+            }
+            RequestError::Io(e) => write!(f, "I/O error: {}", e),
+            RequestError::Memory(e) => write!(f, "Failed to access request buffers: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> Self {
+        RequestError::Io(e)
+    }
+}
+
+impl From<GuestMemoryError> for RequestError {
+    fn from(e: GuestMemoryError) -> Self {
+        RequestError::Memory(e)
+    }
+}
+
+type RequestResult<T> = std::result::Result<T, RequestError>;
+
+// The backing file and what requests need to know about it
+struct Disk {
+    file: File,
+    read_only: bool,
+    // In 512 byte sectors
+    capacity: u64,
+    // With O_DIRECT: the block size in bytes, which every access has to be
+    // aligned to
+    direct_align: Option<u64>,
+    id: [u8; VIRTIO_BLK_ID_BYTES],
+    bounce: Vec<BounceBlock>,
+}
+
+impl Disk {
+    // Serve the request in the buffers of a chain and fill in its status.
+    // Returns how many bytes were written to the chain.
+    fn handle(&mut self, mem: &GuestMemory, descs: &[Descriptor]) -> u32 {
+        let split = descs
+            .iter()
+            .position(|desc| desc.writable)
+            .unwrap_or(descs.len());
+        let (readable, writable) = descs.split_at(split);
+        // The status byte is the last writable one
+        let data_in_len = match total_len(writable).checked_sub(1) {
+            Some(len) => len,
+            None => {
+                eprintln!("virtio-blk: request without a status byte");
+                return 0;
+            }
+        };
+
+        let (status, written) = match self.execute(mem, readable, writable, data_in_len) {
+            Ok(written) => (VIRTIO_BLK_S_OK, written),
+            Err(RequestError::Unsupported(_)) => (VIRTIO_BLK_S_UNSUPP, 0),
+            Err(e) => {
+                eprintln!("virtio-blk: {}", e);
+                (VIRTIO_BLK_S_IOERR, 0)
+            }
+        };
+        if let Err(e) = write_buffers(mem, writable, data_in_len, &[status]) {
+            eprintln!("virtio-blk: Failed to write status: {}", e);
+            return 0;
+        }
+        written + 1
+    }
+
+    fn execute(
+        &mut self,
+        mem: &GuestMemory,
+        readable: &[Descriptor],
+        writable: &[Descriptor],
+        data_in_len: u64,
+    ) -> RequestResult<u32> {
+        let data_out_len = total_len(readable)
+            .checked_sub(HEADER_SIZE)
+            .ok_or_else(|| RequestError::Invalid("short header".to_string()))?;
+        let mut header = RequestHeader::default();
+        read_buffers(mem, readable, 0, header.as_mut_slice())?;
+
+        match header.type_ {
+            VIRTIO_BLK_T_IN => {
+                let offset = self.byte_offset(header.sector, data_in_len)?;
+                self.read(mem, writable, offset, data_in_len)?;
+                Ok(data_in_len as u32)
+            }
+            VIRTIO_BLK_T_OUT => {
+                self.check_writable()?;
+                let offset = self.byte_offset(header.sector, data_out_len)?;
+                self.write(mem, readable, offset, data_out_len)?;
+                Ok(0)
+            }
+            VIRTIO_BLK_T_FLUSH => {
+                self.file.sync_data()?;
+                Ok(0)
+            }
+            VIRTIO_BLK_T_GET_ID => {
+                let len = (data_in_len as usize).min(VIRTIO_BLK_ID_BYTES);
+                write_buffers(mem, writable, 0, &self.id[..len])?;
+                Ok(len as u32)
+            }
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                self.check_writable()?;
+                self.discard_or_zero(mem, readable, header.type_, data_out_len)?;
+                Ok(0)
+            }
+            type_ => Err(RequestError::Unsupported(type_)),
+        }
+    }
+
+    fn check_writable(&self) -> RequestResult<()> {
+        if self.read_only {
+            return Err(RequestError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    // File offset of `len` bytes at `sector`, which have to be whole
+    // sectors on the disk, and whole blocks with O_DIRECT
+    fn byte_offset(&self, sector: u64, len: u64) -> RequestResult<u64> {
+        if !len.is_multiple_of(SECTOR_SIZE) {
+            return Err(RequestError::Invalid(format!(
+                "length 0x{:x} is not a whole number of sectors",
+                len
+            )));
+        }
+        let end = sector.checked_add(len / SECTOR_SIZE);
+        if end.is_none_or(|end| end > self.capacity) {
+            return Err(RequestError::OutOfRange { sector, len });
+        }
+        let offset = sector * SECTOR_SIZE;
+        if let Some(align) = self.direct_align {
+            if !offset.is_multiple_of(align) || !len.is_multiple_of(align) {
+                return Err(RequestError::Invalid(format!(
+                    "0x{:x} bytes at sector {} are not whole {} byte blocks",
+                    len, sector, align
+                )));
+            }
+        }
+        Ok(offset)
+    }
+
+    fn read(
+        &mut self,
+        mem: &GuestMemory,
+        descs: &[Descriptor],
+        offset: u64,
+        len: u64,
+    ) -> RequestResult<()> {
+        let bounce = bounce_bytes(&mut self.bounce);
+        let mut done = 0;
+        while done < len {
+            let count = (len - done).min(bounce.len() as u64);
+            let buf = &mut bounce[..count as usize];
+            self.file.read_exact_at(buf, offset + done)?;
+            write_buffers(mem, descs, done, buf)?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    // The data follows the header in the readable buffers
+    fn write(
+        &mut self,
+        mem: &GuestMemory,
+        descs: &[Descriptor],
+        offset: u64,
+        len: u64,
+    ) -> RequestResult<()> {
+        let bounce = bounce_bytes(&mut self.bounce);
+        let mut done = 0;
+        while done < len {
+            let count = (len - done).min(bounce.len() as u64);
+            let buf = &mut bounce[..count as usize];
+            read_buffers(mem, descs, HEADER_SIZE + done, buf)?;
+            self.file.write_all_at(buf, offset + done)?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn discard_or_zero(
+        &mut self,
+        mem: &GuestMemory,
+        descs: &[Descriptor],
+        type_: u32,
+        len: u64,
+    ) -> RequestResult<()> {
+        let count = len / SEGMENT_SIZE;
+        if !len.is_multiple_of(SEGMENT_SIZE) || count == 0 || count > MAX_SEGMENTS as u64 {
+            return Err(RequestError::Invalid(format!(
+                "0x{:x} bytes of segments",
+                len
+            )));
+        }
+        for index in 0..count {
+            let mut segment = DiscardSegment::default();
+            read_buffers(
+                mem,
+                descs,
+                HEADER_SIZE + index * SEGMENT_SIZE,
+                segment.as_mut_slice(),
+            )?;
+            let unmap = segment.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+            if segment.flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0
+                || (type_ == VIRTIO_BLK_T_DISCARD && unmap)
+            {
+                return Err(RequestError::Unsupported(type_));
+            }
+            let len = segment.num_sectors as u64 * SECTOR_SIZE;
+            let offset = self.byte_offset(segment.sector, len)?;
+            if type_ == VIRTIO_BLK_T_DISCARD {
+                self.discard(offset, len)?;
+            } else {
+                self.write_zeroes(offset, len, unmap)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn fallocate(&self, mode: i32, offset: u64, len: u64) -> io::Result<()> {
+        let ret = unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                mode | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Discarding is only a hint, so file systems that can't punch holes
+    // simply keep the data
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        match self.fallocate(libc::FALLOC_FL_PUNCH_HOLE, offset, len) {
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+            result => result,
+        }
+    }
+
+    // Deallocate the range if allowed, zero it in place if the file system
+    // can, and write zeroes as the last resort
+    fn write_zeroes(&mut self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        if unmap
+            && self
+                .fallocate(libc::FALLOC_FL_PUNCH_HOLE, offset, len)
+                .is_ok()
+        {
+            return Ok(());
+        }
+        if self
+            .fallocate(libc::FALLOC_FL_ZERO_RANGE, offset, len)
+            .is_ok()
+        {
+            return Ok(());
+        }
+        let bounce = bounce_bytes(&mut self.bounce);
+        bounce.fill(0);
+        let mut done = 0;
+        while done < len {
+            let count = (len - done).min(bounce.len() as u64);
+            self.file
+                .write_all_at(&bounce[..count as usize], offset + done)?;
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+// What the device holds on to between activation and reset
+struct Activated {
+    mem: Arc<GuestMemory>,
+    queue: Box<dyn Queue>,
+    interrupt: Arc<VirtioInterrupt>,
+}
+
+// Offset alignment O_DIRECT needs for `file`, which the block size has to
+// be a multiple of
+fn direct_io_alignment(file: &File) -> io::Result<u32> {
+    if file.metadata()?.file_type().is_block_device() {
+        let mut size: libc::c_int = 0;
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), libc::BLKSSZGET, &mut size) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(size as u32);
+    }
+
+    let mut stx: libc::statx = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::statx(
+            file.as_raw_fd(),
+            c"".as_ptr(),
+            libc::AT_EMPTY_PATH,
+            libc::STATX_DIOALIGN,
+            &mut stx,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Older kernels don't say, and a sector is as small as it gets
+    if stx.stx_mask & libc::STATX_DIOALIGN == 0 || stx.stx_dio_offset_align == 0 {
+        return Ok(SECTOR_SIZE as u32);
+    }
+    Ok(stx.stx_dio_offset_align)
+}
+
+pub struct Block {
+    disk: Disk,
+    features: u64,
+    config: VirtioBlkConfig,
+    activated: Option<Activated>,
+}
+
+impl Block {
+    // Open the image `config` describes. Sectors past the last whole block
+    // aren't visible to the guest.
+    pub fn new(config: &BlockConfig) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(!config.read_only);
+        if config.direct {
+            options.custom_flags(libc::O_DIRECT);
+        }
+        let mut file = options.open(&config.path)?;
+        if config.direct {
+            let alignment = direct_io_alignment(&file)?;
+            if config.block_size < alignment {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "block size {} is smaller than the {} bytes O_DIRECT needs",
+                        config.block_size, alignment
+                    ),
+                ));
+            }
+        }
+        // Works for block devices too, unlike the metadata's length
+        let size = file.seek(SeekFrom::End(0))?;
+        let block_sectors = config.block_size as u64 / SECTOR_SIZE;
+        let capacity = size / SECTOR_SIZE / block_sectors * block_sectors;
+
+        // Identify the disk by the file it lives in
+        let metadata = file.metadata()?;
+        let serial = format!("{:x}{:x}", metadata.dev(), metadata.ino());
+        let mut id = [0; VIRTIO_BLK_ID_BYTES];
+        let len = serial.len().min(VIRTIO_BLK_ID_BYTES);
+        id[..len].copy_from_slice(&serial.as_bytes()[..len]);
+
+        let mut features =
+            1 << VIRTIO_BLK_F_SEG_MAX | 1 << VIRTIO_BLK_F_BLK_SIZE | 1 << VIRTIO_BLK_F_FLUSH;
+        if config.read_only {
+            features |= 1 << VIRTIO_BLK_F_RO;
+        } else {
+            features |= 1 << VIRTIO_BLK_F_DISCARD | 1 << VIRTIO_BLK_F_WRITE_ZEROES;
+        }
+        let config_space = VirtioBlkConfig {
+            capacity,
+            // Room for the header and the status
+            seg_max: QUEUE_SIZE as u32 - 2,
+            blk_size: config.block_size,
+            max_discard_sectors: u32::MAX,
+            max_discard_seg: MAX_SEGMENTS,
+            discard_sector_alignment: block_sectors as u32,
+            max_write_zeroes_sectors: u32::MAX,
+            max_write_zeroes_seg: MAX_SEGMENTS,
+            write_zeroes_may_unmap: 1,
+            ..Default::default()
+        };
+
+        Ok(Block {
+            disk: Disk {
+                file,
+                read_only: config.read_only,
+                capacity,
+                direct_align: config.direct.then_some(config.block_size as u64),
+                id,
+                bounce: vec![BounceBlock([0; BOUNCE_BLOCK_SIZE]); BOUNCE_BLOCKS],
+            },
+            features,
+            config: config_space,
+            activated: None,
+        })
+    }
+
+    // Serve everything the driver queued, then interrupt it if it wants
+    fn process_queue(&mut self) -> Result<(), QueueError> {
+        let activated = match &mut self.activated {
+            Some(activated) => activated,
+            None => return Ok(()),
+        };
+        let queue = &mut activated.queue;
+        loop {
+            queue.disable_notification()?;
+            while let Some(chain) = queue.pop()? {
+                let len = self.disk.handle(&activated.mem, chain.descriptors());
+                queue.add_used(&chain, len)?;
+            }
+            if !queue.enable_notification()? {
+                break;
+            }
+        }
+        if queue.needs_notification()? {
+            if let Err(e) = activated.interrupt.signal_used_queue() {
+                eprintln!("virtio-blk: {}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Block {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &[QUEUE_SIZE]
+    }
+
+    fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        data.fill(0);
+        if let Some(config) = self.config.as_slice().get(offset as usize..) {
+            let len = config.len().min(data.len());
+            data[..len].copy_from_slice(&config[..len]);
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: Arc<GuestMemory>,
+        features: u64,
+        queues: Vec<QueueConfig>,
+        interrupt: Arc<VirtioInterrupt>,
+    ) -> Result<(), ActivateError> {
+        let config = &queues[0];
+        if !config.ready {
+            return Err(ActivateError::InvalidQueue(0, "not ready".to_string()));
+        }
+        let queue = queue::new_queue(mem.clone(), config, features)
+            .map_err(|e| ActivateError::InvalidQueue(0, e.to_string()))?;
+        self.activated = Some(Activated {
+            mem,
+            queue,
+            interrupt,
+        });
+        Ok(())
+    }
+
+    fn queue_notify(&mut self, index: u16) -> Result<(), QueueError> {
+        if index != 0 {
+            return Ok(());
+        }
+        self.process_queue()
+    }
+
+    fn reset(&mut self) {
+        self.activated = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const SECTORS: u64 = 16;
+    const HEADER: u64 = 0x1000;
+    const DATA: u64 = 0x2000;
+    const STATUS: u64 = 0x8000;
+
+    // Raw image in the temp directory with every byte of sector n set to
+    // n, removed again when dropped
+    struct Image(PathBuf);
+
+    impl Image {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "virtio-blk-test-{}-{}.img",
+                std::process::id(),
+                name
+            ));
+            let data: Vec<u8> = (0..SECTORS)
+                .flat_map(|sector| [sector as u8; SECTOR_SIZE as usize])
+                .collect();
+            std::fs::write(&path, data).unwrap();
+            Image(path)
+        }
+
+        fn sector(&self, sector: u64) -> Vec<u8> {
+            let data = std::fs::read(&self.0).unwrap();
+            let start = (sector * SECTOR_SIZE) as usize;
+            data[start..start + SECTOR_SIZE as usize].to_vec()
+        }
+    }
+
+    impl Drop for Image {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn disk(image: &Image, read_only: bool) -> Disk {
+        let config = BlockConfig {
+            read_only,
+            ..BlockConfig::new(&image.0)
+        };
+        Block::new(&config).unwrap().disk
+    }
+
+    fn memory() -> GuestMemory {
+        GuestMemory::new(&[(0, 0x10000)]).unwrap()
+    }
+
+    // Serve a request with `data_len` bytes of data buffer at DATA, device
+    // writable for `data_in`. Returns the status and the length the device
+    // reports as written.
+    fn request(
+        disk: &mut Disk,
+        mem: &GuestMemory,
+        type_: u32,
+        sector: u64,
+        data_len: u32,
+        data_in: bool,
+    ) -> (u8, u32) {
+        let header = RequestHeader {
+            type_,
+            reserved: 0,
+            sector,
+        };
+        mem.write_obj(header, HEADER).unwrap();
+        mem.write_obj(0xffu8, STATUS).unwrap();
+        let mut descs = vec![Descriptor {
+            addr: HEADER,
+            len: HEADER_SIZE as u32,
+            writable: false,
+        }];
+        if data_len > 0 {
+            descs.push(Descriptor {
+                addr: DATA,
+                len: data_len,
+                writable: data_in,
+            });
+        }
+        descs.push(Descriptor {
+            addr: STATUS,
+            len: 1,
+            writable: true,
+        });
+        let written = disk.handle(mem, &descs);
+        (mem.read_obj(STATUS).unwrap(), written)
+    }
+
+    fn segment_request(
+        disk: &mut Disk,
+        mem: &GuestMemory,
+        type_: u32,
+        segment: DiscardSegment,
+    ) -> u8 {
+        mem.write_obj(segment, DATA).unwrap();
+        request(disk, mem, type_, 0, SEGMENT_SIZE as u32, false).0
+    }
+
+    #[test]
+    fn in_reads_sectors() {
+        let image = Image::new("in");
+        let mut disk = disk(&image, false);
+        let mem = memory();
+        let len = 2 * SECTOR_SIZE as u32;
+        assert_eq!(
+            request(&mut disk, &mem, VIRTIO_BLK_T_IN, 3, len, true),
+            (VIRTIO_BLK_S_OK, len + 1)
+        );
+        let mut buf = vec![0u8; len as usize];
+        mem.read_slice(&mut buf, DATA).unwrap();
+        assert!(buf[..SECTOR_SIZE as usize].iter().all(|b| *b == 3));
+        assert!(buf[SECTOR_SIZE as usize..].iter().all(|b| *b == 4));
+    }
+
+    #[test]
+    fn out_writes_sectors() {
+        let image = Image::new("out");
+        let mut disk = disk(&image, false);
+        let mem = memory();
+        mem.write_slice(&[0xab; SECTOR_SIZE as usize], DATA)
+            .unwrap();
+        assert_eq!(
+            request(
+                &mut disk,
+                &mem,
+                VIRTIO_BLK_T_OUT,
+                5,
+                SECTOR_SIZE as u32,
+                false
+            ),
+            (VIRTIO_BLK_S_OK, 1)
+        );
+        assert_eq!(image.sector(5), vec![0xab; SECTOR_SIZE as usize]);
+        assert_eq!(image.sector(6), vec![6; SECTOR_SIZE as usize]);
+    }
+
+    #[test]
+    fn out_to_read_only_disk_fails() {
+        let image = Image::new("ro");
+        let mut disk = disk(&image, true);
+        let mem = memory();
+        mem.write_slice(&[0xab; SECTOR_SIZE as usize], DATA)
+            .unwrap();
+        let len = SECTOR_SIZE as u32;
+        assert_eq!(
+            request(&mut disk, &mem, VIRTIO_BLK_T_OUT, 5, len, false).0,
+            VIRTIO_BLK_S_IOERR
+        );
+        assert_eq!(image.sector(5), vec![5; SECTOR_SIZE as usize]);
+        // Reads still work
+        assert_eq!(
+            request(&mut disk, &mem, VIRTIO_BLK_T_IN, 5, len, true).0,
+            VIRTIO_BLK_S_OK
+        );
+    }
+
+    #[test]
+    fn bad_ranges_fail() {
+        let image = Image::new("range");
+        let mut disk = disk(&image, false);
+        let mem = memory();
+        let len = 2 * SECTOR_SIZE as u32;
+        // Past the end, ending past the end, overflowing, partial sector
+        for (sector, len) in [
+            (SECTORS, len),
+            (SECTORS - 1, len),
+            (u64::MAX, len),
+            (0, 100),
+        ] {
+            assert_eq!(
+                request(&mut disk, &mem, VIRTIO_BLK_T_IN, sector, len, true).0,
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+        assert_eq!(
+            request(&mut disk, &mem, VIRTIO_BLK_T_IN, SECTORS - 2, len, true).0,
+            VIRTIO_BLK_S_OK
+        );
+    }
+
+    #[test]
+    fn flush_get_id_and_unsupported() {
+        let image = Image::new("misc");
+        let mut disk = disk(&image, false);
+        let mem = memory();
+        assert_eq!(
+            request(&mut disk, &mem, VIRTIO_BLK_T_FLUSH, 0, 0, false),
+            (VIRTIO_BLK_S_OK, 1)
+        );
+
+        let id_len = VIRTIO_BLK_ID_BYTES as u32;
+        assert_eq!(
+            request(&mut disk, &mem, VIRTIO_BLK_T_GET_ID, 0, id_len, true),
+            (VIRTIO_BLK_S_OK, id_len + 1)
+        );
+        let mut id = [0u8; VIRTIO_BLK_ID_BYTES];
+        mem.read_slice(&mut id, DATA).unwrap();
+        let metadata = std::fs::metadata(&image.0).unwrap();
+        let expected = format!("{:x}{:x}", metadata.dev(), metadata.ino());
+        assert!(String::from_utf8_lossy(&id).starts_with(&expected));
+
+        assert_eq!(
+            request(&mut disk, &mem, 99, 0, 0, false),
+            (VIRTIO_BLK_S_UNSUPP, 1)
+        );
+    }
+
+    #[test]
+    fn write_zeroes_and_discard() {
+        let image = Image::new("zero");
+        let mut disk = disk(&image, false);
+        let mem = memory();
+        let segment = |sector, num_sectors, flags| DiscardSegment {
+            sector,
+            num_sectors,
+            flags,
+        };
+
+        for flags in [0, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP] {
+            assert_eq!(
+                segment_request(
+                    &mut disk,
+                    &mem,
+                    VIRTIO_BLK_T_WRITE_ZEROES,
+                    segment(2, 2, flags)
+                ),
+                VIRTIO_BLK_S_OK
+            );
+        }
+        for sector in 2..4 {
+            assert_eq!(image.sector(sector), vec![0; SECTOR_SIZE as usize]);
+        }
+        assert_eq!(image.sector(4), vec![4; SECTOR_SIZE as usize]);
+
+        assert_eq!(
+            segment_request(&mut disk, &mem, VIRTIO_BLK_T_DISCARD, segment(8, 4, 0)),
+            VIRTIO_BLK_S_OK
+        );
+        // Discard can't unmap, and flags beyond UNMAP don't exist
+        assert_eq!(
+            segment_request(
+                &mut disk,
+                &mem,
+                VIRTIO_BLK_T_DISCARD,
+                segment(8, 4, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP)
+            ),
+            VIRTIO_BLK_S_UNSUPP
+        );
+        assert_eq!(
+            segment_request(
+                &mut disk,
+                &mem,
+                VIRTIO_BLK_T_WRITE_ZEROES,
+                segment(8, 4, 0x2)
+            ),
+            VIRTIO_BLK_S_UNSUPP
+        );
+    }
+
+    #[test]
+    fn discard_ranges_checked() {
+        let image = Image::new("discard-range");
+        let mut rw = disk(&image, false);
+        let mem = memory();
+        for type_ in [VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_WRITE_ZEROES] {
+            for (sector, num_sectors) in [(SECTORS - 1, 2), (SECTORS, 1), (u64::MAX, 1)] {
+                let segment = DiscardSegment {
+                    sector,
+                    num_sectors,
+                    flags: 0,
+                };
+                assert_eq!(
+                    segment_request(&mut rw, &mem, type_, segment),
+                    VIRTIO_BLK_S_IOERR
+                );
+            }
+            // More segments than offered, and a partial one
+            for len in [2 * SEGMENT_SIZE as u32, SEGMENT_SIZE as u32 - 1] {
+                assert_eq!(
+                    request(&mut rw, &mem, type_, 0, len, false).0,
+                    VIRTIO_BLK_S_IOERR
+                );
+            }
+        }
+        assert_eq!(image.sector(SECTORS - 1), vec![15; SECTOR_SIZE as usize]);
+
+        // Read-only disks take neither
+        let mut ro = disk(&image, true);
+        let segment = DiscardSegment {
+            sector: 0,
+            num_sectors: 1,
+            flags: 0,
+        };
+        for type_ in [VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_WRITE_ZEROES] {
+            assert_eq!(
+                segment_request(&mut ro, &mem, type_, segment),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+    }
+
+    #[test]
+    fn direct_io_needs_whole_blocks() {
+        let image = Image::new("direct");
+        let mut disk = disk(&image, false);
+        // As if opened with O_DIRECT and a 4 KiB block size; not every file
+        // system the tests run on takes O_DIRECT
+        disk.direct_align = Some(4096);
+        let mem = memory();
+        let block = 4096;
+        // Misaligned start, partial block
+        for (sector, len) in [(1, block), (8, SECTOR_SIZE as u32)] {
+            assert_eq!(
+                request(&mut disk, &mem, VIRTIO_BLK_T_IN, sector, len, true).0,
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+        assert_eq!(
+            request(&mut disk, &mem, VIRTIO_BLK_T_IN, 8, block, true).0,
+            VIRTIO_BLK_S_OK
+        );
+    }
+}
@@ -0,0 +1,821 @@
+// Virtio-MMIO transport, version 2 (virtio 1.x, "non-legacy").
+//
+// Each device gets one page of MMIO space: 32-bit control registers up to
+// 0x100, the device's configuration space after that. The guest finds
+// devices through `virtio_mmio.device=` on the kernel command line.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use super::{
+    QueueConfig, VirtioDevice, VirtioInterrupt, VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC,
+    VIRTIO_F_RING_PACKED, VIRTIO_F_VERSION_1, VIRTIO_STATUS_ACKNOWLEDGE,
+    VIRTIO_STATUS_DEVICE_NEEDS_RESET, VIRTIO_STATUS_DRIVER, VIRTIO_STATUS_DRIVER_OK,
+    VIRTIO_STATUS_FAILED, VIRTIO_STATUS_FEATURES_OK,
+};
+use crate::bus::BusDevice;
+use crate::eventfd::IoEventFd;
+use crate::memory::GuestMemory;
+
+pub const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+
+const MAGIC_VALUE: u32 = 0x7472_6976; // "virt"
+const VERSION: u32 = 2;
+const VENDOR_ID: u32 = 0;
+
+// Register offsets
+const REG_MAGIC_VALUE: u64 = 0x000;
+const REG_VERSION: u64 = 0x004;
+const REG_DEVICE_ID: u64 = 0x008;
+const REG_VENDOR_ID: u64 = 0x00c;
+const REG_DEVICE_FEATURES: u64 = 0x010;
+const REG_DEVICE_FEATURES_SEL: u64 = 0x014;
+const REG_DRIVER_FEATURES: u64 = 0x020;
+const REG_DRIVER_FEATURES_SEL: u64 = 0x024;
+const REG_QUEUE_SEL: u64 = 0x030;
+const REG_QUEUE_NUM_MAX: u64 = 0x034;
+const REG_QUEUE_NUM: u64 = 0x038;
+const REG_QUEUE_READY: u64 = 0x044;
+// Doorbell: the driver writes the index of a queue with new buffers
+pub const REG_QUEUE_NOTIFY: u64 = 0x050;
+const REG_INTERRUPT_STATUS: u64 = 0x060;
+const REG_INTERRUPT_ACK: u64 = 0x064;
+const REG_STATUS: u64 = 0x070;
+const REG_QUEUE_DESC_LOW: u64 = 0x080;
+const REG_QUEUE_DESC_HIGH: u64 = 0x084;
+const REG_QUEUE_DRIVER_LOW: u64 = 0x090;
+const REG_QUEUE_DRIVER_HIGH: u64 = 0x094;
+const REG_QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const REG_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const REG_CONFIG_GENERATION: u64 = 0x0fc;
+const REG_CONFIG: u64 = 0x100;
+
+// Status bits and the bit that has to be set before each of them
+const STATUS_PREREQUISITES: [(u32, u32); 3] = [
+    (VIRTIO_STATUS_DRIVER, VIRTIO_STATUS_ACKNOWLEDGE),
+    (VIRTIO_STATUS_FEATURES_OK, VIRTIO_STATUS_DRIVER),
+    (VIRTIO_STATUS_DRIVER_OK, VIRTIO_STATUS_FEATURES_OK),
+];
+
+// Features the transport and the queue implementation add to every
+// device's
+const TRANSPORT_FEATURES: u64 = 1 << VIRTIO_F_VERSION_1
+    | 1 << VIRTIO_F_INDIRECT_DESC
+    | 1 << VIRTIO_F_EVENT_IDX
+    | 1 << VIRTIO_F_RING_PACKED;
+
+// `virtio_mmio.device=` parameter telling the guest kernel about a device
+// at `base` raising `irq`
+pub fn cmdline_param(base: u64, irq: u32) -> String {
+    format!(
+        "virtio_mmio.device={}K@0x{:x}:{}",
+        VIRTIO_MMIO_SIZE >> 10,
+        base,
+        irq
+    )
+}
+
+// Set the low or high half of `reg` to `value`
+fn set_half(reg: &mut u64, high: bool, value: u32) {
+    if high {
+        *reg = (*reg & 0xffff_ffff) | ((value as u64) << 32);
+    } else {
+        *reg = (*reg & !0xffff_ffff) | value as u64;
+    }
+}
+
+pub struct MmioTransport {
+    device: Box<dyn VirtioDevice>,
+    mem: Arc<GuestMemory>,
+    interrupt: Arc<VirtioInterrupt>,
+    status: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    queues: Vec<QueueConfig>,
+    // The device is serving its queues
+    activated: bool,
+}
+
+impl MmioTransport {
+    pub fn new(
+        mem: Arc<GuestMemory>,
+        device: Box<dyn VirtioDevice>,
+        interrupt: Arc<VirtioInterrupt>,
+    ) -> Self {
+        let queues = device
+            .queue_max_sizes()
+            .iter()
+            .map(|max_size| QueueConfig::new(*max_size))
+            .collect();
+        MmioTransport {
+            device,
+            mem,
+            interrupt,
+            status: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            queues,
+            activated: false,
+        }
+    }
+
+    pub fn queue_count(&self) -> usize {
+        self.queues.len()
+    }
+
+    // The driver rang the doorbell of queue `index`, through the register
+    // or an ioeventfd on it
+    pub fn queue_notify(&mut self, index: u32) {
+        if !self.activated {
+            return;
+        }
+        if let Err(e) = self.device.queue_notify(index as u16) {
+            // Nothing more gets served until the driver resets us
+            self.device.reset();
+            self.activated = false;
+            self.needs_reset(&format!("queue {}: {}", index, e));
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.features() | TRANSPORT_FEATURES
+    }
+
+    fn selected_queue(&self) -> Option<&QueueConfig> {
+        self.queues.get(self.queue_sel as usize)
+    }
+
+    // The selected queue, if the driver may still change its setup
+    fn selected_queue_mut(&mut self) -> Option<&mut QueueConfig> {
+        if self.status & VIRTIO_STATUS_DRIVER_OK != 0 {
+            return None;
+        }
+        self.queues
+            .get_mut(self.queue_sel as usize)
+            .filter(|queue| !queue.ready)
+    }
+
+    // The driver may only accept features the device offered, and has to
+    // accept VERSION_1 to use this transport
+    fn features_ok(&self) -> bool {
+        self.driver_features & !self.device_features() == 0
+            && self.driver_features & (1 << VIRTIO_F_VERSION_1) != 0
+    }
+
+    fn activate(&mut self) {
+        let invalid = self
+            .queues
+            .iter()
+            .position(|queue| queue.ready && (queue.size == 0 || queue.size > queue.max_size));
+        if let Some(index) = invalid {
+            let size = self.queues[index].size;
+            self.needs_reset(&format!("queue {} has invalid size {}", index, size));
+            return;
+        }
+        match self.device.activate(
+            self.mem.clone(),
+            self.driver_features,
+            self.queues.clone(),
+            self.interrupt.clone(),
+        ) {
+            Ok(()) => self.activated = true,
+            Err(e) => self.needs_reset(&e.to_string()),
+        }
+    }
+
+    // Something went wrong that only a reset can fix; let the driver know
+    fn needs_reset(&mut self, reason: &str) {
+        eprintln!("virtio-mmio: {}", reason);
+        self.status |= VIRTIO_STATUS_DEVICE_NEEDS_RESET;
+        if let Err(e) = self.interrupt.signal_config_change() {
+            eprintln!("virtio-mmio: {}", e);
+        }
+    }
+
+    // Writing 0 resets the device. Otherwise the driver sets one more
+    // status bit at a time, in order; anything else is ignored.
+    fn set_status(&mut self, status: u32) {
+        if status == 0 {
+            self.reset();
+            return;
+        }
+        let added = status & !self.status;
+        if self.status & !status & !VIRTIO_STATUS_DEVICE_NEEDS_RESET != 0 {
+            eprintln!(
+                "virtio-mmio: driver cleared status bits 0x{:x}",
+                self.status & !status
+            );
+            return;
+        }
+        for (bit, prerequisite) in STATUS_PREREQUISITES {
+            if added & bit != 0 && status & prerequisite == 0 {
+                eprintln!("virtio-mmio: invalid status 0x{:x}", status);
+                return;
+            }
+        }
+
+        // Not setting FEATURES_OK is how the device turns the features down
+        let mut status = status | (self.status & VIRTIO_STATUS_DEVICE_NEEDS_RESET);
+        if added & VIRTIO_STATUS_FEATURES_OK != 0 && !self.features_ok() {
+            status &= !VIRTIO_STATUS_FEATURES_OK;
+        }
+        if status & VIRTIO_STATUS_FEATURES_OK == 0 {
+            status &= !VIRTIO_STATUS_DRIVER_OK;
+        }
+        self.status = status;
+        if added & status & VIRTIO_STATUS_DRIVER_OK != 0 && status & VIRTIO_STATUS_FAILED == 0 {
+            self.activate();
+        }
+    }
+
+    fn read_register(&self, offset: u64) -> u32 {
+        match offset {
+            REG_MAGIC_VALUE => MAGIC_VALUE,
+            REG_VERSION => VERSION,
+            REG_DEVICE_ID => self.device.device_type(),
+            REG_VENDOR_ID => VENDOR_ID,
+            REG_DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            // A queue that doesn't exist reads as size 0
+            REG_QUEUE_NUM_MAX => self
+                .selected_queue()
+                .map_or(0, |queue| queue.max_size as u32),
+            REG_QUEUE_READY => self.selected_queue().map_or(0, |queue| queue.ready as u32),
+            REG_INTERRUPT_STATUS => self.interrupt.status(),
+            REG_STATUS => self.status,
+            REG_CONFIG_GENERATION => self.interrupt.config_generation(),
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32) {
+        let features_writable =
+            self.status & VIRTIO_STATUS_DRIVER != 0 && self.status & VIRTIO_STATUS_FEATURES_OK == 0;
+        match offset {
+            REG_DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            REG_DRIVER_FEATURES if features_writable => match self.driver_features_sel {
+                0 => set_half(&mut self.driver_features, false, value),
+                1 => set_half(&mut self.driver_features, true, value),
+                _ => {}
+            },
+            REG_DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            REG_QUEUE_SEL => self.queue_sel = value,
+            REG_QUEUE_NUM => {
+                if let Some(queue) = self.selected_queue_mut() {
+                    queue.size = value as u16;
+                }
+            }
+            REG_QUEUE_READY => {
+                // Ready can only be set while the driver sets up queues
+                if let Some(queue) = self.queues.get_mut(self.queue_sel as usize) {
+                    if self.status & VIRTIO_STATUS_DRIVER_OK == 0 {
+                        queue.ready = value == 1;
+                    }
+                }
+            }
+            REG_QUEUE_NOTIFY => self.queue_notify(value),
+            REG_INTERRUPT_ACK => self.interrupt.ack(value),
+            REG_STATUS => self.set_status(value),
+            REG_QUEUE_DESC_LOW | REG_QUEUE_DESC_HIGH => {
+                if let Some(queue) = self.selected_queue_mut() {
+                    set_half(&mut queue.desc_table, offset == REG_QUEUE_DESC_HIGH, value);
+                }
+            }
+            REG_QUEUE_DRIVER_LOW | REG_QUEUE_DRIVER_HIGH => {
+                if let Some(queue) = self.selected_queue_mut() {
+                    set_half(
+                        &mut queue.avail_ring,
+                        offset == REG_QUEUE_DRIVER_HIGH,
+                        value,
+                    );
+                }
+            }
+            REG_QUEUE_DEVICE_LOW | REG_QUEUE_DEVICE_HIGH => {
+                if let Some(queue) = self.selected_queue_mut() {
+                    set_half(&mut queue.used_ring, offset == REG_QUEUE_DEVICE_HIGH, value);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Control registers only take aligned 32-bit accesses; the configuration
+// space is up to the device
+impl BusDevice for MmioTransport {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        if offset >= REG_CONFIG {
+            self.device.read_config(offset - REG_CONFIG, data);
+            return;
+        }
+        if data.len() != 4 || !offset.is_multiple_of(4) {
+            data.fill(0xff);
+            return;
+        }
+        data.copy_from_slice(&self.read_register(offset).to_le_bytes());
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if offset >= REG_CONFIG {
+            self.device.write_config(offset - REG_CONFIG, data);
+            return;
+        }
+        if data.len() != 4 || !offset.is_multiple_of(4) {
+            return;
+        }
+        let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        self.write_register(offset, value);
+    }
+
+    // Back to the state the device powered on in, as on a driver reset
+    fn reset(&mut self) {
+        if self.activated {
+            self.device.reset();
+            self.activated = false;
+        }
+        self.status = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        for queue in &mut self.queues {
+            *queue = QueueConfig::new(queue.max_size);
+        }
+        self.interrupt.reset();
+    }
+}
+
+// Serves a transport's queue doorbells from a thread of its own. Each
+// IoEventFd is registered on REG_QUEUE_NOTIFY matching one queue index, so
+// the guest's notifications complete in KVM without a vCPU exit.
+pub struct QueueNotifier {
+    stop: EventFd,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl QueueNotifier {
+    pub fn spawn(
+        transport: Arc<Mutex<MmioTransport>>,
+        doorbells: Vec<IoEventFd>,
+    ) -> io::Result<Self> {
+        let stop = EventFd::new(EFD_NONBLOCK)?;
+        let stop_event = stop.try_clone()?;
+        let thread = thread::Builder::new()
+            .name("virtio-mmio-notify".to_string())
+            .spawn(move || {
+                if let Err(e) = serve_doorbells(&transport, &doorbells, &stop_event) {
+                    eprintln!("virtio-mmio: waiting for notifications failed: {}", e);
+                }
+            })?;
+        Ok(QueueNotifier {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for QueueNotifier {
+    fn drop(&mut self) {
+        let _ = self.stop.write(1);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Pass doorbell rings on to `transport` until `stop` is signalled
+fn serve_doorbells(
+    transport: &Mutex<MmioTransport>,
+    doorbells: &[IoEventFd],
+    stop: &EventFd,
+) -> io::Result<()> {
+    let mut fds: Vec<libc::pollfd> = [stop.as_raw_fd()]
+        .into_iter()
+        .chain(
+            doorbells
+                .iter()
+                .map(|doorbell| doorbell.event().as_raw_fd()),
+        )
+        .map(|fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    loop {
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if fds[0].revents != 0 {
+            return Ok(());
+        }
+        for (doorbell, fd) in doorbells.iter().zip(&fds[1..]) {
+            if fd.revents == 0 {
+                continue;
+            }
+            // Rings since the last read are one notification
+            match doorbell.event().read() {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+            if let Some(index) = doorbell.datamatch() {
+                transport.lock().unwrap().queue_notify(index);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::devices::virtio::queue::QueueError;
+    use crate::devices::virtio::{ActivateError, VIRTIO_INT_CONFIG};
+    use crate::devices::Interrupt;
+
+    const DEVICE_FEATURES: u64 = 1 << 5 | 1 << 35;
+    const STATUS_UP_TO_DRIVER: u32 = VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER;
+    const STATUS_UP_TO_FEATURES_OK: u32 = STATUS_UP_TO_DRIVER | VIRTIO_STATUS_FEATURES_OK;
+
+    // Counts how often the transport raised its interrupt
+    struct TestInterrupt(Arc<AtomicUsize>);
+
+    impl Interrupt for TestInterrupt {
+        fn trigger(&self) -> io::Result<()> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    // What the transport did to the device
+    #[derive(Default)]
+    struct DeviceLog {
+        activated: Option<(u64, Vec<QueueConfig>)>,
+        notified: Vec<u16>,
+        resets: usize,
+        // Notifications fail as if the driver broke a queue
+        broken: bool,
+    }
+
+    // Two queues; activation fails if a ready queue's rings are outside
+    // guest memory
+    struct TestDevice(Arc<Mutex<DeviceLog>>);
+
+    impl VirtioDevice for TestDevice {
+        fn device_type(&self) -> u32 {
+            2
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            &[16, 8]
+        }
+
+        fn features(&self) -> u64 {
+            DEVICE_FEATURES
+        }
+
+        fn read_config(&self, offset: u64, data: &mut [u8]) {
+            data.fill(offset as u8);
+        }
+
+        fn activate(
+            &mut self,
+            mem: Arc<GuestMemory>,
+            features: u64,
+            queues: Vec<QueueConfig>,
+            _interrupt: Arc<VirtioInterrupt>,
+        ) -> Result<(), ActivateError> {
+            for (index, queue) in queues.iter().enumerate().filter(|(_, q)| q.ready) {
+                for ring in [queue.desc_table, queue.avail_ring, queue.used_ring] {
+                    if mem.check_range(ring, 1).is_err() {
+                        return Err(ActivateError::InvalidQueue(
+                            index,
+                            format!("ring at 0x{:x} is outside guest memory", ring),
+                        ));
+                    }
+                }
+            }
+            self.0.lock().unwrap().activated = Some((features, queues));
+            Ok(())
+        }
+
+        fn queue_notify(&mut self, index: u16) -> Result<(), QueueError> {
+            let mut log = self.0.lock().unwrap();
+            if log.broken {
+                return Err(QueueError::InvalidAvailIndex(0xffff));
+            }
+            log.notified.push(index);
+            Ok(())
+        }
+
+        fn reset(&mut self) {
+            let mut log = self.0.lock().unwrap();
+            log.activated = None;
+            log.resets += 1;
+        }
+    }
+
+    struct Fixture {
+        transport: MmioTransport,
+        log: Arc<Mutex<DeviceLog>>,
+        interrupts: Arc<AtomicUsize>,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let mem = Arc::new(GuestMemory::new(&[(0, 0x10000)]).unwrap());
+            let log = Arc::new(Mutex::new(DeviceLog::default()));
+            let interrupts = Arc::new(AtomicUsize::new(0));
+            let interrupt = Arc::new(VirtioInterrupt::new(Box::new(TestInterrupt(
+                interrupts.clone(),
+            ))));
+            Fixture {
+                transport: MmioTransport::new(mem, Box::new(TestDevice(log.clone())), interrupt),
+                log,
+                interrupts,
+            }
+        }
+
+        fn read(&mut self, offset: u64) -> u32 {
+            let mut data = [0u8; 4];
+            self.transport.read(offset, &mut data);
+            u32::from_le_bytes(data)
+        }
+
+        fn write(&mut self, offset: u64, value: u32) {
+            self.transport.write(offset, &value.to_le_bytes());
+        }
+
+        fn write_features(&mut self, features: u64) {
+            self.write(REG_DRIVER_FEATURES_SEL, 0);
+            self.write(REG_DRIVER_FEATURES, features as u32);
+            self.write(REG_DRIVER_FEATURES_SEL, 1);
+            self.write(REG_DRIVER_FEATURES, (features >> 32) as u32);
+        }
+
+        // Walk the status up to FEATURES_OK, accepting `features`
+        fn negotiate(&mut self, features: u64) {
+            self.write(REG_STATUS, VIRTIO_STATUS_ACKNOWLEDGE);
+            self.write(REG_STATUS, STATUS_UP_TO_DRIVER);
+            self.write_features(features);
+            self.write(REG_STATUS, STATUS_UP_TO_FEATURES_OK);
+        }
+
+        // Program queue `index` with its rings from `base` and mark it ready
+        fn setup_queue(&mut self, index: u32, size: u32, base: u64) {
+            self.write(REG_QUEUE_SEL, index);
+            self.write(REG_QUEUE_NUM, size);
+            for (reg, addr) in [
+                (REG_QUEUE_DESC_LOW, base),
+                (REG_QUEUE_DRIVER_LOW, base + 0x1000),
+                (REG_QUEUE_DEVICE_LOW, base + 0x2000),
+            ] {
+                self.write(reg, addr as u32);
+                self.write(reg + 4, (addr >> 32) as u32);
+            }
+            self.write(REG_QUEUE_READY, 1);
+        }
+
+        fn driver_ok(&mut self) {
+            self.write(
+                REG_STATUS,
+                STATUS_UP_TO_FEATURES_OK | VIRTIO_STATUS_DRIVER_OK,
+            );
+        }
+
+        fn activated(&self) -> bool {
+            self.log.lock().unwrap().activated.is_some()
+        }
+    }
+
+    fn accepted_features() -> u64 {
+        1 << 5 | 1 << VIRTIO_F_VERSION_1
+    }
+
+    #[test]
+    fn cmdline_param_describes_device() {
+        assert_eq!(
+            cmdline_param(0xd000_1000, 5),
+            "virtio_mmio.device=4K@0xd0001000:5"
+        );
+    }
+
+    #[test]
+    fn identification_registers() {
+        let mut f = Fixture::new();
+        assert_eq!(f.read(REG_MAGIC_VALUE), MAGIC_VALUE);
+        assert_eq!(f.read(REG_VERSION), 2);
+        assert_eq!(f.read(REG_DEVICE_ID), 2);
+        assert_eq!(f.read(REG_VENDOR_ID), 0);
+        for (queue, max_size) in [(0, 16), (1, 8), (2, 0)] {
+            f.write(REG_QUEUE_SEL, queue);
+            assert_eq!(f.read(REG_QUEUE_NUM_MAX), max_size);
+        }
+        // Narrow or unaligned register reads don't hit a register
+        let mut data = [0u8; 2];
+        f.transport.read(REG_MAGIC_VALUE, &mut data);
+        assert_eq!(data, [0xff; 2]);
+        assert_eq!(f.read(REG_VERSION + 2), 0xffff_ffff);
+        // Beyond the registers is the device's configuration space
+        let mut data = [0u8; 2];
+        f.transport.read(REG_CONFIG + 3, &mut data);
+        assert_eq!(data, [3; 2]);
+    }
+
+    #[test]
+    fn feature_select_pages() {
+        let mut f = Fixture::new();
+        let offered = DEVICE_FEATURES | TRANSPORT_FEATURES;
+        for (page, expected) in [(0, offered as u32), (1, (offered >> 32) as u32), (2, 0)] {
+            f.write(REG_DEVICE_FEATURES_SEL, page);
+            assert_eq!(f.read(REG_DEVICE_FEATURES), expected);
+        }
+
+        // Driver features are only taken between DRIVER and FEATURES_OK,
+        // and only on the first two pages
+        f.write_features(accepted_features());
+        assert_eq!(f.transport.driver_features, 0);
+        f.write(REG_STATUS, VIRTIO_STATUS_ACKNOWLEDGE);
+        f.write(REG_STATUS, STATUS_UP_TO_DRIVER);
+        f.write_features(accepted_features());
+        f.write(REG_DRIVER_FEATURES_SEL, 2);
+        f.write(REG_DRIVER_FEATURES, 0xffff_ffff);
+        assert_eq!(f.transport.driver_features, accepted_features());
+        f.write(REG_STATUS, STATUS_UP_TO_FEATURES_OK);
+        f.write_features(DEVICE_FEATURES);
+        assert_eq!(f.transport.driver_features, accepted_features());
+    }
+
+    #[test]
+    fn status_bits_are_set_in_order() {
+        let mut f = Fixture::new();
+        // DRIVER before ACKNOWLEDGE
+        f.write(REG_STATUS, VIRTIO_STATUS_DRIVER);
+        assert_eq!(f.read(REG_STATUS), 0);
+        f.write(REG_STATUS, VIRTIO_STATUS_ACKNOWLEDGE);
+        f.write(REG_STATUS, STATUS_UP_TO_DRIVER);
+        // DRIVER_OK before FEATURES_OK
+        f.write(REG_STATUS, STATUS_UP_TO_DRIVER | VIRTIO_STATUS_DRIVER_OK);
+        assert_eq!(f.read(REG_STATUS), STATUS_UP_TO_DRIVER);
+        // Clearing a bit
+        f.write(REG_STATUS, VIRTIO_STATUS_ACKNOWLEDGE);
+        assert_eq!(f.read(REG_STATUS), STATUS_UP_TO_DRIVER);
+    }
+
+    #[test]
+    fn features_ok_refused_for_bad_features() {
+        let mut f = Fixture::new();
+        // Without VERSION_1, then with a feature that wasn't offered
+        for features in [1 << 5, accepted_features() | 1 << 6] {
+            f.negotiate(features);
+            assert_eq!(f.read(REG_STATUS), STATUS_UP_TO_DRIVER);
+        }
+        f.negotiate(accepted_features());
+        assert_eq!(f.read(REG_STATUS), STATUS_UP_TO_FEATURES_OK);
+    }
+
+    #[test]
+    fn driver_ok_activates_device() {
+        let mut f = Fixture::new();
+        f.negotiate(accepted_features());
+        f.setup_queue(0, 8, 0x4000);
+        // Notifications before activation go nowhere
+        f.write(REG_QUEUE_NOTIFY, 0);
+        f.driver_ok();
+        assert_eq!(
+            f.read(REG_STATUS),
+            STATUS_UP_TO_FEATURES_OK | VIRTIO_STATUS_DRIVER_OK
+        );
+
+        let (features, queues) = f.log.lock().unwrap().activated.clone().unwrap();
+        assert_eq!(features, accepted_features());
+        assert_eq!(
+            queues[0],
+            QueueConfig {
+                max_size: 16,
+                size: 8,
+                ready: true,
+                desc_table: 0x4000,
+                avail_ring: 0x5000,
+                used_ring: 0x6000,
+            }
+        );
+        assert_eq!(queues[1], QueueConfig::new(8));
+
+        // Queue setup is frozen now
+        f.write(REG_QUEUE_SEL, 1);
+        f.write(REG_QUEUE_READY, 1);
+        assert_eq!(f.read(REG_QUEUE_READY), 0);
+        f.write(REG_QUEUE_NOTIFY, 1);
+        assert_eq!(f.log.lock().unwrap().notified, vec![1]);
+    }
+
+    #[test]
+    fn ready_queue_setup_is_frozen() {
+        let mut f = Fixture::new();
+        f.negotiate(accepted_features());
+        f.setup_queue(0, 8, 0x4000);
+        f.write(REG_QUEUE_NUM, 4);
+        f.write(REG_QUEUE_DESC_LOW, 0x8000);
+        assert_eq!(f.transport.queues[0].size, 8);
+        assert_eq!(f.transport.queues[0].desc_table, 0x4000);
+        // Until the driver takes the queue back
+        f.write(REG_QUEUE_READY, 0);
+        f.write(REG_QUEUE_NUM, 4);
+        assert_eq!(f.transport.queues[0].size, 4);
+    }
+
+    #[test]
+    fn bad_queue_size_needs_reset() {
+        for size in [0, 17] {
+            let mut f = Fixture::new();
+            f.negotiate(accepted_features());
+            f.setup_queue(0, size, 0x4000);
+            f.driver_ok();
+            assert!(!f.activated());
+            assert_ne!(f.read(REG_STATUS) & VIRTIO_STATUS_DEVICE_NEEDS_RESET, 0);
+            assert_eq!(f.read(REG_INTERRUPT_STATUS), VIRTIO_INT_CONFIG);
+            assert_eq!(f.read(REG_CONFIG_GENERATION), 1);
+            assert_eq!(f.interrupts.load(Ordering::SeqCst), 1);
+            // The queue stays dead to notifications
+            f.write(REG_QUEUE_NOTIFY, 0);
+            assert!(f.log.lock().unwrap().notified.is_empty());
+        }
+    }
+
+    #[test]
+    fn bad_rings_need_reset() {
+        let mut f = Fixture::new();
+        f.negotiate(accepted_features());
+        f.setup_queue(0, 8, 0x4000);
+        f.setup_queue(1, 8, 0xf000);
+        f.driver_ok();
+        assert!(!f.activated());
+        assert_ne!(f.read(REG_STATUS) & VIRTIO_STATUS_DEVICE_NEEDS_RESET, 0);
+        assert_eq!(f.read(REG_INTERRUPT_STATUS), VIRTIO_INT_CONFIG);
+
+        // The driver resets and tries again with sane rings
+        f.write(REG_STATUS, 0);
+        f.negotiate(accepted_features());
+        f.setup_queue(0, 8, 0x4000);
+        f.driver_ok();
+        assert!(f.activated());
+        assert_eq!(f.read(REG_STATUS) & VIRTIO_STATUS_DEVICE_NEEDS_RESET, 0);
+    }
+
+    #[test]
+    fn queue_error_needs_reset() {
+        let mut f = Fixture::new();
+        f.negotiate(accepted_features());
+        f.setup_queue(0, 8, 0x4000);
+        f.driver_ok();
+        f.log.lock().unwrap().broken = true;
+        f.write(REG_QUEUE_NOTIFY, 0);
+        assert!(!f.activated());
+        assert_eq!(f.log.lock().unwrap().resets, 1);
+        assert_ne!(f.read(REG_STATUS) & VIRTIO_STATUS_DEVICE_NEEDS_RESET, 0);
+        assert_eq!(f.read(REG_INTERRUPT_STATUS), VIRTIO_INT_CONFIG);
+
+        // Nothing is served until the driver resets the device
+        f.log.lock().unwrap().broken = false;
+        f.write(REG_QUEUE_NOTIFY, 0);
+        assert!(f.log.lock().unwrap().notified.is_empty());
+    }
+
+    #[test]
+    fn status_zero_resets() {
+        let mut f = Fixture::new();
+        f.negotiate(accepted_features());
+        f.setup_queue(0, 8, 0x4000);
+        f.driver_ok();
+        f.transport.interrupt.signal_config_change().unwrap();
+        f.write(REG_STATUS, 0);
+
+        assert_eq!(f.log.lock().unwrap().resets, 1);
+        assert!(!f.activated());
+        assert_eq!(f.read(REG_STATUS), 0);
+        assert_eq!(f.read(REG_INTERRUPT_STATUS), 0);
+        assert_eq!(f.transport.driver_features, 0);
+        assert_eq!(f.transport.queues[0], QueueConfig::new(16));
+        f.write(REG_QUEUE_NOTIFY, 0);
+        assert!(f.log.lock().unwrap().notified.is_empty());
+    }
+}
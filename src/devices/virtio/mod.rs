@@ -0,0 +1,170 @@
+// Virtio devices (virtio 1.x) and the transport they are attached with.
+//
+// A device only deals with its queues and its configuration space; feature
+// negotiation, the status state machine and queue setup are left to the
+// transport, which hands the queues over once the driver sets DRIVER_OK.
+
+pub mod block;
+pub mod mmio;
+pub mod queue;
+
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::Interrupt;
+use crate::memory::GuestMemory;
+use queue::QueueError;
+
+// Device types
+pub const VIRTIO_ID_BLOCK: u32 = 2;
+
+// Feature bits every device gets from the transport and queues
+pub const VIRTIO_F_INDIRECT_DESC: u32 = 28;
+pub const VIRTIO_F_EVENT_IDX: u32 = 29;
+pub const VIRTIO_F_VERSION_1: u32 = 32;
+pub const VIRTIO_F_RING_PACKED: u32 = 34;
+
+// Device status bits, set by the driver in this order
+pub const VIRTIO_STATUS_ACKNOWLEDGE: u32 = 0x01;
+pub const VIRTIO_STATUS_DRIVER: u32 = 0x02;
+pub const VIRTIO_STATUS_FEATURES_OK: u32 = 0x08;
+pub const VIRTIO_STATUS_DRIVER_OK: u32 = 0x04;
+pub const VIRTIO_STATUS_DEVICE_NEEDS_RESET: u32 = 0x40;
+pub const VIRTIO_STATUS_FAILED: u32 = 0x80;
+
+// Interrupt status bits: a used buffer was added, the configuration changed
+pub const VIRTIO_INT_VRING: u32 = 0x01;
+pub const VIRTIO_INT_CONFIG: u32 = 0x02;
+
+// Where the driver put a queue, as programmed through the transport
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueConfig {
+    pub max_size: u16,
+    pub size: u16,
+    pub ready: bool,
+    pub desc_table: u64,
+    // Driver area: the available ring, or the driver event suppression
+    // structure of a packed queue
+    pub avail_ring: u64,
+    // Device area: the used ring, or the device event suppression
+    // structure of a packed queue
+    pub used_ring: u64,
+}
+
+impl QueueConfig {
+    pub fn new(max_size: u16) -> Self {
+        QueueConfig {
+            max_size,
+            size: max_size,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ActivateError {
+    // A queue the device needs is not ready or badly set up
+    InvalidQueue(usize, String),
+    // The device's backend failed to start
+    Io(io::Error),
+}
+
+impl fmt::Display for ActivateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ActivateError::InvalidQueue(index, msg) => write!(f, "Queue {}: {}", index, msg),
+            ActivateError::Io(e) => write!(f, "Failed to activate device: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ActivateError {}
+
+// The device's interrupt line together with the interrupt status the
+// driver reads to find out why it fired. Shared between the transport and
+// the activated device.
+pub struct VirtioInterrupt {
+    status: AtomicU32,
+    config_generation: AtomicU32,
+    interrupt: Mutex<Box<dyn Interrupt>>,
+}
+
+impl VirtioInterrupt {
+    pub fn new(interrupt: Box<dyn Interrupt>) -> Self {
+        VirtioInterrupt {
+            status: AtomicU32::new(0),
+            config_generation: AtomicU32::new(0),
+            interrupt: Mutex::new(interrupt),
+        }
+    }
+
+    // Tell the driver there are new buffers in a used ring
+    pub fn signal_used_queue(&self) -> io::Result<()> {
+        self.signal(VIRTIO_INT_VRING)
+    }
+
+    // Tell the driver the configuration space changed
+    pub fn signal_config_change(&self) -> io::Result<()> {
+        self.config_generation.fetch_add(1, Ordering::SeqCst);
+        self.signal(VIRTIO_INT_CONFIG)
+    }
+
+    fn signal(&self, bits: u32) -> io::Result<()> {
+        self.status.fetch_or(bits, Ordering::SeqCst);
+        self.interrupt.lock().unwrap().trigger()
+    }
+
+    pub fn status(&self) -> u32 {
+        self.status.load(Ordering::SeqCst)
+    }
+
+    // The driver handled the interrupts in `bits`
+    pub fn ack(&self, bits: u32) {
+        self.status.fetch_and(!bits, Ordering::SeqCst);
+    }
+
+    pub fn config_generation(&self) -> u32 {
+        self.config_generation.load(Ordering::SeqCst)
+    }
+
+    fn reset(&self) {
+        self.status.store(0, Ordering::SeqCst);
+    }
+}
+
+// A virtio device, independent of the transport it's attached with
+pub trait VirtioDevice: Send {
+    // VIRTIO_ID_* of the device
+    fn device_type(&self) -> u32;
+
+    // Largest size of each queue; also tells how many queues there are
+    fn queue_max_sizes(&self) -> &[u16];
+
+    // Device specific feature bits on offer
+    fn features(&self) -> u64;
+
+    fn read_config(&self, offset: u64, data: &mut [u8]);
+
+    // Most configuration spaces are read-only
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    // The driver is done setting up: serve `queues` from now on, using the
+    // features both sides agreed on
+    fn activate(
+        &mut self,
+        mem: Arc<GuestMemory>,
+        features: u64,
+        queues: Vec<QueueConfig>,
+        interrupt: Arc<VirtioInterrupt>,
+    ) -> Result<(), ActivateError>;
+
+    // The driver made new buffers available in queue `index`. An error means
+    // the queue is broken and the device has to be reset.
+    fn queue_notify(&mut self, index: u16) -> Result<(), QueueError>;
+
+    // The driver reset the device: forget the queues and wait for the
+    // next activation
+    fn reset(&mut self);
+}
@@ -0,0 +1,283 @@
+// Virtqueues: the rings the driver hands buffers to the device through.
+//
+// A device pops descriptor chains the driver made available, does the
+// request and puts the chain on the used ring. Everything read from the
+// rings is checked before a device gets to see it: buffers have to be in
+// guest RAM, chains can't loop or grow longer than the queue, and indirect
+// tables have to be well formed.
+
+pub mod packed;
+pub mod split;
+
+use std::fmt;
+use std::sync::Arc;
+
+use super::{QueueConfig, VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC, VIRTIO_F_RING_PACKED};
+use crate::memory::{GuestMemory, GuestMemoryError};
+use packed::PackedQueue;
+use split::SplitQueue;
+
+// Descriptor flags
+pub const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+pub const VIRTQ_DESC_F_INDIRECT: u16 = 0x4;
+
+// Size of a descriptor, split or packed, in a ring as in indirect tables
+const DESCRIPTOR_SIZE: u64 = 16;
+
+// Largest queue size either ring layout allows
+pub const MAX_QUEUE_SIZE: u16 = 32768;
+
+// EVENT_IDX: whether moving an index from `old` to `new` crossed `event`
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+#[derive(Debug)]
+pub enum QueueError {
+    // Not a size the ring layout allows
+    InvalidSize(u16),
+    // A ring is misaligned or not in guest RAM
+    InvalidRing(&'static str, u64),
+    // The driver claims more chains are available than the queue holds
+    InvalidAvailIndex(u16),
+    // A descriptor index past the end of its table
+    InvalidDescriptorIndex(u16),
+    // A buffer that isn't all in guest RAM
+    InvalidBuffer { addr: u64, len: u32 },
+    // More descriptors than the table has: the chain loops
+    DescriptorLoop,
+    // More descriptors than the queue size, or more bytes than fit a u32
+    ChainTooLong,
+    // Indirect descriptor the driver may not use, or a broken table
+    InvalidIndirect(&'static str),
+    // Device readable descriptor after a device writable one
+    ReadableAfterWritable,
+    Memory(GuestMemoryError),
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueueError::InvalidSize(size) => write!(f, "Invalid queue size {}", size),
+            QueueError::InvalidRing(ring, addr) => {
+                write!(f, "Invalid {} address 0x{:x}", ring, addr)
+            }
+            QueueError::InvalidAvailIndex(idx) => write!(f, "Invalid available index {}", idx),
+            QueueError::InvalidDescriptorIndex(index) => {
+                write!(f, "Descriptor index {} out of range", index)
+            }
+            QueueError::InvalidBuffer { addr, len } => {
+                write!(f, "Buffer 0x{:x}+0x{:x} is not in guest memory", addr, len)
+            }
+            QueueError::DescriptorLoop => write!(f, "Descriptor chain loops"),
+            QueueError::ChainTooLong => write!(f, "Descriptor chain too long"),
+            QueueError::InvalidIndirect(msg) => write!(f, "Invalid indirect descriptor: {}", msg),
+            QueueError::ReadableAfterWritable => {
+                write!(f, "Device readable descriptor after a writable one")
+            }
+            QueueError::Memory(e) => write!(f, "Failed to access queue: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+impl From<GuestMemoryError> for QueueError {
+    fn from(e: GuestMemoryError) -> Self {
+        QueueError::Memory(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, QueueError>;
+
+// One buffer of a chain, already checked to be in guest RAM
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    // The device writes to the buffer rather than reading from it
+    pub writable: bool,
+}
+
+// A request from the driver: device readable buffers followed by device
+// writable ones
+#[derive(Clone, Debug)]
+pub struct DescriptorChain {
+    // What the used ring identifies the chain by: the head descriptor of
+    // a split queue, the buffer id of a packed one
+    id: u16,
+    // Ring entries the chain took up, to skip when it is used
+    ring_entries: u16,
+    descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn descriptors(&self) -> &[Descriptor] {
+        &self.descriptors
+    }
+}
+
+// Collects and checks the buffers of a chain as a queue walks it. Limits
+// are per chain: `max_len` descriptors in all, however many tables they
+// are spread over.
+struct ChainBuilder<'a> {
+    mem: &'a GuestMemory,
+    chain: DescriptorChain,
+    max_len: usize,
+    total_bytes: u32,
+}
+
+impl<'a> ChainBuilder<'a> {
+    fn new(mem: &'a GuestMemory, max_len: u16) -> Self {
+        ChainBuilder {
+            mem,
+            chain: DescriptorChain {
+                id: 0,
+                ring_entries: 0,
+                descriptors: Vec::new(),
+            },
+            max_len: max_len as usize,
+            total_bytes: 0,
+        }
+    }
+
+    fn push(&mut self, addr: u64, len: u32, writable: bool) -> Result<()> {
+        if self.chain.descriptors.len() >= self.max_len {
+            return Err(QueueError::ChainTooLong);
+        }
+        if !writable
+            && self
+                .chain
+                .descriptors
+                .last()
+                .is_some_and(|desc| desc.writable)
+        {
+            return Err(QueueError::ReadableAfterWritable);
+        }
+        self.mem
+            .check_range(addr, len as usize)
+            .map_err(|_| QueueError::InvalidBuffer { addr, len })?;
+        self.total_bytes = self
+            .total_bytes
+            .checked_add(len)
+            .ok_or(QueueError::ChainTooLong)?;
+        self.chain.descriptors.push(Descriptor {
+            addr,
+            len,
+            writable,
+        });
+        Ok(())
+    }
+
+    // Where the indirect table a descriptor points to lives, and how many
+    // entries it has
+    fn indirect_table(&self, addr: u64, len: u32) -> Result<(u64, u16)> {
+        if len == 0 || !(len as u64).is_multiple_of(DESCRIPTOR_SIZE) {
+            return Err(QueueError::InvalidIndirect("bad table length"));
+        }
+        let entries = len as u64 / DESCRIPTOR_SIZE;
+        if entries > self.max_len as u64 {
+            return Err(QueueError::ChainTooLong);
+        }
+        self.mem
+            .check_range(addr, len as usize)
+            .map_err(|_| QueueError::InvalidBuffer { addr, len })?;
+        Ok((addr, entries as u16))
+    }
+
+    fn finish(mut self, id: u16, ring_entries: u16) -> DescriptorChain {
+        self.chain.id = id;
+        self.chain.ring_entries = ring_entries;
+        self.chain
+    }
+}
+
+// A virtqueue as the device sees it, whatever the ring layout
+//
+// Devices drain a queue with notifications off, then turn them back on and
+// check for buffers that came in meanwhile:
+//
+//     loop {
+//         queue.disable_notification()?;
+//         while let Some(chain) = queue.pop()? { ... }
+//         if !queue.enable_notification()? { break; }
+//     }
+pub trait Queue: Send {
+    // The next chain the driver made available, if any. A chain that fails
+    // the checks is left where it is, so the queue keeps failing until the
+    // device is reset.
+    fn pop(&mut self) -> Result<Option<DescriptorChain>>;
+
+    // Give `chain` back to the driver with `len` bytes written to it
+    fn add_used(&mut self, chain: &DescriptorChain, len: u32) -> Result<()>;
+
+    // Whether the driver wants an interrupt for the chains used since the
+    // last time this returned true
+    fn needs_notification(&mut self) -> Result<bool>;
+
+    // Ask the driver to notify the device of new buffers again. Returns
+    // whether there are buffers already, which may have come in without a
+    // notification.
+    fn enable_notification(&mut self) -> Result<bool>;
+
+    // Tell the driver not to bother notifying the device
+    fn disable_notification(&mut self) -> Result<()>;
+}
+
+// Set up the queue the driver configured, in the layout `features` asked for
+pub fn new_queue(
+    mem: Arc<GuestMemory>,
+    config: &QueueConfig,
+    features: u64,
+) -> Result<Box<dyn Queue>> {
+    let indirect = features & (1 << VIRTIO_F_INDIRECT_DESC) != 0;
+    let event_idx = features & (1 << VIRTIO_F_EVENT_IDX) != 0;
+    if features & (1 << VIRTIO_F_RING_PACKED) != 0 {
+        Ok(Box::new(PackedQueue::new(
+            mem, config, indirect, event_idx,
+        )?))
+    } else {
+        Ok(Box::new(SplitQueue::new(mem, config, indirect, event_idx)?))
+    }
+}
+
+// Guest memory and descriptor helpers for the split and packed queue tests
+#[cfg(test)]
+mod test_utils {
+    use std::sync::Arc;
+
+    use super::QueueConfig;
+    use crate::memory::{ByteValued, GuestMemory};
+
+    // Above the rings of either layout
+    pub const INDIRECT_TABLE: u64 = 0x3000;
+    pub const BUFFERS: u64 = 0x4000;
+    pub const MEM_SIZE: usize = 0x10000;
+
+    pub fn memory() -> Arc<GuestMemory> {
+        Arc::new(GuestMemory::new(&[(0, MEM_SIZE)]).unwrap())
+    }
+
+    // A ready queue of `size` entries with its three areas at the given
+    // addresses
+    pub fn config(size: u16, desc_table: u64, avail_ring: u64, used_ring: u64) -> QueueConfig {
+        QueueConfig {
+            max_size: size,
+            size,
+            ready: true,
+            desc_table,
+            avail_ring,
+            used_ring,
+        }
+    }
+
+    // Put a descriptor of either layout at `addr`
+    pub fn write_desc<T: ByteValued>(mem: &GuestMemory, addr: u64, desc: T) {
+        mem.write_obj(desc, addr).unwrap();
+    }
+}
@@ -0,0 +1,528 @@
+// Packed virtqueues (VIRTIO_F_RING_PACKED): a single descriptor ring both
+// sides write to, plus an event suppression structure for each direction.
+//
+// The driver makes a descriptor available by setting its AVAIL flag to its
+// wrap counter and USED to the opposite; the device hands it back by
+// setting both to its own. Each side flips its wrap counter whenever it
+// goes past the end of the ring, so a stale descriptor from the previous
+// lap never looks new.
+
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
+
+use super::{
+    need_event, ChainBuilder, DescriptorChain, Queue, QueueError, Result, DESCRIPTOR_SIZE,
+    MAX_QUEUE_SIZE, VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
+};
+use crate::devices::virtio::QueueConfig;
+use crate::memory::{ByteValued, GuestMemory};
+
+// Descriptor flags only packed rings have
+const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
+const VIRTQ_DESC_F_USED: u16 = 1 << 15;
+
+// Event suppression flags
+const RING_EVENT_FLAGS_ENABLE: u16 = 0x0;
+const RING_EVENT_FLAGS_DISABLE: u16 = 0x1;
+// Only with EVENT_IDX: notify when the ring reaches the given descriptor
+const RING_EVENT_FLAGS_DESC: u16 = 0x2;
+
+// Bit of an event suppression descriptor offset that holds the wrap counter
+const EVENT_WRAP_SHIFT: u16 = 15;
+
+const DESC_RING_ALIGN: u64 = 16;
+const EVENT_ALIGN: u64 = 4;
+
+// Offsets in a descriptor and in an event suppression structure
+const DESC_LEN: u64 = 8;
+const DESC_ID: u64 = 12;
+const DESC_FLAGS: u64 = 14;
+const EVENT_OFF_WRAP: u64 = 0;
+const EVENT_FLAGS: u64 = 2;
+const EVENT_SIZE: u64 = 4;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct PackedDescriptor {
+    addr: u64,
+    len: u32,
+    // Buffer id, in the last descriptor of a chain
+    id: u16,
+    flags: u16,
+}
+
+unsafe impl ByteValued for PackedDescriptor {}
+
+pub struct PackedQueue {
+    mem: Arc<GuestMemory>,
+    size: u16,
+    desc_ring: u64,
+    // Written by the driver: when the device should interrupt it
+    driver_event: u64,
+    // Written by the device: when the driver should notify it
+    device_event: u64,
+    // VIRTIO_F_INDIRECT_DESC was negotiated
+    indirect: bool,
+    // VIRTIO_F_EVENT_IDX was negotiated
+    event_idx: bool,
+    next_avail: u16,
+    avail_wrap: bool,
+    next_used: u16,
+    used_wrap: bool,
+    // Used index and wrap counter when the driver was last interrupted,
+    // for EVENT_IDX
+    signalled_used: Option<(u16, bool)>,
+}
+
+impl PackedQueue {
+    pub fn new(
+        mem: Arc<GuestMemory>,
+        config: &QueueConfig,
+        indirect: bool,
+        event_idx: bool,
+    ) -> Result<Self> {
+        let size = config.size;
+        if size == 0 || size > MAX_QUEUE_SIZE {
+            return Err(QueueError::InvalidSize(size));
+        }
+        let rings = [
+            (
+                "descriptor ring",
+                config.desc_table,
+                DESC_RING_ALIGN,
+                DESCRIPTOR_SIZE * size as u64,
+            ),
+            (
+                "driver event suppression",
+                config.avail_ring,
+                EVENT_ALIGN,
+                EVENT_SIZE,
+            ),
+            (
+                "device event suppression",
+                config.used_ring,
+                EVENT_ALIGN,
+                EVENT_SIZE,
+            ),
+        ];
+        for (ring, addr, align, len) in rings {
+            if addr % align != 0 || mem.check_range(addr, len as usize).is_err() {
+                return Err(QueueError::InvalidRing(ring, addr));
+            }
+        }
+        Ok(PackedQueue {
+            mem,
+            size,
+            desc_ring: config.desc_table,
+            driver_event: config.avail_ring,
+            device_event: config.used_ring,
+            indirect,
+            event_idx,
+            next_avail: 0,
+            avail_wrap: true,
+            next_used: 0,
+            used_wrap: true,
+            signalled_used: None,
+        })
+    }
+
+    fn desc_addr(&self, index: u16) -> u64 {
+        self.desc_ring + index as u64 * DESCRIPTOR_SIZE
+    }
+
+    // Whether the driver made the descriptor at `next_avail` available
+    fn avail_pending(&self) -> Result<bool> {
+        let flags: u16 = self
+            .mem
+            .read_obj(self.desc_addr(self.next_avail) + DESC_FLAGS)?;
+        let avail = flags & VIRTQ_DESC_F_AVAIL != 0;
+        let used = flags & VIRTQ_DESC_F_USED != 0;
+        Ok(avail == self.avail_wrap && used != self.avail_wrap)
+    }
+
+    fn set_device_event(&self, off_wrap: u16, flags: u16) -> Result<()> {
+        self.mem
+            .write_obj(off_wrap, self.device_event + EVENT_OFF_WRAP)?;
+        self.mem.write_obj(flags, self.device_event + EVENT_FLAGS)?;
+        Ok(())
+    }
+
+    // The descriptors of an indirect table, in order. Chaining inside the
+    // table is implied, so NEXT doesn't matter there.
+    fn read_indirect(&self, chain: &mut ChainBuilder, desc: &PackedDescriptor) -> Result<()> {
+        if !self.indirect {
+            return Err(QueueError::InvalidIndirect("not negotiated"));
+        }
+        if desc.flags & VIRTQ_DESC_F_NEXT != 0 {
+            return Err(QueueError::InvalidIndirect("table with a next descriptor"));
+        }
+        let (table, entries) = chain.indirect_table(desc.addr, desc.len)?;
+        for index in 0..entries as u64 {
+            let desc: PackedDescriptor = self.mem.read_obj(table + index * DESCRIPTOR_SIZE)?;
+            if desc.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                return Err(QueueError::InvalidIndirect("nested table"));
+            }
+            chain.push(desc.addr, desc.len, desc.flags & VIRTQ_DESC_F_WRITE != 0)?;
+        }
+        Ok(())
+    }
+}
+
+impl Queue for PackedQueue {
+    fn pop(&mut self) -> Result<Option<DescriptorChain>> {
+        if !self.avail_pending()? {
+            return Ok(None);
+        }
+        // Don't read the descriptors before the flags that made them
+        // available
+        fence(Ordering::Acquire);
+
+        // The driver makes the rest of the chain available before the first
+        // descriptor, so it is all there now
+        let mut chain = ChainBuilder::new(&self.mem, self.size);
+        let mut index = self.next_avail;
+        let mut wrap = self.avail_wrap;
+        let mut ring_entries = 0;
+        loop {
+            if ring_entries == self.size {
+                return Err(QueueError::ChainTooLong);
+            }
+            let desc: PackedDescriptor = self.mem.read_obj(self.desc_addr(index))?;
+            ring_entries += 1;
+            index += 1;
+            if index == self.size {
+                index = 0;
+                wrap = !wrap;
+            }
+
+            if desc.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                self.read_indirect(&mut chain, &desc)?;
+            } else {
+                chain.push(desc.addr, desc.len, desc.flags & VIRTQ_DESC_F_WRITE != 0)?;
+            }
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                self.next_avail = index;
+                self.avail_wrap = wrap;
+                return Ok(Some(chain.finish(desc.id, ring_entries)));
+            }
+        }
+    }
+
+    // Used descriptors go into the ring in the order chains complete, each
+    // skipping as many entries as its chain took up
+    fn add_used(&mut self, chain: &DescriptorChain, len: u32) -> Result<()> {
+        let desc = self.desc_addr(self.next_used);
+        self.mem.write_obj(len, desc + DESC_LEN)?;
+        self.mem.write_obj(chain.id(), desc + DESC_ID)?;
+
+        let mut flags = 0;
+        if self.used_wrap {
+            flags |= VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED;
+        }
+        if len > 0 {
+            flags |= VIRTQ_DESC_F_WRITE;
+        }
+        // The driver must see id and length before the flags that hand the
+        // descriptor over
+        fence(Ordering::Release);
+        self.mem.write_obj(flags, desc + DESC_FLAGS)?;
+
+        self.next_used += chain.ring_entries;
+        if self.next_used >= self.size {
+            self.next_used -= self.size;
+            self.used_wrap = !self.used_wrap;
+        }
+        Ok(())
+    }
+
+    fn needs_notification(&mut self) -> Result<bool> {
+        // Order the used descriptor writes before reading what the driver
+        // wants
+        fence(Ordering::SeqCst);
+        let signalled = self
+            .signalled_used
+            .replace((self.next_used, self.used_wrap));
+        let flags: u16 = self.mem.read_obj(self.driver_event + EVENT_FLAGS)?;
+        match flags {
+            RING_EVENT_FLAGS_DISABLE => return Ok(false),
+            RING_EVENT_FLAGS_DESC if self.event_idx => {}
+            _ => return Ok(true),
+        }
+
+        let off_wrap: u16 = self.mem.read_obj(self.driver_event + EVENT_OFF_WRAP)?;
+        let (old, old_wrap) = match signalled {
+            Some(old) => old,
+            None => return Ok(true),
+        };
+        // Indexes from the previous lap count as negative
+        let event_wrap = off_wrap >> EVENT_WRAP_SHIFT != 0;
+        let mut event = off_wrap & !(1 << EVENT_WRAP_SHIFT);
+        if event_wrap != self.used_wrap {
+            event = event.wrapping_sub(self.size);
+        }
+        let old = if old_wrap != self.used_wrap {
+            old.wrapping_sub(self.size)
+        } else {
+            old
+        };
+        Ok(need_event(event, self.next_used, old))
+    }
+
+    fn enable_notification(&mut self) -> Result<bool> {
+        if self.event_idx {
+            let off_wrap = self.next_avail | (self.avail_wrap as u16) << EVENT_WRAP_SHIFT;
+            self.set_device_event(off_wrap, RING_EVENT_FLAGS_DESC)?;
+        } else {
+            self.set_device_event(0, RING_EVENT_FLAGS_ENABLE)?;
+        }
+        // The driver may have added buffers before it saw the change
+        fence(Ordering::SeqCst);
+        self.avail_pending()
+    }
+
+    fn disable_notification(&mut self) -> Result<()> {
+        self.set_device_event(0, RING_EVENT_FLAGS_DISABLE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_utils::{config, memory, write_desc, BUFFERS, INDIRECT_TABLE, MEM_SIZE};
+    use super::*;
+
+    const SIZE: u16 = 4;
+    const DESC_RING: u64 = 0x0;
+    const DRIVER_EVENT: u64 = 0x1000;
+    const DEVICE_EVENT: u64 = 0x1010;
+
+    fn queue(indirect: bool, event_idx: bool) -> PackedQueue {
+        let config = config(SIZE, DESC_RING, DRIVER_EVENT, DEVICE_EVENT);
+        PackedQueue::new(memory(), &config, indirect, event_idx).unwrap()
+    }
+
+    // Flags that make a descriptor available on the driver's `wrap` lap
+    fn avail_flags(wrap: bool) -> u16 {
+        if wrap {
+            VIRTQ_DESC_F_AVAIL
+        } else {
+            VIRTQ_DESC_F_USED
+        }
+    }
+
+    fn desc(addr: u64, len: u32, id: u16, flags: u16) -> PackedDescriptor {
+        PackedDescriptor {
+            addr,
+            len,
+            id,
+            flags,
+        }
+    }
+
+    fn read_desc(q: &PackedQueue, index: u16) -> PackedDescriptor {
+        q.mem.read_obj(q.desc_addr(index)).unwrap()
+    }
+
+    fn set_driver_event(q: &PackedQueue, off_wrap: u16, flags: u16) {
+        q.mem
+            .write_obj(off_wrap, DRIVER_EVENT + EVENT_OFF_WRAP)
+            .unwrap();
+        q.mem.write_obj(flags, DRIVER_EVENT + EVENT_FLAGS).unwrap();
+    }
+
+    #[test]
+    fn wrap_counter_flips_at_end_of_ring() {
+        let mut q = queue(false, false);
+        for index in 0..SIZE {
+            write_desc(
+                &q.mem,
+                q.desc_addr(index),
+                desc(BUFFERS, 0x10, index, avail_flags(true)),
+            );
+        }
+        for index in 0..SIZE {
+            let chain = q.pop().unwrap().unwrap();
+            assert_eq!(chain.id(), index);
+            q.add_used(&chain, 0).unwrap();
+        }
+        assert_eq!((q.next_avail, q.avail_wrap), (0, false));
+        assert_eq!((q.next_used, q.used_wrap), (0, false));
+
+        // What the device handed back on the last lap isn't new
+        assert!(q.pop().unwrap().is_none());
+
+        write_desc(
+            &q.mem,
+            q.desc_addr(0),
+            desc(BUFFERS, 0x10, 7, avail_flags(false)),
+        );
+        let chain = q.pop().unwrap().unwrap();
+        assert_eq!(chain.id(), 7);
+        q.add_used(&chain, 0).unwrap();
+        // Used on the second lap: AVAIL and USED both clear
+        assert_eq!(read_desc(&q, 0).flags, 0);
+    }
+
+    #[test]
+    fn out_of_order_used_skips_ring_entries() {
+        let mut q = queue(false, false);
+        let flags = avail_flags(true);
+        write_desc(
+            &q.mem,
+            q.desc_addr(0),
+            desc(BUFFERS, 0x10, 0, flags | VIRTQ_DESC_F_NEXT),
+        );
+        write_desc(&q.mem, q.desc_addr(1), desc(BUFFERS, 0x10, 1, flags));
+        write_desc(&q.mem, q.desc_addr(2), desc(BUFFERS, 0x10, 2, flags));
+        let first = q.pop().unwrap().unwrap();
+        let second = q.pop().unwrap().unwrap();
+        assert_eq!((first.id(), first.ring_entries), (1, 2));
+        assert_eq!((second.id(), second.ring_entries), (2, 1));
+
+        q.add_used(&second, 0x10).unwrap();
+        assert_eq!(q.next_used, 1);
+        q.add_used(&first, 0).unwrap();
+        assert_eq!(q.next_used, 3);
+
+        let used = read_desc(&q, 0);
+        assert_eq!((used.id, used.len), (2, 0x10));
+        assert_eq!(
+            used.flags,
+            VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED | VIRTQ_DESC_F_WRITE
+        );
+        let used = read_desc(&q, 1);
+        assert_eq!((used.id, used.len), (1, 0));
+        assert_eq!(used.flags, VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED);
+    }
+
+    fn device_event(q: &PackedQueue) -> (u16, u16) {
+        (
+            q.mem.read_obj(DEVICE_EVENT + EVENT_OFF_WRAP).unwrap(),
+            q.mem.read_obj(DEVICE_EVENT + EVENT_FLAGS).unwrap(),
+        )
+    }
+
+    #[test]
+    fn device_event_suppression() {
+        let mut q = queue(false, false);
+        q.disable_notification().unwrap();
+        assert_eq!(device_event(&q).1, RING_EVENT_FLAGS_DISABLE);
+        assert!(!q.enable_notification().unwrap());
+        assert_eq!(device_event(&q), (0, RING_EVENT_FLAGS_ENABLE));
+
+        // With EVENT_IDX the driver is asked to notify for the next entry
+        // the device will look at, on the lap it is in
+        let mut q = queue(false, true);
+        for index in 0..SIZE - 1 {
+            write_desc(
+                &q.mem,
+                q.desc_addr(index),
+                desc(BUFFERS, 0x10, index, avail_flags(true)),
+            );
+            q.pop().unwrap().unwrap();
+        }
+        assert!(!q.enable_notification().unwrap());
+        assert_eq!(
+            device_event(&q),
+            (3 | 1 << EVENT_WRAP_SHIFT, RING_EVENT_FLAGS_DESC)
+        );
+        // A buffer that came in meanwhile is reported
+        write_desc(
+            &q.mem,
+            q.desc_addr(3),
+            desc(BUFFERS, 0x10, 3, avail_flags(true)),
+        );
+        assert!(q.enable_notification().unwrap());
+        q.pop().unwrap().unwrap();
+        assert!(!q.enable_notification().unwrap());
+        assert_eq!(device_event(&q), (0, RING_EVENT_FLAGS_DESC));
+    }
+
+    #[test]
+    fn driver_event_flags() {
+        let mut q = queue(false, false);
+        write_desc(
+            &q.mem,
+            q.desc_addr(0),
+            desc(BUFFERS, 0x10, 0, avail_flags(true)),
+        );
+        let chain = q.pop().unwrap().unwrap();
+        set_driver_event(&q, 0, RING_EVENT_FLAGS_DISABLE);
+        q.add_used(&chain, 0).unwrap();
+        assert!(!q.needs_notification().unwrap());
+        set_driver_event(&q, 0, RING_EVENT_FLAGS_ENABLE);
+        assert!(q.needs_notification().unwrap());
+    }
+
+    #[test]
+    fn event_desc_across_laps() {
+        let mut q = queue(false, true);
+        write_desc(
+            &q.mem,
+            q.desc_addr(0),
+            desc(BUFFERS, 0x10, 0, avail_flags(true)),
+        );
+        let chain = q.pop().unwrap().unwrap();
+
+        q.next_used = 3;
+        q.signalled_used = Some((3, true));
+        // The driver wants to hear about entry 3 of this lap, which the
+        // device passes on its way into the next one
+        set_driver_event(&q, 3 | 1 << EVENT_WRAP_SHIFT, RING_EVENT_FLAGS_DESC);
+        q.add_used(&chain, 0).unwrap();
+        q.add_used(&chain, 0).unwrap();
+        assert_eq!((q.next_used, q.used_wrap), (1, false));
+        assert!(q.needs_notification().unwrap());
+
+        // Entry 1 of the previous lap is long gone
+        set_driver_event(&q, 1 | 1 << EVENT_WRAP_SHIFT, RING_EVENT_FLAGS_DESC);
+        q.add_used(&chain, 0).unwrap();
+        assert!(!q.needs_notification().unwrap());
+
+        // Entry 2 of this lap was just used
+        set_driver_event(&q, 2, RING_EVENT_FLAGS_DESC);
+        q.add_used(&chain, 0).unwrap();
+        assert!(q.needs_notification().unwrap());
+    }
+
+    #[test]
+    fn indirect_ignores_next() {
+        let mut q = queue(true, false);
+        for index in 0..3 {
+            let flags = VIRTQ_DESC_F_NEXT | if index == 2 { VIRTQ_DESC_F_WRITE } else { 0 };
+            write_desc(
+                &q.mem,
+                INDIRECT_TABLE + index * DESCRIPTOR_SIZE,
+                desc(BUFFERS, 0x10, 0, flags),
+            );
+        }
+        write_desc(
+            &q.mem,
+            q.desc_addr(0),
+            desc(
+                INDIRECT_TABLE,
+                3 * DESCRIPTOR_SIZE as u32,
+                5,
+                avail_flags(true) | VIRTQ_DESC_F_INDIRECT,
+            ),
+        );
+
+        let chain = q.pop().unwrap().unwrap();
+        assert_eq!((chain.id(), chain.ring_entries), (5, 1));
+        assert_eq!(chain.descriptors().len(), 3);
+        assert!(chain.descriptors()[2].writable);
+        assert_eq!(q.next_avail, 1);
+    }
+
+    #[test]
+    fn broken_chain_is_not_consumed() {
+        let mut q = queue(false, false);
+        write_desc(
+            &q.mem,
+            q.desc_addr(0),
+            desc(MEM_SIZE as u64, 0x10, 0, avail_flags(true)),
+        );
+        assert!(matches!(q.pop(), Err(QueueError::InvalidBuffer { .. })));
+        assert_eq!((q.next_avail, q.avail_wrap), (0, true));
+        assert!(q.pop().is_err());
+    }
+}
@@ -0,0 +1,507 @@
+// Split virtqueues: a descriptor table, the available ring the driver puts
+// chain heads on and the used ring the device returns them on.
+//
+// With VIRTIO_F_EVENT_IDX each side also publishes the ring index it wants
+// to hear about next, at the end of the other side's ring, instead of
+// turning notifications on and off with a flag.
+
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
+
+use super::{
+    need_event, ChainBuilder, DescriptorChain, Queue, QueueError, Result, DESCRIPTOR_SIZE,
+    MAX_QUEUE_SIZE, VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
+};
+use crate::devices::virtio::QueueConfig;
+use crate::memory::{ByteValued, GuestMemory};
+
+// Driver: no interrupts for used buffers, please
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 0x1;
+// Device: no notifications for available buffers, please
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 0x1;
+
+const DESC_TABLE_ALIGN: u64 = 16;
+const AVAIL_RING_ALIGN: u64 = 2;
+const USED_RING_ALIGN: u64 = 4;
+
+// Both rings start with 16-bit flags and index fields
+const RING_FLAGS: u64 = 0;
+const RING_IDX: u64 = 2;
+const RING_ENTRIES: u64 = 4;
+
+const AVAIL_ENTRY_SIZE: u64 = 2;
+const USED_ENTRY_SIZE: u64 = 8;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct SplitDescriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct UsedElem {
+    // Head of the chain
+    id: u32,
+    len: u32,
+}
+
+unsafe impl ByteValued for SplitDescriptor {}
+unsafe impl ByteValued for UsedElem {}
+
+pub struct SplitQueue {
+    mem: Arc<GuestMemory>,
+    size: u16,
+    desc_table: u64,
+    avail_ring: u64,
+    used_ring: u64,
+    // VIRTIO_F_INDIRECT_DESC was negotiated
+    indirect: bool,
+    // VIRTIO_F_EVENT_IDX was negotiated
+    event_idx: bool,
+    next_avail: u16,
+    next_used: u16,
+    // Used index when the driver was last interrupted, for EVENT_IDX
+    signalled_used: Option<u16>,
+}
+
+impl SplitQueue {
+    pub fn new(
+        mem: Arc<GuestMemory>,
+        config: &QueueConfig,
+        indirect: bool,
+        event_idx: bool,
+    ) -> Result<Self> {
+        let size = config.size;
+        if size == 0 || size > MAX_QUEUE_SIZE || !size.is_power_of_two() {
+            return Err(QueueError::InvalidSize(size));
+        }
+        let rings = [
+            (
+                "descriptor table",
+                config.desc_table,
+                DESC_TABLE_ALIGN,
+                DESCRIPTOR_SIZE * size as u64,
+            ),
+            (
+                "available ring",
+                config.avail_ring,
+                AVAIL_RING_ALIGN,
+                RING_ENTRIES + AVAIL_ENTRY_SIZE * size as u64 + 2,
+            ),
+            (
+                "used ring",
+                config.used_ring,
+                USED_RING_ALIGN,
+                RING_ENTRIES + USED_ENTRY_SIZE * size as u64 + 2,
+            ),
+        ];
+        for (ring, addr, align, len) in rings {
+            if addr % align != 0 || mem.check_range(addr, len as usize).is_err() {
+                return Err(QueueError::InvalidRing(ring, addr));
+            }
+        }
+        Ok(SplitQueue {
+            mem,
+            size,
+            desc_table: config.desc_table,
+            avail_ring: config.avail_ring,
+            used_ring: config.used_ring,
+            indirect,
+            event_idx,
+            next_avail: 0,
+            next_used: 0,
+            signalled_used: None,
+        })
+    }
+
+    fn avail_idx(&self) -> Result<u16> {
+        Ok(self.mem.read_obj(self.avail_ring + RING_IDX)?)
+    }
+
+    // used_event sits after the available ring's entries
+    fn used_event(&self) -> Result<u16> {
+        let addr = self.avail_ring + RING_ENTRIES + AVAIL_ENTRY_SIZE * self.size as u64;
+        Ok(self.mem.read_obj(addr)?)
+    }
+
+    // avail_event sits after the used ring's entries
+    fn set_avail_event(&self, idx: u16) -> Result<()> {
+        let addr = self.used_ring + RING_ENTRIES + USED_ENTRY_SIZE * self.size as u64;
+        Ok(self.mem.write_obj(idx, addr)?)
+    }
+
+    fn set_used_flags(&self, flags: u16) -> Result<()> {
+        Ok(self.mem.write_obj(flags, self.used_ring + RING_FLAGS)?)
+    }
+
+    // Walk the chain starting at descriptor `head`, following at most one
+    // indirect table
+    fn read_chain(&self, head: u16) -> Result<DescriptorChain> {
+        let mut chain = ChainBuilder::new(&self.mem, self.size);
+        let mut table = self.desc_table;
+        let mut table_len = self.size;
+        let mut in_indirect = false;
+        let mut index = head;
+        let mut visited = 0;
+        loop {
+            if index >= table_len {
+                return Err(QueueError::InvalidDescriptorIndex(index));
+            }
+            visited += 1;
+            if visited > table_len {
+                return Err(QueueError::DescriptorLoop);
+            }
+            let desc: SplitDescriptor =
+                self.mem.read_obj(table + index as u64 * DESCRIPTOR_SIZE)?;
+
+            if desc.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                if !self.indirect {
+                    return Err(QueueError::InvalidIndirect("not negotiated"));
+                }
+                if in_indirect {
+                    return Err(QueueError::InvalidIndirect("nested table"));
+                }
+                if desc.flags & VIRTQ_DESC_F_NEXT != 0 {
+                    return Err(QueueError::InvalidIndirect("table with a next descriptor"));
+                }
+                (table, table_len) = chain.indirect_table(desc.addr, desc.len)?;
+                in_indirect = true;
+                index = 0;
+                visited = 0;
+                continue;
+            }
+
+            chain.push(desc.addr, desc.len, desc.flags & VIRTQ_DESC_F_WRITE != 0)?;
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Ok(chain.finish(head, 1));
+            }
+            index = desc.next;
+        }
+    }
+}
+
+impl Queue for SplitQueue {
+    fn pop(&mut self) -> Result<Option<DescriptorChain>> {
+        let avail_idx = self.avail_idx()?;
+        let pending = avail_idx.wrapping_sub(self.next_avail);
+        if pending > self.size {
+            return Err(QueueError::InvalidAvailIndex(avail_idx));
+        }
+        if pending == 0 {
+            return Ok(None);
+        }
+        // Don't read the entry before the index that made it available
+        fence(Ordering::Acquire);
+
+        let entry = self.avail_ring
+            + RING_ENTRIES
+            + AVAIL_ENTRY_SIZE * (self.next_avail % self.size) as u64;
+        let head: u16 = self.mem.read_obj(entry)?;
+        // A broken chain stays on the ring, as on a packed queue
+        let chain = self.read_chain(head)?;
+        self.next_avail = self.next_avail.wrapping_add(1);
+        Ok(Some(chain))
+    }
+
+    fn add_used(&mut self, chain: &DescriptorChain, len: u32) -> Result<()> {
+        let entry =
+            self.used_ring + RING_ENTRIES + USED_ENTRY_SIZE * (self.next_used % self.size) as u64;
+        let elem = UsedElem {
+            id: chain.id() as u32,
+            len,
+        };
+        self.mem.write_obj(elem, entry)?;
+        self.next_used = self.next_used.wrapping_add(1);
+        // The driver must see the entry before the index that hands it over
+        fence(Ordering::Release);
+        self.mem
+            .write_obj(self.next_used, self.used_ring + RING_IDX)?;
+        Ok(())
+    }
+
+    fn needs_notification(&mut self) -> Result<bool> {
+        // Order the used index write before reading what the driver wants
+        fence(Ordering::SeqCst);
+        if !self.event_idx {
+            let flags: u16 = self.mem.read_obj(self.avail_ring + RING_FLAGS)?;
+            return Ok(flags & VIRTQ_AVAIL_F_NO_INTERRUPT == 0);
+        }
+        let used_event = self.used_event()?;
+        Ok(match self.signalled_used.replace(self.next_used) {
+            Some(old) => need_event(used_event, self.next_used, old),
+            None => true,
+        })
+    }
+
+    fn enable_notification(&mut self) -> Result<bool> {
+        if self.event_idx {
+            self.set_avail_event(self.next_avail)?;
+        } else {
+            self.set_used_flags(0)?;
+        }
+        // The driver may have added buffers before it saw the change
+        fence(Ordering::SeqCst);
+        Ok(self.avail_idx()? != self.next_avail)
+    }
+
+    fn disable_notification(&mut self) -> Result<()> {
+        // With EVENT_IDX the driver won't notify again until the device
+        // moves avail_event on
+        if self.event_idx {
+            return Ok(());
+        }
+        self.set_used_flags(VIRTQ_USED_F_NO_NOTIFY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_utils::{config, memory, write_desc, BUFFERS, INDIRECT_TABLE, MEM_SIZE};
+    use super::*;
+
+    const SIZE: u16 = 16;
+    const DESC_TABLE: u64 = 0x0;
+    const AVAIL_RING: u64 = 0x1000;
+    const USED_RING: u64 = 0x2000;
+
+    fn queue(indirect: bool, event_idx: bool) -> SplitQueue {
+        let config = config(SIZE, DESC_TABLE, AVAIL_RING, USED_RING);
+        SplitQueue::new(memory(), &config, indirect, event_idx).unwrap()
+    }
+
+    fn set_desc(q: &SplitQueue, table: u64, index: u16, desc: SplitDescriptor) {
+        write_desc(&q.mem, table + index as u64 * DESCRIPTOR_SIZE, desc);
+    }
+
+    fn desc(addr: u64, len: u32, flags: u16, next: u16) -> SplitDescriptor {
+        SplitDescriptor {
+            addr,
+            len,
+            flags,
+            next,
+        }
+    }
+
+    // Put `head` on the available ring
+    fn make_avail(q: &SplitQueue, head: u16) {
+        let idx = q.avail_idx().unwrap();
+        let entry = AVAIL_RING + RING_ENTRIES + AVAIL_ENTRY_SIZE * (idx % SIZE) as u64;
+        q.mem.write_obj(head, entry).unwrap();
+        q.mem
+            .write_obj(idx.wrapping_add(1), AVAIL_RING + RING_IDX)
+            .unwrap();
+    }
+
+    #[test]
+    fn pop_chain() {
+        let mut q = queue(false, false);
+        set_desc(&q, DESC_TABLE, 3, desc(BUFFERS, 0x10, VIRTQ_DESC_F_NEXT, 5));
+        set_desc(
+            &q,
+            DESC_TABLE,
+            5,
+            desc(BUFFERS + 0x10, 0x20, VIRTQ_DESC_F_WRITE, 0),
+        );
+        make_avail(&q, 3);
+
+        let chain = q.pop().unwrap().unwrap();
+        assert_eq!(chain.id(), 3);
+        assert_eq!(chain.descriptors().len(), 2);
+        assert!(!chain.descriptors()[0].writable);
+        assert!(chain.descriptors()[1].writable);
+        assert!(q.pop().unwrap().is_none());
+    }
+
+    #[test]
+    fn buffer_out_of_range() {
+        let mut q = queue(false, false);
+        set_desc(&q, DESC_TABLE, 0, desc(MEM_SIZE as u64 - 0x10, 0x20, 0, 0));
+        make_avail(&q, 0);
+        assert!(matches!(q.pop(), Err(QueueError::InvalidBuffer { .. })));
+    }
+
+    #[test]
+    fn head_out_of_range() {
+        let mut q = queue(false, false);
+        make_avail(&q, SIZE);
+        assert!(matches!(
+            q.pop(),
+            Err(QueueError::InvalidDescriptorIndex(SIZE))
+        ));
+    }
+
+    #[test]
+    fn descriptor_loop() {
+        let mut q = queue(false, false);
+        set_desc(&q, DESC_TABLE, 0, desc(BUFFERS, 0x10, VIRTQ_DESC_F_NEXT, 1));
+        set_desc(&q, DESC_TABLE, 1, desc(BUFFERS, 0x10, VIRTQ_DESC_F_NEXT, 0));
+        make_avail(&q, 0);
+        assert!(matches!(q.pop(), Err(QueueError::DescriptorLoop)));
+    }
+
+    #[test]
+    fn indirect_table() {
+        let mut q = queue(true, false);
+        set_desc(
+            &q,
+            DESC_TABLE,
+            0,
+            desc(
+                INDIRECT_TABLE,
+                2 * DESCRIPTOR_SIZE as u32,
+                VIRTQ_DESC_F_INDIRECT,
+                0,
+            ),
+        );
+        set_desc(
+            &q,
+            INDIRECT_TABLE,
+            0,
+            desc(BUFFERS, 0x10, VIRTQ_DESC_F_NEXT, 1),
+        );
+        set_desc(
+            &q,
+            INDIRECT_TABLE,
+            1,
+            desc(BUFFERS, 0x10, VIRTQ_DESC_F_WRITE, 0),
+        );
+        make_avail(&q, 0);
+
+        let chain = q.pop().unwrap().unwrap();
+        assert_eq!(chain.id(), 0);
+        assert_eq!(chain.descriptors().len(), 2);
+    }
+
+    #[test]
+    fn indirect_not_negotiated() {
+        let mut q = queue(false, false);
+        set_desc(
+            &q,
+            DESC_TABLE,
+            0,
+            desc(
+                INDIRECT_TABLE,
+                DESCRIPTOR_SIZE as u32,
+                VIRTQ_DESC_F_INDIRECT,
+                0,
+            ),
+        );
+        set_desc(&q, INDIRECT_TABLE, 0, desc(BUFFERS, 0x10, 0, 0));
+        make_avail(&q, 0);
+        assert!(matches!(q.pop(), Err(QueueError::InvalidIndirect(_))));
+    }
+
+    #[test]
+    fn nested_indirect() {
+        let mut q = queue(true, false);
+        set_desc(
+            &q,
+            DESC_TABLE,
+            0,
+            desc(
+                INDIRECT_TABLE,
+                DESCRIPTOR_SIZE as u32,
+                VIRTQ_DESC_F_INDIRECT,
+                0,
+            ),
+        );
+        set_desc(
+            &q,
+            INDIRECT_TABLE,
+            0,
+            desc(
+                INDIRECT_TABLE,
+                DESCRIPTOR_SIZE as u32,
+                VIRTQ_DESC_F_INDIRECT,
+                0,
+            ),
+        );
+        make_avail(&q, 0);
+        assert!(matches!(q.pop(), Err(QueueError::InvalidIndirect(_))));
+    }
+
+    #[test]
+    fn chain_too_long() {
+        let mut q = queue(true, false);
+        let entries = SIZE + 1;
+        set_desc(
+            &q,
+            DESC_TABLE,
+            0,
+            desc(
+                INDIRECT_TABLE,
+                entries as u32 * DESCRIPTOR_SIZE as u32,
+                VIRTQ_DESC_F_INDIRECT,
+                0,
+            ),
+        );
+        for index in 0..entries {
+            set_desc(
+                &q,
+                INDIRECT_TABLE,
+                index,
+                desc(BUFFERS, 0x10, VIRTQ_DESC_F_NEXT, index + 1),
+            );
+        }
+        make_avail(&q, 0);
+        assert!(matches!(q.pop(), Err(QueueError::ChainTooLong)));
+    }
+
+    #[test]
+    fn bad_avail_index() {
+        let mut q = queue(false, false);
+        q.mem.write_obj(SIZE + 1, AVAIL_RING + RING_IDX).unwrap();
+        assert!(matches!(
+            q.pop(),
+            Err(QueueError::InvalidAvailIndex(idx)) if idx == SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn broken_chain_is_not_consumed() {
+        let mut q = queue(false, false);
+        set_desc(&q, DESC_TABLE, 0, desc(MEM_SIZE as u64, 0x10, 0, 0));
+        make_avail(&q, 0);
+        assert!(q.pop().is_err());
+        assert_eq!(q.next_avail, 0);
+        assert!(q.pop().is_err());
+    }
+
+    #[test]
+    fn used_event_across_wrap() {
+        let mut q = queue(false, true);
+        set_desc(&q, DESC_TABLE, 0, desc(BUFFERS, 0x10, 0, 0));
+        let chain = {
+            make_avail(&q, 0);
+            q.pop().unwrap().unwrap()
+        };
+        let used_event = RING_ENTRIES + AVAIL_ENTRY_SIZE * SIZE as u64;
+
+        q.next_used = 0xfffe;
+        q.signalled_used = Some(0xfffe);
+        // The driver wants to hear about index 0xffff being used, which the
+        // used index passes on its way to 1
+        q.mem.write_obj(0xffffu16, AVAIL_RING + used_event).unwrap();
+        q.add_used(&chain, 0).unwrap();
+        q.add_used(&chain, 0).unwrap();
+        q.add_used(&chain, 0).unwrap();
+        assert_eq!(q.next_used, 1);
+        assert!(q.needs_notification().unwrap());
+
+        // Already past 0, so moving on to 3 doesn't cross it
+        q.mem.write_obj(0u16, AVAIL_RING + used_event).unwrap();
+        q.add_used(&chain, 0).unwrap();
+        q.add_used(&chain, 0).unwrap();
+        assert!(!q.needs_notification().unwrap());
+
+        // ... but crossing 4 on the way to 5 does
+        q.mem.write_obj(4u16, AVAIL_RING + used_event).unwrap();
+        q.add_used(&chain, 0).unwrap();
+        q.add_used(&chain, 0).unwrap();
+        assert!(q.needs_notification().unwrap());
+    }
+}
@@ -0,0 +1,187 @@
+// Dirty page tracking for live migration and incremental snapshots.
+//
+// Guest writes are logged by KVM per memory slot (KVM_MEM_LOG_DIRTY_PAGES),
+// writes made by the VMM itself (device emulation, loaders) are logged in
+// each region's userspace bitmap. A DirtyTracker merges both into one
+// bitmap per slot for the current round.
+
+use kvm_ioctls::VmFd;
+
+use crate::layout::PAGE_SIZE;
+use crate::memory::GuestMemory;
+
+// Dirty pages of one memory slot for the current round
+#[derive(Debug)]
+struct SlotBitmap {
+    slot: u32,
+    guest_base: u64,
+    size: usize,
+    bitmap: Vec<u64>,
+}
+
+impl SlotBitmap {
+    fn merge(&mut self, bits: &[u64]) {
+        for (word, bits) in self.bitmap.iter_mut().zip(bits) {
+            *word |= bits;
+        }
+    }
+
+    fn pages(&self) -> impl Iterator<Item = u64> + '_ {
+        self.bitmap
+            .iter()
+            .enumerate()
+            .flat_map(move |(index, word)| {
+                let base = self.guest_base + (index as u64 * 64) * PAGE_SIZE;
+                SetBits(*word).map(move |bit| base + bit as u64 * PAGE_SIZE)
+            })
+    }
+}
+
+// Iterator over the indices of the set bits of a word, lowest first
+struct SetBits(u64);
+
+impl Iterator for SetBits {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.0 == 0 {
+            return None;
+        }
+        let bit = self.0.trailing_zeros();
+        self.0 &= self.0 - 1;
+        Some(bit)
+    }
+}
+
+// Collects the guest pages written since the last reset. Expects memory
+// region N to be registered as KVM slot N, as the VMM does.
+#[derive(Debug)]
+pub struct DirtyTracker {
+    slots: Vec<SlotBitmap>,
+}
+
+impl DirtyTracker {
+    pub fn new(memory: &GuestMemory) -> Self {
+        let slots = memory
+            .regions()
+            .iter()
+            .enumerate()
+            .map(|(slot, region)| {
+                let pages = region.size().div_ceil(PAGE_SIZE as usize);
+                SlotBitmap {
+                    slot: slot as u32,
+                    guest_base: region.guest_base(),
+                    size: region.size(),
+                    bitmap: vec![0; pages.div_ceil(64)],
+                }
+            })
+            .collect();
+        DirtyTracker { slots }
+    }
+
+    // Fetch the pages dirtied since the last sync from KVM and from the VMM
+    // and add them to the current round. Fetching clears KVM's log, so
+    // nothing is lost between syncs.
+    pub fn sync(&mut self, vm: &VmFd, memory: &GuestMemory) -> Result<(), kvm_ioctls::Error> {
+        for (slot, region) in self.slots.iter_mut().zip(memory.regions()) {
+            let kvm_bits = vm.get_dirty_log(slot.slot, slot.size)?;
+            slot.merge(&kvm_bits);
+            slot.merge(&region.dirty_bitmap().take());
+        }
+        Ok(())
+    }
+
+    // Guest physical address of every dirty page in this round, in
+    // ascending order
+    pub fn dirty_pages(&self) -> impl Iterator<Item = u64> + '_ {
+        self.slots.iter().flat_map(|slot| slot.pages())
+    }
+
+    pub fn dirty_count(&self) -> usize {
+        self.slots
+            .iter()
+            .flat_map(|slot| &slot.bitmap)
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_dirty(&self, addr: u64) -> bool {
+        self.slots.iter().any(|slot| {
+            if addr < slot.guest_base || addr >= slot.guest_base + slot.size as u64 {
+                return false;
+            }
+            let page = ((addr - slot.guest_base) / PAGE_SIZE) as usize;
+            slot.bitmap[page / 64] & (1 << (page % 64)) != 0
+        })
+    }
+
+    // Forget everything collected so far and start a new round
+    pub fn reset(&mut self) {
+        for slot in &mut self.slots {
+            slot.bitmap.iter_mut().for_each(|word| *word = 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two slots: 130 pages at 0, then 2 pages at 1 MiB
+    fn tracker() -> DirtyTracker {
+        let memory = GuestMemory::new(&[(0, 130 * 0x1000), (0x10_0000, 0x2000)]).unwrap();
+        DirtyTracker::new(&memory)
+    }
+
+    #[test]
+    fn new_sizes_bitmaps_per_slot() {
+        let tracker = tracker();
+        let words: Vec<usize> = tracker.slots.iter().map(|s| s.bitmap.len()).collect();
+        assert_eq!(words, [3, 1]);
+        assert_eq!(tracker.dirty_count(), 0);
+        assert_eq!(tracker.dirty_pages().count(), 0);
+    }
+
+    #[test]
+    fn merge_ors_bitmaps() {
+        let mut tracker = tracker();
+        tracker.slots[0].merge(&[0b0101, 0, 1 << 1]);
+        tracker.slots[0].merge(&[0b0011, 1 << 63, 0]);
+        assert_eq!(tracker.slots[0].bitmap, [0b0111, 1 << 63, 1 << 1]);
+        assert_eq!(tracker.dirty_count(), 5);
+    }
+
+    #[test]
+    fn dirty_pages_in_address_order() {
+        let mut tracker = tracker();
+        tracker.slots[1].merge(&[0b10]);
+        tracker.slots[0].merge(&[1 << 63 | 1, 0, 1 << 1]);
+        let pages: Vec<u64> = tracker.dirty_pages().collect();
+        assert_eq!(pages, [0x0, 63 * 0x1000, 129 * 0x1000, 0x10_1000]);
+        assert!(tracker.is_dirty(0x0fff));
+        assert!(tracker.is_dirty(129 * 0x1000 + 0x10));
+        assert!(!tracker.is_dirty(0x1000));
+        assert!(!tracker.is_dirty(0x10_0000));
+        assert!(tracker.is_dirty(0x10_1000));
+        // Outside every slot
+        assert!(!tracker.is_dirty(0x20_0000));
+    }
+
+    #[test]
+    fn reset_clears_round() {
+        let mut tracker = tracker();
+        tracker.slots[0].merge(&[!0, !0, 0b11]);
+        tracker.slots[1].merge(&[0b11]);
+        assert_eq!(tracker.dirty_count(), 132);
+        tracker.reset();
+        assert_eq!(tracker.dirty_count(), 0);
+        assert_eq!(tracker.dirty_pages().next(), None);
+    }
+
+    #[test]
+    fn set_bits_lowest_first() {
+        let bits: Vec<u32> = SetBits(1 << 63 | 1 << 5 | 1).collect();
+        assert_eq!(bits, [0, 5, 63]);
+        assert_eq!(SetBits(0).next(), None);
+    }
+}
@@ -0,0 +1,192 @@
+// E820 memory map: what the guest may use as RAM and which ranges it must
+// leave alone. Built once from the memory layout and handed to the kernel
+// through the Linux zero page or the PVH memmap.
+
+use std::fmt;
+
+use crate::layout::MemoryLayout;
+use crate::mptable::MPTABLE_START;
+
+// Extended BIOS data area up to the end of conventional memory. The MP
+// table lives at its start.
+pub const EBDA_START: u64 = MPTABLE_START;
+pub const EBDA_END: u64 = 0xa_0000;
+// Where firmware would put ACPI tables. There are none, so it's reserved
+// like the rest of the BIOS area.
+pub const ACPI_TABLES_START: u64 = 0xe_0000;
+pub const ACPI_TABLES_END: u64 = 0xf_0000;
+// System BIOS ROM
+pub const BIOS_START: u64 = 0xf_0000;
+pub const BIOS_END: u64 = 0x10_0000;
+
+// Raw E820 types as the boot protocols encode them
+pub const E820_RAM: u32 = 1;
+pub const E820_RESERVED: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum E820Type {
+    // Usable RAM
+    Ram,
+    // Not RAM, or RAM the guest must not touch
+    Reserved,
+}
+
+impl E820Type {
+    pub fn raw(self) -> u32 {
+        match self {
+            E820Type::Ram => E820_RAM,
+            E820Type::Reserved => E820_RESERVED,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct E820Entry {
+    pub addr: u64,
+    pub size: u64,
+    pub type_: E820Type,
+}
+
+impl E820Entry {
+    pub fn end(&self) -> u64 {
+        self.addr + self.size
+    }
+}
+
+impl fmt::Display for E820Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[0x{:016x}-0x{:016x}] {:?}",
+            self.addr,
+            self.end() - 1,
+            self.type_
+        )
+    }
+}
+
+// E820 entries sorted by address, none overlapping another
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct E820Map {
+    entries: Vec<E820Entry>,
+}
+
+impl E820Map {
+    pub fn new() -> Self {
+        E820Map::default()
+    }
+
+    // The map for a VM with `layout`: low RAM up to the EBDA, the legacy
+    // BIOS areas, the rest of RAM, and the 32-bit MMIO gap
+    pub fn from_layout(layout: &MemoryLayout) -> Result<Self, String> {
+        let mut map = E820Map::new();
+        for &(base, size) in layout.ram_regions() {
+            let end = base + size as u64;
+            if base < BIOS_END {
+                // RAM from 0 always covers the first MiB
+                map.add(base, EBDA_START - base, E820Type::Ram)?;
+                map.add(EBDA_START, EBDA_END - EBDA_START, E820Type::Reserved)?;
+                map.add(
+                    ACPI_TABLES_START,
+                    ACPI_TABLES_END - ACPI_TABLES_START,
+                    E820Type::Reserved,
+                )?;
+                map.add(BIOS_START, BIOS_END - BIOS_START, E820Type::Reserved)?;
+                if end > BIOS_END {
+                    map.add(BIOS_END, end - BIOS_END, E820Type::Ram)?;
+                }
+            } else {
+                map.add(base, size as u64, E820Type::Ram)?;
+            }
+        }
+        let (gap_start, gap_end) = layout.mmio_gap();
+        map.add(gap_start, gap_end - gap_start, E820Type::Reserved)?;
+        Ok(map)
+    }
+
+    // Add [addr, addr + size), which must not overlap any existing entry
+    pub fn add(&mut self, addr: u64, size: u64, type_: E820Type) -> Result<(), String> {
+        if size == 0 || addr.checked_add(size).is_none() {
+            return Err(format!(
+                "Invalid E820 range at 0x{:x}, size 0x{:x}",
+                addr, size
+            ));
+        }
+        let entry = E820Entry { addr, size, type_ };
+        if let Some(other) = self
+            .entries
+            .iter()
+            .find(|other| entry.addr < other.end() && other.addr < entry.end())
+        {
+            return Err(format!("E820 entry {} overlaps {}", entry, other));
+        }
+        let index = self.entries.partition_point(|other| other.addr < addr);
+        self.entries.insert(index, entry);
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[E820Entry] {
+        &self.entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::{MemoryLayoutBuilder, MMIO_GAP_END, MMIO_GAP_START};
+
+    #[test]
+    fn add_keeps_entries_sorted() {
+        let mut map = E820Map::new();
+        map.add(0x2000, 0x1000, E820Type::Ram).unwrap();
+        map.add(0x0, 0x1000, E820Type::Ram).unwrap();
+        // Touching neighbours on both sides is fine
+        map.add(0x1000, 0x1000, E820Type::Reserved).unwrap();
+        let addrs: Vec<u64> = map.entries().iter().map(|entry| entry.addr).collect();
+        assert_eq!(addrs, [0x0, 0x1000, 0x2000]);
+    }
+
+    #[test]
+    fn add_rejects_overlap() {
+        let mut map = E820Map::new();
+        map.add(0x1000, 0x2000, E820Type::Ram).unwrap();
+        // Overlapping the start, the end, contained and containing
+        assert!(map.add(0x0, 0x1001, E820Type::Reserved).is_err());
+        assert!(map.add(0x2fff, 0x1000, E820Type::Reserved).is_err());
+        assert!(map.add(0x1800, 0x100, E820Type::Reserved).is_err());
+        assert!(map.add(0x0, 0x4000, E820Type::Reserved).is_err());
+        assert_eq!(map.entries().len(), 1);
+    }
+
+    #[test]
+    fn add_rejects_bad_ranges() {
+        let mut map = E820Map::new();
+        assert!(map.add(0x1000, 0, E820Type::Ram).is_err());
+        assert!(map.add(u64::MAX - 0xfff, 0x2000, E820Type::Ram).is_err());
+        assert!(map.entries().is_empty());
+    }
+
+    #[test]
+    fn from_layout() {
+        let ram_size = (MMIO_GAP_START + 0x1000_0000) as usize;
+        let layout = MemoryLayoutBuilder::new(ram_size).build().unwrap();
+        let map = E820Map::from_layout(&layout).unwrap();
+        let entries: Vec<(u64, u64, E820Type)> = map
+            .entries()
+            .iter()
+            .map(|entry| (entry.addr, entry.end(), entry.type_))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (0, EBDA_START, E820Type::Ram),
+                (EBDA_START, EBDA_END, E820Type::Reserved),
+                (ACPI_TABLES_START, ACPI_TABLES_END, E820Type::Reserved),
+                (BIOS_START, BIOS_END, E820Type::Reserved),
+                (BIOS_END, MMIO_GAP_START, E820Type::Ram),
+                (MMIO_GAP_START, MMIO_GAP_END, E820Type::Reserved),
+                (MMIO_GAP_END, MMIO_GAP_END + 0x1000_0000, E820Type::Ram),
+            ]
+        );
+    }
+}
@@ -0,0 +1,129 @@
+// EventFd based signaling between device threads and KVM, without a trip
+// through a vCPU thread.
+//
+// An irqfd injects an interrupt whenever its EventFd is written, from any
+// thread. An ioeventfd makes a guest write to a doorbell address complete
+// inside KVM and signal an EventFd instead of exiting to the VMM.
+
+use std::io;
+use std::sync::Arc;
+
+use kvm_ioctls::{IoEventAddress, NoDatamatch, VmFd};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use crate::devices::Interrupt;
+
+// An interrupt raised by writing an EventFd bound to a GSI
+pub struct IrqFd {
+    vm: Arc<VmFd>,
+    gsi: u32,
+    event: EventFd,
+}
+
+impl IrqFd {
+    // Bind a new edge-triggered irqfd to `gsi`
+    pub fn register(vm: Arc<VmFd>, gsi: u32) -> io::Result<Self> {
+        let event = EventFd::new(EFD_NONBLOCK)?;
+        vm.register_irqfd(&event, gsi)?;
+        Ok(IrqFd { vm, gsi, event })
+    }
+
+    pub fn gsi(&self) -> u32 {
+        self.gsi
+    }
+
+    // Writing 1 here raises the interrupt; the handle can be cloned into
+    // other threads
+    pub fn event(&self) -> &EventFd {
+        &self.event
+    }
+}
+
+impl Interrupt for IrqFd {
+    fn trigger(&self) -> io::Result<()> {
+        self.event.write(1)
+    }
+}
+
+impl Drop for IrqFd {
+    fn drop(&mut self) {
+        let _ = self.vm.unregister_irqfd(&self.event, self.gsi);
+    }
+}
+
+// Address space of a doorbell
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoEventSpace {
+    Pio,
+    Mmio,
+}
+
+// A doorbell: guest writes to `addr`, optionally only those of a given
+// 32-bit value, signal `event`
+pub struct IoEventFd {
+    vm: Arc<VmFd>,
+    space: IoEventSpace,
+    addr: u64,
+    datamatch: Option<u32>,
+    event: EventFd,
+}
+
+impl IoEventFd {
+    pub fn register(
+        vm: Arc<VmFd>,
+        space: IoEventSpace,
+        addr: u64,
+        datamatch: Option<u32>,
+    ) -> io::Result<Self> {
+        let ioevent = IoEventFd {
+            vm,
+            space,
+            addr,
+            datamatch,
+            event: EventFd::new(EFD_NONBLOCK)?,
+        };
+        let address = ioevent.address();
+        match datamatch {
+            Some(value) => ioevent
+                .vm
+                .register_ioevent(&ioevent.event, &address, value)?,
+            None => ioevent
+                .vm
+                .register_ioevent(&ioevent.event, &address, NoDatamatch)?,
+        }
+        Ok(ioevent)
+    }
+
+    fn address(&self) -> IoEventAddress {
+        match self.space {
+            IoEventSpace::Pio => IoEventAddress::Pio(self.addr),
+            IoEventSpace::Mmio => IoEventAddress::Mmio(self.addr),
+        }
+    }
+
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    pub fn datamatch(&self) -> Option<u32> {
+        self.datamatch
+    }
+
+    // Readable once the guest rang the doorbell; reading returns how many
+    // times it did since the last read
+    pub fn event(&self) -> &EventFd {
+        &self.event
+    }
+}
+
+impl Drop for IoEventFd {
+    fn drop(&mut self) {
+        let address = self.address();
+        let _ = match self.datamatch {
+            Some(value) => self.vm.unregister_ioevent(&self.event, &address, value),
+            None => self
+                .vm
+                .unregister_ioevent(&self.event, &address, NoDatamatch),
+        };
+    }
+}
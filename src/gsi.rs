@@ -0,0 +1,415 @@
+// GSI routing: what each GSI raises when an irqfd fires or the line is
+// set, an input pin of one of KVM's interrupt controllers or an MSI.
+//
+// KVM_SET_GSI_ROUTING replaces KVM's whole table, so the table is kept here
+// and pushed in full on every change. Changes are made on a copy and only
+// take effect once KVM accepted it, so a device's routes appear or go away
+// all at once, or not at all.
+//
+// GSIs below FIRST_DEVICE_GSI belong to the IOAPIC pins (and PIC pins for
+// the ISA IRQs); devices get GSIs allocated above them, typically for MSIs.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::mem::{size_of, size_of_val};
+use std::sync::{Arc, Mutex};
+
+use kvm_bindings::{
+    kvm_irq_routing, kvm_irq_routing_entry, kvm_irq_routing_irqchip, kvm_irq_routing_msi,
+    KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE, KVM_IRQ_ROUTING_IRQCHIP,
+    KVM_IRQ_ROUTING_MSI,
+};
+use kvm_ioctls::VmFd;
+
+// One past the last IOAPIC pin
+pub const FIRST_DEVICE_GSI: u32 = 24;
+// KVM_MAX_IRQ_ROUTES
+pub const MAX_GSI: u32 = 4096;
+
+// ISA IRQs that reach the PICs as well as the IOAPIC
+const PIC_IRQS: u32 = 16;
+
+// The interrupt controllers KVM emulates in the kernel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IrqChipId {
+    PicMaster,
+    PicSlave,
+    Ioapic,
+}
+
+impl IrqChipId {
+    fn raw(self) -> u32 {
+        match self {
+            IrqChipId::PicMaster => KVM_IRQCHIP_PIC_MASTER,
+            IrqChipId::PicSlave => KVM_IRQCHIP_PIC_SLAVE,
+            IrqChipId::Ioapic => KVM_IRQCHIP_IOAPIC,
+        }
+    }
+}
+
+// Address and data a device writes to raise a message signalled interrupt
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IrqRoute {
+    // Input pin of an in-kernel interrupt controller
+    Irqchip { chip: IrqChipId, pin: u32 },
+    Msi(MsiMessage),
+}
+
+impl IrqRoute {
+    fn to_kvm(self, gsi: u32) -> kvm_irq_routing_entry {
+        let mut entry = kvm_irq_routing_entry {
+            gsi,
+            ..Default::default()
+        };
+        match self {
+            IrqRoute::Irqchip { chip, pin } => {
+                entry.type_ = KVM_IRQ_ROUTING_IRQCHIP;
+                entry.u.irqchip = kvm_irq_routing_irqchip {
+                    irqchip: chip.raw(),
+                    pin,
+                };
+            }
+            IrqRoute::Msi(msi) => {
+                entry.type_ = KVM_IRQ_ROUTING_MSI;
+                entry.u.msi = kvm_irq_routing_msi {
+                    address_lo: msi.address as u32,
+                    address_hi: (msi.address >> 32) as u32,
+                    data: msi.data,
+                    ..Default::default()
+                };
+            }
+        }
+        entry
+    }
+}
+
+#[derive(Debug)]
+pub enum RoutingError {
+    // Every GSI up to MAX_GSI is taken
+    NoFreeGsi,
+    // The GSI is out of range, or not one that was allocated
+    InvalidGsi(u32),
+    // KVM refused the new table; the old one stays in place
+    Kvm(kvm_ioctls::Error),
+}
+
+impl fmt::Display for RoutingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RoutingError::NoFreeGsi => write!(f, "No free GSI left"),
+            RoutingError::InvalidGsi(gsi) => write!(f, "Invalid GSI {}", gsi),
+            RoutingError::Kvm(e) => write!(f, "Failed to set GSI routing: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RoutingError {}
+
+pub type Result<T> = std::result::Result<T, RoutingError>;
+
+// Routes by GSI, plus the GSIs handed out to devices. A GSI can have more
+// than one route: ISA IRQs go to a PIC pin and an IOAPIC pin at once.
+#[derive(Clone, Debug, Default)]
+pub struct RoutingTable {
+    routes: BTreeMap<u32, Vec<IrqRoute>>,
+    allocated: BTreeSet<u32>,
+}
+
+impl RoutingTable {
+    // KVM's default routing for the full in-kernel irqchip: GSIs 0-15 to
+    // both PICs and the IOAPIC, 16-23 to the IOAPIC only
+    fn kvm_default() -> Self {
+        let mut table = RoutingTable::default();
+        for gsi in 0..FIRST_DEVICE_GSI {
+            let mut routes = Vec::new();
+            if gsi < PIC_IRQS {
+                let chip = if gsi < 8 {
+                    IrqChipId::PicMaster
+                } else {
+                    IrqChipId::PicSlave
+                };
+                routes.push(IrqRoute::Irqchip { chip, pin: gsi % 8 });
+            }
+            routes.push(IrqRoute::Irqchip {
+                chip: IrqChipId::Ioapic,
+                pin: gsi,
+            });
+            table.routes.insert(gsi, routes);
+        }
+        table
+    }
+
+    // Hand out the lowest GSI no device is using, without any routes yet
+    pub fn allocate(&mut self) -> Result<u32> {
+        let gsi = (FIRST_DEVICE_GSI..MAX_GSI)
+            .find(|gsi| !self.allocated.contains(gsi))
+            .ok_or(RoutingError::NoFreeGsi)?;
+        self.allocated.insert(gsi);
+        Ok(gsi)
+    }
+
+    // Give the GSI back, dropping its routes
+    pub fn free(&mut self, gsi: u32) -> Result<()> {
+        if !self.allocated.remove(&gsi) {
+            return Err(RoutingError::InvalidGsi(gsi));
+        }
+        self.routes.remove(&gsi);
+        Ok(())
+    }
+
+    // Replace the routes of `gsi`; an empty list leaves it unrouted. Device
+    // GSIs must have been allocated first.
+    pub fn set(&mut self, gsi: u32, routes: Vec<IrqRoute>) -> Result<()> {
+        if gsi >= MAX_GSI || (gsi >= FIRST_DEVICE_GSI && !self.allocated.contains(&gsi)) {
+            return Err(RoutingError::InvalidGsi(gsi));
+        }
+        if routes.is_empty() {
+            self.routes.remove(&gsi);
+        } else {
+            self.routes.insert(gsi, routes);
+        }
+        Ok(())
+    }
+
+    pub fn routes(&self, gsi: u32) -> &[IrqRoute] {
+        self.routes
+            .get(&gsi)
+            .map_or(&[], |routes| routes.as_slice())
+    }
+
+    fn to_kvm(&self) -> Vec<kvm_irq_routing_entry> {
+        self.routes
+            .iter()
+            .flat_map(|(gsi, routes)| routes.iter().map(|route| route.to_kvm(*gsi)))
+            .collect()
+    }
+}
+
+// Replace KVM's whole GSI routing table with `entries`
+fn set_gsi_routing(vm: &VmFd, entries: &[kvm_irq_routing_entry]) -> Result<()> {
+    // kvm_irq_routing ends in a flexible array of entries, so allocate
+    // enough headers back to back to hold them
+    let bytes = size_of::<kvm_irq_routing>() + size_of_val(entries);
+    let mut buf = Vec::new();
+    buf.resize_with(
+        bytes.div_ceil(size_of::<kvm_irq_routing>()),
+        kvm_irq_routing::default,
+    );
+    buf[0].nr = entries.len() as u32;
+    unsafe {
+        buf[0]
+            .entries
+            .as_mut_slice(entries.len())
+            .copy_from_slice(entries);
+    }
+    vm.set_gsi_routing(&buf[0]).map_err(RoutingError::Kvm)
+}
+
+// Owner of the VM's GSI routing table, shared by everything that adds or
+// removes routes
+pub struct GsiRouter {
+    vm: Arc<VmFd>,
+    table: Mutex<RoutingTable>,
+}
+
+impl GsiRouter {
+    // Start from KVM's default routing for the full in-kernel irqchip,
+    // which is already in place, so nothing is pushed yet
+    pub fn new_kernel(vm: Arc<VmFd>) -> Self {
+        GsiRouter {
+            vm,
+            table: Mutex::new(RoutingTable::kvm_default()),
+        }
+    }
+
+    // Start from the empty table a split irqchip begins with. The
+    // userspace IOAPIC routes its own pins.
+    pub fn new_split(vm: Arc<VmFd>) -> Self {
+        GsiRouter {
+            vm,
+            table: Mutex::new(RoutingTable::default()),
+        }
+    }
+
+    // Make all of `f`'s changes to the table with a single
+    // KVM_SET_GSI_ROUTING. If `f` or KVM fails, nothing changes.
+    pub fn update<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut RoutingTable) -> Result<T>,
+    {
+        let mut table = self.table.lock().unwrap();
+        let mut new_table = table.clone();
+        let result = f(&mut new_table)?;
+        set_gsi_routing(&self.vm, &new_table.to_kvm())?;
+        *table = new_table;
+        Ok(result)
+    }
+
+    // Allocate a GSI that raises `msi`, e.g. to bind an irqfd to
+    pub fn allocate_msi(&self, msi: MsiMessage) -> Result<u32> {
+        self.update(|table| {
+            let gsi = table.allocate()?;
+            table.set(gsi, vec![IrqRoute::Msi(msi)])?;
+            Ok(gsi)
+        })
+    }
+
+    // Point an allocated GSI at a new message, as when the guest
+    // reprograms a device's MSI
+    pub fn update_msi(&self, gsi: u32, msi: MsiMessage) -> Result<()> {
+        self.update(|table| table.set(gsi, vec![IrqRoute::Msi(msi)]))
+    }
+
+    // Drop the routes of an allocated GSI and give it back
+    pub fn release(&self, gsi: u32) -> Result<()> {
+        self.update(|table| table.free(gsi))
+    }
+
+    // A copy of the table as KVM currently has it
+    pub fn table(&self) -> RoutingTable {
+        self.table.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSI: MsiMessage = MsiMessage {
+        address: 0x1_fee0_1000,
+        data: 0x4031,
+    };
+
+    #[test]
+    fn allocate_reuses_lowest_free_gsi() {
+        let mut table = RoutingTable::default();
+        assert_eq!(table.allocate().unwrap(), FIRST_DEVICE_GSI);
+        assert_eq!(table.allocate().unwrap(), FIRST_DEVICE_GSI + 1);
+        assert_eq!(table.allocate().unwrap(), FIRST_DEVICE_GSI + 2);
+        table.free(FIRST_DEVICE_GSI + 1).unwrap();
+        assert_eq!(table.allocate().unwrap(), FIRST_DEVICE_GSI + 1);
+        assert_eq!(table.allocate().unwrap(), FIRST_DEVICE_GSI + 3);
+    }
+
+    #[test]
+    fn allocate_runs_out() {
+        let mut table = RoutingTable::default();
+        for _ in FIRST_DEVICE_GSI..MAX_GSI {
+            table.allocate().unwrap();
+        }
+        assert!(matches!(table.allocate(), Err(RoutingError::NoFreeGsi)));
+        table.free(100).unwrap();
+        assert_eq!(table.allocate().unwrap(), 100);
+    }
+
+    #[test]
+    fn free_rejects_unallocated_gsis() {
+        let mut table = RoutingTable::kvm_default();
+        let gsi = table.allocate().unwrap();
+        table.free(gsi).unwrap();
+        // Twice, never allocated and an IOAPIC pin
+        for gsi in [gsi, gsi + 1, 4] {
+            assert!(matches!(table.free(gsi), Err(RoutingError::InvalidGsi(g)) if g == gsi));
+        }
+        assert_eq!(table.routes(4).len(), 2);
+    }
+
+    #[test]
+    fn free_drops_routes() {
+        let mut table = RoutingTable::default();
+        let gsi = table.allocate().unwrap();
+        table.set(gsi, vec![IrqRoute::Msi(MSI)]).unwrap();
+        assert_eq!(table.routes(gsi), &[IrqRoute::Msi(MSI)]);
+        table.free(gsi).unwrap();
+        assert!(table.routes(gsi).is_empty());
+        // A reused GSI starts out unrouted
+        assert_eq!(table.allocate().unwrap(), gsi);
+        assert!(table.routes(gsi).is_empty());
+    }
+
+    #[test]
+    fn set_checks_gsi() {
+        let mut table = RoutingTable::default();
+        // Device GSIs have to be allocated, pin GSIs don't
+        assert!(matches!(
+            table.set(FIRST_DEVICE_GSI, vec![IrqRoute::Msi(MSI)]),
+            Err(RoutingError::InvalidGsi(_))
+        ));
+        assert!(matches!(
+            table.set(MAX_GSI, Vec::new()),
+            Err(RoutingError::InvalidGsi(_))
+        ));
+        table.set(5, vec![IrqRoute::Msi(MSI)]).unwrap();
+        assert_eq!(table.routes(5), &[IrqRoute::Msi(MSI)]);
+        // No routes leaves the GSI out of the table
+        table.set(5, Vec::new()).unwrap();
+        assert!(table.routes(5).is_empty());
+        assert!(table.to_kvm().is_empty());
+    }
+
+    #[test]
+    fn kvm_default_routes_isa_irqs_to_pics_and_ioapic() {
+        let table = RoutingTable::kvm_default();
+        let ioapic = |pin| IrqRoute::Irqchip {
+            chip: IrqChipId::Ioapic,
+            pin,
+        };
+        assert_eq!(
+            table.routes(3),
+            &[
+                IrqRoute::Irqchip {
+                    chip: IrqChipId::PicMaster,
+                    pin: 3
+                },
+                ioapic(3)
+            ]
+        );
+        assert_eq!(
+            table.routes(10),
+            &[
+                IrqRoute::Irqchip {
+                    chip: IrqChipId::PicSlave,
+                    pin: 2
+                },
+                ioapic(10)
+            ]
+        );
+        assert_eq!(table.routes(20), &[ioapic(20)]);
+        assert!(table.routes(FIRST_DEVICE_GSI).is_empty());
+    }
+
+    #[test]
+    fn to_kvm_lists_every_route_in_gsi_order() {
+        let mut table = RoutingTable::kvm_default();
+        let gsi = table.allocate().unwrap();
+        table.set(gsi, vec![IrqRoute::Msi(MSI)]).unwrap();
+
+        let entries = table.to_kvm();
+        // Two routes for each ISA IRQ, one for the other pins, and the MSI
+        assert_eq!(entries.len(), 2 * 16 + 8 + 1);
+        assert!(entries.windows(2).all(|w| w[0].gsi <= w[1].gsi));
+
+        let pic = &entries[2 * 9];
+        assert_eq!((pic.gsi, pic.type_), (9, KVM_IRQ_ROUTING_IRQCHIP));
+        let irqchip = unsafe { pic.u.irqchip };
+        assert_eq!((irqchip.irqchip, irqchip.pin), (KVM_IRQCHIP_PIC_SLAVE, 1));
+        let pin = &entries[2 * 9 + 1];
+        assert_eq!((pin.gsi, pin.type_), (9, KVM_IRQ_ROUTING_IRQCHIP));
+        let irqchip = unsafe { pin.u.irqchip };
+        assert_eq!((irqchip.irqchip, irqchip.pin), (KVM_IRQCHIP_IOAPIC, 9));
+
+        let msi = entries.last().unwrap();
+        assert_eq!((msi.gsi, msi.type_), (gsi, KVM_IRQ_ROUTING_MSI));
+        let msi = unsafe { msi.u.msi };
+        assert_eq!(
+            (msi.address_lo, msi.address_hi, msi.data),
+            (0xfee0_1000, 0x1, 0x4031)
+        );
+    }
+}
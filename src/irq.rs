@@ -0,0 +1,146 @@
+// Interrupt delivery through KVM's interrupt controllers.
+//
+// With the full in-kernel irqchip, GSIs 0-15 reach both the PIC and the
+// IOAPIC, 16-23 only the IOAPIC, as set up by KVM's default routing. In
+// split mode GSIs are pins of the userspace IOAPIC.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use kvm_bindings::{
+    kvm_irqchip, kvm_pit_state2, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
+};
+use kvm_ioctls::VmFd;
+
+use crate::devices::ioapic::Ioapic;
+use crate::devices::Interrupt;
+
+// Which interrupt controllers KVM emulates
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IrqChipMode {
+    // In-kernel irqchip when the guest needs one: with more than one vCPU or
+    // when booting a kernel. Single-vCPU payloads run without, so that HLT
+    // still exits to the VMM.
+    #[default]
+    Auto,
+    // No interrupt controllers; devices can't raise interrupts. The only
+    // mode in which HLT exits to the VMM and all vCPUs halting ends the
+    // run with ShutdownReason::AllHalted; otherwise KVM keeps halted vCPUs
+    // waiting for an interrupt and the VM has to be stopped.
+    None,
+    // PIC, IOAPIC, LAPICs and PIT all in KVM
+    Kernel,
+    // LAPICs in KVM, IOAPIC in userspace (KVM_CAP_SPLIT_IRQCHIP). There is
+    // no PIC and no PIT, so guests have to use the local APIC timer.
+    Split,
+}
+
+// Register state of KVM's PICs, IOAPIC and PIT
+#[derive(Clone)]
+pub struct KernelIrqChipState {
+    chips: Vec<kvm_irqchip>,
+    pit: kvm_pit_state2,
+}
+
+// Handle on the VM's interrupt controllers, cheap to clone into devices
+#[derive(Clone)]
+pub struct IrqChip {
+    vm: Arc<VmFd>,
+    // Split mode only
+    ioapic: Option<Arc<Mutex<Ioapic>>>,
+}
+
+impl IrqChip {
+    pub fn new(vm: Arc<VmFd>) -> Self {
+        IrqChip { vm, ioapic: None }
+    }
+
+    // Interrupts go through `ioapic` instead of KVM's IOAPIC and PIC
+    pub fn new_split(vm: Arc<VmFd>, ioapic: Arc<Mutex<Ioapic>>) -> Self {
+        IrqChip {
+            vm,
+            ioapic: Some(ioapic),
+        }
+    }
+
+    // Whether interrupts go through the userspace IOAPIC
+    pub fn is_split(&self) -> bool {
+        self.ioapic.is_some()
+    }
+
+    // Raise and lower `gsi`, delivering one edge-triggered interrupt
+    pub fn trigger_irq(&self, gsi: u32) -> Result<(), kvm_ioctls::Error> {
+        self.set_irq_level(gsi, true)?;
+        self.set_irq_level(gsi, false)
+    }
+
+    // Assert or deassert the level-triggered `gsi`
+    pub fn set_irq_level(&self, gsi: u32, asserted: bool) -> Result<(), kvm_ioctls::Error> {
+        match &self.ioapic {
+            Some(ioapic) => ioapic.lock().unwrap().service_irq(gsi, asserted),
+            None => self.vm.set_irq_line(gsi, asserted),
+        }
+    }
+
+    // Pass on the guest's EOI of `vector`, which KVM only reports in split
+    // mode
+    pub fn end_of_interrupt(&self, vector: u8) -> Result<(), kvm_ioctls::Error> {
+        match &self.ioapic {
+            Some(ioapic) => ioapic.lock().unwrap().end_of_interrupt(vector),
+            None => Ok(()),
+        }
+    }
+
+    // None in split mode, where there are no in-kernel PICs, IOAPIC or PIT
+    pub fn save_state(&self) -> Result<Option<KernelIrqChipState>, kvm_ioctls::Error> {
+        if self.ioapic.is_some() {
+            return Ok(None);
+        }
+        let mut chips = Vec::new();
+        for chip_id in [
+            KVM_IRQCHIP_PIC_MASTER,
+            KVM_IRQCHIP_PIC_SLAVE,
+            KVM_IRQCHIP_IOAPIC,
+        ] {
+            let mut chip = kvm_irqchip {
+                chip_id,
+                ..Default::default()
+            };
+            self.vm.get_irqchip(&mut chip)?;
+            chips.push(chip);
+        }
+        Ok(Some(KernelIrqChipState {
+            chips,
+            pit: self.vm.get_pit2()?,
+        }))
+    }
+
+    pub fn restore_state(&self, state: &KernelIrqChipState) -> Result<(), kvm_ioctls::Error> {
+        for chip in &state.chips {
+            self.vm.set_irqchip(chip)?;
+        }
+        self.vm.set_pit2(&state.pit)
+    }
+}
+
+// An edge-triggered GSI raised through IrqChip::trigger_irq. In split mode
+// the userspace IOAPIC keeps an edge on a masked pin pending until the guest
+// unmasks it, where an irqfd would find no route and drop it.
+pub struct IrqLine {
+    irqchip: IrqChip,
+    gsi: u32,
+}
+
+impl IrqLine {
+    pub fn new(irqchip: IrqChip, gsi: u32) -> Self {
+        IrqLine { irqchip, gsi }
+    }
+}
+
+impl Interrupt for IrqLine {
+    fn trigger(&self) -> io::Result<()> {
+        self.irqchip
+            .trigger_irq(self.gsi)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+}
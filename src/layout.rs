@@ -0,0 +1,184 @@
+// Guest physical address space layout.
+//
+// RAM starts at 0 and grows upwards. A window below 4 GiB is kept free of
+// RAM for 32-bit MMIO (LAPIC, IOAPIC, TSS, device BARs); RAM that would
+// land there is moved above 4 GiB instead. Each contiguous piece of RAM
+// becomes its own KVM memory slot.
+
+pub const PAGE_SIZE: u64 = 0x1000;
+
+// Default 32-bit MMIO gap: [3 GiB, 4 GiB)
+pub const MMIO_GAP_START: u64 = 0xc000_0000;
+pub const MMIO_GAP_END: u64 = 0x1_0000_0000;
+
+// Where guest RAM ends up, plus the holes the guest must not use as RAM
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryLayout {
+    ram_regions: Vec<(u64, usize)>,
+    mmio_gap_start: u64,
+    mmio_gap_end: u64,
+}
+
+impl MemoryLayout {
+    // (guest base, size) of every RAM region, in address order
+    pub fn ram_regions(&self) -> &[(u64, usize)] {
+        &self.ram_regions
+    }
+
+    // The reserved 32-bit MMIO window as [start, end)
+    pub fn mmio_gap(&self) -> (u64, u64) {
+        (self.mmio_gap_start, self.mmio_gap_end)
+    }
+
+    pub fn ram_size(&self) -> usize {
+        self.ram_regions.iter().map(|(_, size)| size).sum()
+    }
+
+    // First address past the end of the highest RAM region
+    pub fn ram_end(&self) -> u64 {
+        self.ram_regions
+            .last()
+            .map_or(0, |(base, size)| base + *size as u64)
+    }
+
+    // First address past the end of the RAM below the MMIO gap. 32-bit boot
+    // protocols can only load kernels, initrds and tables below this.
+    pub fn low_ram_end(&self) -> u64 {
+        self.ram_regions
+            .iter()
+            .map(|(base, size)| base + *size as u64)
+            .filter(|end| *end <= self.mmio_gap_start)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn is_ram(&self, addr: u64) -> bool {
+        self.ram_regions
+            .iter()
+            .any(|(base, size)| addr >= *base && addr < base + *size as u64)
+    }
+}
+
+// Builds a MemoryLayout for a given amount of RAM
+#[derive(Clone, Debug)]
+pub struct MemoryLayoutBuilder {
+    ram_size: usize,
+    mmio_gap_start: u64,
+    mmio_gap_end: u64,
+}
+
+impl MemoryLayoutBuilder {
+    pub fn new(ram_size: usize) -> Self {
+        MemoryLayoutBuilder {
+            ram_size,
+            mmio_gap_start: MMIO_GAP_START,
+            mmio_gap_end: MMIO_GAP_END,
+        }
+    }
+
+    // Use [start, end) as the 32-bit MMIO gap instead of the default
+    pub fn mmio_gap(mut self, start: u64, end: u64) -> Self {
+        self.mmio_gap_start = start;
+        self.mmio_gap_end = end;
+        self
+    }
+
+    pub fn build(self) -> Result<MemoryLayout, String> {
+        if self.ram_size == 0 || !(self.ram_size as u64).is_multiple_of(PAGE_SIZE) {
+            return Err(format!(
+                "RAM size 0x{:x} must be a non-zero multiple of the page size",
+                self.ram_size
+            ));
+        }
+        if self.mmio_gap_start == 0
+            || self.mmio_gap_start >= self.mmio_gap_end
+            || !self.mmio_gap_start.is_multiple_of(PAGE_SIZE)
+            || !self.mmio_gap_end.is_multiple_of(PAGE_SIZE)
+        {
+            return Err(format!(
+                "Invalid MMIO gap [0x{:x}, 0x{:x})",
+                self.mmio_gap_start, self.mmio_gap_end
+            ));
+        }
+
+        let ram_size = self.ram_size as u64;
+        let mut ram_regions = Vec::new();
+        if ram_size <= self.mmio_gap_start {
+            ram_regions.push((0, self.ram_size));
+        } else {
+            // Whatever doesn't fit below the gap continues right above it
+            ram_regions.push((0, self.mmio_gap_start as usize));
+            let high = ram_size - self.mmio_gap_start;
+            self.mmio_gap_end
+                .checked_add(high)
+                .ok_or_else(|| format!("RAM size 0x{:x} is too large", self.ram_size))?;
+            ram_regions.push((self.mmio_gap_end, high as usize));
+        }
+
+        Ok(MemoryLayout {
+            ram_regions,
+            mmio_gap_start: self.mmio_gap_start,
+            mmio_gap_end: self.mmio_gap_end,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_ram_stays_below_gap() {
+        let layout = MemoryLayoutBuilder::new(16 << 20).build().unwrap();
+        assert_eq!(layout.ram_regions(), [(0, 16 << 20)]);
+        assert_eq!(layout.ram_end(), 16 << 20);
+        assert_eq!(layout.low_ram_end(), 16 << 20);
+    }
+
+    #[test]
+    fn ram_up_to_gap_is_one_region() {
+        let size = MMIO_GAP_START as usize;
+        let layout = MemoryLayoutBuilder::new(size).build().unwrap();
+        assert_eq!(layout.ram_regions(), [(0, size)]);
+        assert!(!layout.is_ram(MMIO_GAP_START));
+    }
+
+    #[test]
+    fn ram_past_gap_is_split() {
+        let high = 0x1000_0000;
+        let size = MMIO_GAP_START as usize + high;
+        let layout = MemoryLayoutBuilder::new(size).build().unwrap();
+        assert_eq!(
+            layout.ram_regions(),
+            [(0, MMIO_GAP_START as usize), (MMIO_GAP_END, high)]
+        );
+        assert_eq!(layout.ram_size(), size);
+        assert_eq!(layout.ram_end(), MMIO_GAP_END + high as u64);
+        assert_eq!(layout.low_ram_end(), MMIO_GAP_START);
+        assert!(layout.is_ram(MMIO_GAP_START - 1));
+        assert!(!layout.is_ram(MMIO_GAP_START));
+        assert!(!layout.is_ram(MMIO_GAP_END - 1));
+        assert!(layout.is_ram(MMIO_GAP_END));
+    }
+
+    #[test]
+    fn custom_gap() {
+        let layout = MemoryLayoutBuilder::new(0x3000_0000)
+            .mmio_gap(0x1000_0000, 0x2000_0000)
+            .build()
+            .unwrap();
+        assert_eq!(
+            layout.ram_regions(),
+            [(0, 0x1000_0000), (0x2000_0000, 0x2000_0000)]
+        );
+        assert_eq!(layout.mmio_gap(), (0x1000_0000, 0x2000_0000));
+    }
+
+    #[test]
+    fn bad_sizes_rejected() {
+        assert!(MemoryLayoutBuilder::new(0).build().is_err());
+        assert!(MemoryLayoutBuilder::new(0x1800).build().is_err());
+        let bad_gap = MemoryLayoutBuilder::new(1 << 20).mmio_gap(0x2000, 0x1000);
+        assert!(bad_gap.build().is_err());
+    }
+}
@@ -0,0 +1,18 @@
+// A KVM-based VMM with one thread per vCPU. The binary in main.rs boots a
+// VM from a VmConfig; everything it builds on is exported from here.
+
+pub mod bus;
+pub mod config;
+pub mod devices;
+pub mod dirty;
+pub mod e820;
+pub mod eventfd;
+pub mod gsi;
+pub mod irq;
+pub mod layout;
+pub mod loader;
+pub mod memory;
+pub mod mptable;
+pub mod regs;
+pub mod vcpu;
+pub mod vmm;
@@ -0,0 +1,113 @@
+// Linux x86 boot protocol structures, see Documentation/arch/x86/boot.rst
+// and arch/x86/include/uapi/asm/bootparam.h.
+
+use std::mem;
+
+use crate::memory::ByteValued;
+
+// "HdrS"
+pub const HDR_MAGIC: u32 = 0x5372_6448;
+pub const BOOT_FLAG: u16 = 0xaa55;
+
+// loadflags
+pub const LOADED_HIGH: u8 = 0x01;
+
+// xloadflags: the kernel has the 64-bit entry point at load address + 0x200
+pub const XLF_KERNEL_64: u16 = 0x01;
+
+// type_of_loader for boot loaders without an assigned id
+pub const LOADER_TYPE_UNDEFINED: u8 = 0xff;
+
+pub const E820_MAX_ENTRIES_ZEROPAGE: usize = 128;
+
+// The real-mode kernel header, at offset 0x1f1 of a bzImage and of the
+// zero page
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SetupHeader {
+    pub setup_sects: u8,
+    pub root_flags: u16,
+    pub syssize: u32,
+    pub ram_size: u16,
+    pub vid_mode: u16,
+    pub root_dev: u16,
+    pub boot_flag: u16,
+    pub jump: u16,
+    pub header: u32,
+    pub version: u16,
+    pub realmode_swtch: u32,
+    pub start_sys_seg: u16,
+    pub kernel_version: u16,
+    pub type_of_loader: u8,
+    pub loadflags: u8,
+    pub setup_move_size: u16,
+    pub code32_start: u32,
+    pub ramdisk_image: u32,
+    pub ramdisk_size: u32,
+    pub bootsect_kludge: u32,
+    pub heap_end_ptr: u16,
+    pub ext_loader_ver: u8,
+    pub ext_loader_type: u8,
+    pub cmd_line_ptr: u32,
+    pub initrd_addr_max: u32,
+    pub kernel_alignment: u32,
+    pub relocatable_kernel: u8,
+    pub min_alignment: u8,
+    pub xloadflags: u16,
+    pub cmdline_size: u32,
+    pub hardware_subarch: u32,
+    pub hardware_subarch_data: u64,
+    pub payload_offset: u32,
+    pub payload_length: u32,
+    pub setup_data: u64,
+    pub pref_address: u64,
+    pub init_size: u32,
+    pub handover_offset: u32,
+    pub kernel_info_offset: u32,
+}
+
+// Offset of the setup header in a bzImage and in boot_params
+pub const SETUP_HEADER_OFFSET: usize = 0x1f1;
+
+// One entry of the BIOS E820 memory map
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BootE820Entry {
+    pub addr: u64,
+    pub size: u64,
+    pub type_: u32,
+}
+
+// The zero page handed to the kernel in %rsi. Only the fields a boot loader
+// fills in are named, the rest is kept as padding at the right offsets.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct BootParams {
+    _pad0: [u8; 0x70],
+    pub acpi_rsdp_addr: u64,
+    _pad1: [u8; 0x48],
+    pub ext_ramdisk_image: u32,
+    pub ext_ramdisk_size: u32,
+    pub ext_cmd_line_ptr: u32,
+    _pad2: [u8; 0x11c],
+    pub e820_entries: u8,
+    _pad3: [u8; 8],
+    pub hdr: SetupHeader,
+    _pad4: [u8; 0x64],
+    pub e820_table: [BootE820Entry; E820_MAX_ENTRIES_ZEROPAGE],
+    _pad5: [u8; 0x330],
+}
+
+impl Default for BootParams {
+    fn default() -> Self {
+        // All-zeroes is the documented initial state of the zero page
+        unsafe { mem::zeroed() }
+    }
+}
+
+const _: () = assert!(mem::size_of::<SetupHeader>() == 0x26c - SETUP_HEADER_OFFSET);
+const _: () = assert!(mem::size_of::<BootParams>() == 0x1000);
+
+unsafe impl ByteValued for SetupHeader {}
+unsafe impl ByteValued for BootE820Entry {}
+unsafe impl ByteValued for BootParams {}
@@ -0,0 +1,94 @@
+// Linux bzImage loader, following the 64-bit boot protocol: the
+// protected-mode part of the image is copied to HIMEM_START and entered at
+// its 64-bit entry point with %rsi pointing at the zero page. The real-mode
+// setup code is never run, so the zero page is filled in here instead.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use super::bootparam::{
+    SetupHeader, BOOT_FLAG, HDR_MAGIC, LOADED_HIGH, SETUP_HEADER_OFFSET, XLF_KERNEL_64,
+};
+use super::{BootProtocol, LoadedKernel, LoaderError, Result, HIMEM_START};
+use crate::layout::MemoryLayout;
+use crate::memory::{ByteValued, GuestMemory};
+
+// Oldest boot protocol that advertises the 64-bit entry point in xloadflags
+const MIN_BOOT_PROTOCOL: u16 = 0x020c;
+// The 64-bit entry point is this far into the protected-mode kernel
+const STARTUP_64_OFFSET: u64 = 0x200;
+// A setup_sects of 0 means 4 for historical reasons
+const DEFAULT_SETUP_SECTS: u8 = 4;
+const SECTOR_SIZE: u64 = 512;
+
+// Read and sanity-check the setup header of a bzImage
+fn read_setup_header(image: &mut File) -> Result<SetupHeader> {
+    let mut hdr = SetupHeader::default();
+    image.seek(SeekFrom::Start(SETUP_HEADER_OFFSET as u64))?;
+    image
+        .read_exact(hdr.as_mut_slice())
+        .map_err(|_| LoaderError::InvalidKernel("image too small for a setup header".into()))?;
+
+    let (boot_flag, magic, version, xloadflags) =
+        (hdr.boot_flag, hdr.header, hdr.version, hdr.xloadflags);
+    if boot_flag != BOOT_FLAG || magic != HDR_MAGIC {
+        return Err(LoaderError::InvalidKernel("not a bzImage".into()));
+    }
+    if version < MIN_BOOT_PROTOCOL {
+        return Err(LoaderError::InvalidKernel(format!(
+            "boot protocol {}.{:02} is too old, need 2.12",
+            version >> 8,
+            version & 0xff
+        )));
+    }
+    if hdr.loadflags & LOADED_HIGH == 0 {
+        return Err(LoaderError::InvalidKernel(
+            "zImage kernels loaded at 0x10000 are not supported".into(),
+        ));
+    }
+    if xloadflags & XLF_KERNEL_64 == 0 {
+        return Err(LoaderError::InvalidKernel("no 64-bit entry point".into()));
+    }
+    Ok(hdr)
+}
+
+// Copy the protected-mode kernel of a bzImage to HIMEM_START
+pub fn load(mem: &GuestMemory, layout: &MemoryLayout, image: &mut File) -> Result<LoadedKernel> {
+    let hdr = read_setup_header(image)?;
+
+    let setup_sects = match hdr.setup_sects {
+        0 => DEFAULT_SETUP_SECTS,
+        sects => sects,
+    };
+    let kernel_offset = (setup_sects as u64 + 1) * SECTOR_SIZE;
+    let image_len = image.metadata()?.len();
+    if image_len <= kernel_offset {
+        return Err(LoaderError::InvalidKernel(
+            "no protected-mode kernel after the setup code".into(),
+        ));
+    }
+    let kernel_len = image_len - kernel_offset;
+
+    // The decompressor needs init_size bytes from the load address, which
+    // is usually well beyond the compressed image
+    let kernel_end = HIMEM_START + kernel_len.max(hdr.init_size as u64);
+    if kernel_end > layout.low_ram_end() {
+        return Err(LoaderError::DoesNotFit(format!(
+            "kernel needs RAM up to 0x{:x}, low RAM ends at 0x{:x}",
+            kernel_end,
+            layout.low_ram_end()
+        )));
+    }
+
+    let mut kernel = Vec::with_capacity(kernel_len as usize);
+    image.seek(SeekFrom::Start(kernel_offset))?;
+    image.read_to_end(&mut kernel)?;
+    mem.write_slice(&kernel, HIMEM_START)?;
+
+    Ok(LoadedKernel {
+        entry_addr: HIMEM_START + STARTUP_64_OFFSET,
+        kernel_end,
+        protocol: BootProtocol::Linux,
+        setup_header: Some(hdr),
+    })
+}
@@ -0,0 +1,226 @@
+// ELF64 kernel loader for uncompressed vmlinux images and unikernels.
+// PT_LOAD segments are copied to their physical addresses. A Xen
+// PHYS32_ENTRY note marks a PVH entry point; without one the kernel is
+// entered at e_entry through the Linux 64-bit boot protocol.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use super::{BootProtocol, LoadedKernel, LoaderError, Result, HIMEM_START};
+use crate::layout::MemoryLayout;
+use crate::memory::{ByteValued, GuestMemory};
+
+pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+// Note owner and type carrying the 32-bit PVH entry point
+const XEN_ELFNOTE_OWNER: &[u8] = b"Xen\0";
+const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct Elf64Ehdr {
+    e_ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct Elf64Phdr {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct Elf64Nhdr {
+    n_namesz: u32,
+    n_descsz: u32,
+    n_type: u32,
+}
+
+unsafe impl ByteValued for Elf64Ehdr {}
+unsafe impl ByteValued for Elf64Phdr {}
+unsafe impl ByteValued for Elf64Nhdr {}
+
+// Note names and descriptors are padded to 4 bytes
+fn note_align(len: u32) -> usize {
+    (len as usize + 3) & !3
+}
+
+// Make sure a segment's file contents are really in the image before
+// allocating a buffer of the size the header claims
+fn check_in_file(image: &File, phdr: &Elf64Phdr, what: &str) -> Result<()> {
+    let file_len = image.metadata()?.len();
+    match phdr.p_offset.checked_add(phdr.p_filesz) {
+        Some(end) if end <= file_len => Ok(()),
+        _ => Err(LoaderError::InvalidKernel(format!("truncated {}", what))),
+    }
+}
+
+fn read_ehdr(image: &mut File) -> Result<Elf64Ehdr> {
+    let mut ehdr = Elf64Ehdr::default();
+    image.seek(SeekFrom::Start(0))?;
+    image
+        .read_exact(ehdr.as_mut_slice())
+        .map_err(|_| LoaderError::InvalidKernel("image too small for an ELF header".into()))?;
+
+    if ehdr.e_ident[..4] != ELF_MAGIC {
+        return Err(LoaderError::InvalidKernel("not an ELF file".into()));
+    }
+    if ehdr.e_ident[4] != ELFCLASS64 || ehdr.e_ident[5] != ELFDATA2LSB {
+        return Err(LoaderError::InvalidKernel(
+            "only little-endian ELF64 is supported".into(),
+        ));
+    }
+    if ehdr.e_type != ET_EXEC || ehdr.e_machine != EM_X86_64 {
+        return Err(LoaderError::InvalidKernel(
+            "not an x86_64 executable".into(),
+        ));
+    }
+    if ehdr.e_phentsize as usize != std::mem::size_of::<Elf64Phdr>() {
+        return Err(LoaderError::InvalidKernel(format!(
+            "unexpected program header size {}",
+            ehdr.e_phentsize
+        )));
+    }
+    Ok(ehdr)
+}
+
+fn read_phdrs(image: &mut File, ehdr: &Elf64Ehdr) -> Result<Vec<Elf64Phdr>> {
+    image.seek(SeekFrom::Start(ehdr.e_phoff))?;
+    let mut phdrs = vec![Elf64Phdr::default(); ehdr.e_phnum as usize];
+    for phdr in phdrs.iter_mut() {
+        image
+            .read_exact(phdr.as_mut_slice())
+            .map_err(|_| LoaderError::InvalidKernel("truncated program headers".into()))?;
+    }
+    Ok(phdrs)
+}
+
+// Look for a PHYS32_ENTRY note in a PT_NOTE segment
+fn find_pvh_entry(image: &mut File, phdr: &Elf64Phdr) -> Result<Option<u64>> {
+    check_in_file(image, phdr, "note segment")?;
+    let mut notes = vec![0u8; phdr.p_filesz as usize];
+    image.seek(SeekFrom::Start(phdr.p_offset))?;
+    image
+        .read_exact(&mut notes)
+        .map_err(|_| LoaderError::InvalidKernel("truncated note segment".into()))?;
+
+    let nhdr_size = std::mem::size_of::<Elf64Nhdr>();
+    let mut offset = 0;
+    while offset + nhdr_size <= notes.len() {
+        let mut nhdr = Elf64Nhdr::default();
+        nhdr.as_mut_slice()
+            .copy_from_slice(&notes[offset..offset + nhdr_size]);
+        let name_start = offset + nhdr_size;
+        let desc_start = name_start + note_align(nhdr.n_namesz);
+        let next = desc_start + note_align(nhdr.n_descsz);
+        if next > notes.len() {
+            return Err(LoaderError::InvalidKernel("truncated ELF note".into()));
+        }
+
+        let name = &notes[name_start..name_start + nhdr.n_namesz as usize];
+        if name == XEN_ELFNOTE_OWNER && nhdr.n_type == XEN_ELFNOTE_PHYS32_ENTRY {
+            // The entry is emitted as a pointer-sized value; it has to be a
+            // 32-bit address either way
+            if nhdr.n_descsz < 4 {
+                return Err(LoaderError::InvalidKernel(
+                    "PHYS32_ENTRY note too short".into(),
+                ));
+            }
+            let mut entry = [0u8; 4];
+            entry.copy_from_slice(&notes[desc_start..desc_start + 4]);
+            return Ok(Some(u32::from_le_bytes(entry) as u64));
+        }
+        offset = next;
+    }
+    Ok(None)
+}
+
+// Copy the PT_LOAD segments of an ELF kernel to guest memory
+pub fn load(mem: &GuestMemory, layout: &MemoryLayout, image: &mut File) -> Result<LoadedKernel> {
+    let ehdr = read_ehdr(image)?;
+    let phdrs = read_phdrs(image, &ehdr)?;
+
+    let mut kernel_end = 0;
+    let mut pvh_entry = None;
+    for phdr in &phdrs {
+        match phdr.p_type {
+            PT_LOAD => {
+                if phdr.p_filesz > phdr.p_memsz {
+                    return Err(LoaderError::InvalidKernel(
+                        "segment larger in the file than in memory".into(),
+                    ));
+                }
+                // Boot structures live below HIMEM_START, and both boot
+                // protocols need the kernel in 32-bit addressable RAM
+                let end = phdr.p_paddr.checked_add(phdr.p_memsz);
+                if phdr.p_paddr < HIMEM_START || end.is_none_or(|end| end > layout.low_ram_end()) {
+                    return Err(LoaderError::DoesNotFit(format!(
+                        "segment at 0x{:x}, size 0x{:x}, must be within [0x{:x}, 0x{:x})",
+                        phdr.p_paddr,
+                        phdr.p_memsz,
+                        HIMEM_START,
+                        layout.low_ram_end()
+                    )));
+                }
+
+                check_in_file(image, phdr, "segment")?;
+                let mut segment = vec![0u8; phdr.p_filesz as usize];
+                image.seek(SeekFrom::Start(phdr.p_offset))?;
+                image
+                    .read_exact(&mut segment)
+                    .map_err(|_| LoaderError::InvalidKernel("truncated segment".into()))?;
+                mem.write_slice(&segment, phdr.p_paddr)?;
+                // .bss and friends
+                mem.zero(
+                    phdr.p_paddr + phdr.p_filesz,
+                    (phdr.p_memsz - phdr.p_filesz) as usize,
+                )?;
+                kernel_end = kernel_end.max(phdr.p_paddr + phdr.p_memsz);
+            }
+            PT_NOTE if pvh_entry.is_none() => pvh_entry = find_pvh_entry(image, phdr)?,
+            _ => {}
+        }
+    }
+    if kernel_end == 0 {
+        return Err(LoaderError::InvalidKernel("no loadable segments".into()));
+    }
+
+    let (entry_addr, protocol) = match pvh_entry {
+        Some(entry) => (entry, BootProtocol::Pvh),
+        None => (ehdr.e_entry, BootProtocol::Linux),
+    };
+    Ok(LoadedKernel {
+        entry_addr,
+        kernel_end,
+        protocol,
+        setup_header: None,
+    })
+}
@@ -0,0 +1,250 @@
+// Loading guest kernels into guest memory, and the data structures the
+// kernel expects to find next to it at boot.
+
+pub mod bootparam;
+pub mod bzimage;
+pub mod elf;
+pub mod pvh;
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::{fmt, io};
+
+use crate::e820::E820Map;
+use crate::layout::{MemoryLayout, PAGE_SIZE};
+use crate::memory::{GuestMemory, GuestMemoryError};
+use bootparam::{
+    BootE820Entry, BootParams, SetupHeader, BOOT_FLAG, E820_MAX_ENTRIES_ZEROPAGE, HDR_MAGIC,
+    LOADER_TYPE_UNDEFINED,
+};
+
+// The protected-mode kernel goes at 1 MiB, above the real-mode area and
+// the legacy BIOS/VGA holes
+pub const HIMEM_START: u64 = 0x10_0000;
+// Linux zero page (struct boot_params)
+pub const ZERO_PAGE_START: u64 = 0x7000;
+// Kernel command line, NUL-terminated, in a page of its own. The E820 map
+// reports it as usable RAM, which is safe: Linux copies the command line
+// out (copy_bootdata) before it allocates any memory, and PVH boots take
+// the same path, so the page is free to reuse afterwards.
+pub const CMDLINE_START: u64 = 0x2_0000;
+pub const CMDLINE_MAX_SIZE: usize = PAGE_SIZE as usize;
+
+// There is no keyboard controller to pulse the reset line, so the guest
+// reboots by triple faulting, which the VMM treats as a reset
+pub const DEFAULT_CMDLINE: &str = "console=ttyS0 reboot=t panic=1";
+
+// Alignment a kernel without a setup header is assumed to need
+const KERNEL_ALIGNMENT: u32 = 0x100_0000;
+
+#[derive(Debug)]
+pub enum LoaderError {
+    // Reading the kernel image failed
+    Io(io::Error),
+    // The image is not something we know how to boot
+    InvalidKernel(String),
+    // The kernel or one of its boot structures doesn't fit in guest RAM
+    DoesNotFit(String),
+    // The command line is longer than the guest accepts
+    CmdlineTooLong(usize),
+    // Reading the initrd failed
+    Initrd(io::Error),
+    Memory(GuestMemoryError),
+}
+
+impl fmt::Display for LoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoaderError::Io(e) => write!(f, "Failed to read kernel image: {}", e),
+            LoaderError::InvalidKernel(msg) => write!(f, "Invalid kernel image: {}", msg),
+            LoaderError::DoesNotFit(msg) => write!(f, "Does not fit in guest memory: {}", msg),
+            LoaderError::CmdlineTooLong(len) => {
+                write!(f, "Kernel command line too long: {} bytes", len)
+            }
+            LoaderError::Initrd(e) => write!(f, "Failed to read initrd: {}", e),
+            LoaderError::Memory(e) => write!(f, "Failed to write guest memory: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LoaderError {}
+
+impl From<io::Error> for LoaderError {
+    fn from(e: io::Error) -> Self {
+        LoaderError::Io(e)
+    }
+}
+
+impl From<GuestMemoryError> for LoaderError {
+    fn from(e: GuestMemoryError) -> Self {
+        LoaderError::Memory(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, LoaderError>;
+
+// How a loaded kernel expects to be entered
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootProtocol {
+    // Linux 64-bit boot protocol: long mode, %rsi points at the zero page
+    Linux,
+    // Xen PVH: 32-bit protected mode without paging, %ebx points at
+    // hvm_start_info
+    Pvh,
+}
+
+// A kernel sitting in guest memory, ready to be entered
+#[derive(Clone, Copy, Debug)]
+pub struct LoadedKernel {
+    // Guest physical address of the entry point
+    pub entry_addr: u64,
+    // First address past the kernel, including the room it needs to
+    // decompress itself
+    pub kernel_end: u64,
+    pub protocol: BootProtocol,
+    // Setup header of a bzImage, the base for the zero page's copy
+    pub setup_header: Option<SetupHeader>,
+}
+
+impl LoadedKernel {
+    // Longest command line the kernel accepts, including the NUL
+    pub fn cmdline_max_size(&self) -> usize {
+        match self.setup_header {
+            Some(hdr) => CMDLINE_MAX_SIZE.min(hdr.cmdline_size as usize + 1),
+            None => CMDLINE_MAX_SIZE,
+        }
+    }
+
+    // First address past the highest byte the kernel can read an initrd
+    // from
+    fn initrd_limit(&self) -> u64 {
+        match self.setup_header {
+            Some(hdr) => hdr.initrd_addr_max as u64 + 1,
+            None => u64::MAX,
+        }
+    }
+}
+
+// Where the initrd ended up in guest memory
+#[derive(Clone, Copy, Debug)]
+pub struct Initrd {
+    pub addr: u64,
+    pub size: usize,
+}
+
+// Load an ELF vmlinux or a bzImage, depending on what the image starts with
+pub fn load_kernel(
+    mem: &GuestMemory,
+    layout: &MemoryLayout,
+    image: &mut File,
+) -> Result<LoadedKernel> {
+    let mut magic = [0u8; 4];
+    image.seek(SeekFrom::Start(0))?;
+    image
+        .read_exact(&mut magic)
+        .map_err(|_| LoaderError::InvalidKernel("image is empty".into()))?;
+    if magic == elf::ELF_MAGIC {
+        elf::load(mem, layout, image)
+    } else {
+        bzimage::load(mem, layout, image)
+    }
+}
+
+// Write the kernel command line as a C string at `addr`. `max_size`
+// includes the terminating NUL.
+pub fn load_cmdline(mem: &GuestMemory, addr: u64, cmdline: &str, max_size: usize) -> Result<()> {
+    let len = cmdline.len() + 1;
+    if len > max_size {
+        return Err(LoaderError::CmdlineTooLong(cmdline.len()));
+    }
+    mem.write_slice(cmdline.as_bytes(), addr)?;
+    mem.write_obj(0u8, addr + cmdline.len() as u64)?;
+    Ok(())
+}
+
+// Copy an initrd as high as possible into low RAM, page aligned, above the
+// kernel and below both the MMIO gap and the kernel's initrd_addr_max
+pub fn load_initrd(
+    mem: &GuestMemory,
+    layout: &MemoryLayout,
+    kernel: &LoadedKernel,
+    image: &mut File,
+) -> Result<Initrd> {
+    let mut initrd = Vec::new();
+    image
+        .seek(SeekFrom::Start(0))
+        .map_err(LoaderError::Initrd)?;
+    image
+        .read_to_end(&mut initrd)
+        .map_err(LoaderError::Initrd)?;
+
+    let limit = layout.low_ram_end().min(kernel.initrd_limit());
+    let addr = limit
+        .checked_sub(initrd.len() as u64)
+        .map(|addr| addr & !(PAGE_SIZE - 1))
+        .filter(|addr| *addr >= kernel.kernel_end)
+        .ok_or_else(|| {
+            LoaderError::DoesNotFit(format!(
+                "initrd of 0x{:x} bytes between the kernel end 0x{:x} and 0x{:x}",
+                initrd.len(),
+                kernel.kernel_end,
+                limit
+            ))
+        })?;
+    mem.write_slice(&initrd, addr)?;
+
+    Ok(Initrd {
+        addr,
+        size: initrd.len(),
+    })
+}
+
+// Build the zero page for a kernel entered through the Linux boot protocol,
+// with its command line at `cmdline_addr`, and write it to `addr`
+pub fn setup_zero_page(
+    mem: &GuestMemory,
+    e820: &E820Map,
+    kernel: &LoadedKernel,
+    cmdline_addr: u64,
+    initrd: Option<&Initrd>,
+    addr: u64,
+) -> Result<()> {
+    let mut params = BootParams::default();
+    params.hdr = kernel.setup_header.unwrap_or_else(|| SetupHeader {
+        // A vmlinux has no header to copy, only fill in what the kernel
+        // checks
+        boot_flag: BOOT_FLAG,
+        header: HDR_MAGIC,
+        kernel_alignment: KERNEL_ALIGNMENT,
+        ..Default::default()
+    });
+    params.hdr.type_of_loader = LOADER_TYPE_UNDEFINED;
+    params.hdr.cmd_line_ptr = cmdline_addr as u32;
+    params.hdr.code32_start = HIMEM_START as u32;
+    if let Some(initrd) = initrd {
+        params.hdr.ramdisk_image = initrd.addr as u32;
+        params.hdr.ramdisk_size = initrd.size as u32;
+        params.ext_ramdisk_image = (initrd.addr >> 32) as u32;
+        params.ext_ramdisk_size = (initrd.size as u64 >> 32) as u32;
+    }
+
+    let entries = e820.entries();
+    if entries.len() > E820_MAX_ENTRIES_ZEROPAGE {
+        return Err(LoaderError::DoesNotFit(format!(
+            "{} E820 entries, the zero page holds {}",
+            entries.len(),
+            E820_MAX_ENTRIES_ZEROPAGE
+        )));
+    }
+    for (slot, entry) in params.e820_table.iter_mut().zip(entries) {
+        *slot = BootE820Entry {
+            addr: entry.addr,
+            size: entry.size,
+            type_: entry.type_.raw(),
+        };
+    }
+    params.e820_entries = entries.len() as u8;
+
+    mem.write_obj(params, addr)?;
+    Ok(())
+}
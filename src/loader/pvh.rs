@@ -0,0 +1,109 @@
+// Xen PVH boot structures, see xen/include/public/arch-x86/hvm/start_info.h.
+// The kernel gets %ebx pointing at hvm_start_info, which points on to the
+// command line and the memory map.
+
+use super::{Initrd, LoaderError, Result};
+use crate::e820::E820Map;
+use crate::layout::PAGE_SIZE;
+use crate::memory::{ByteValued, GuestMemory};
+
+// "xEn3" with the top bit of the last byte set
+pub const XEN_HVM_START_MAGIC_VALUE: u32 = 0x336e_c578;
+// Version 1 adds the memory map
+const XEN_HVM_START_INFO_VERSION: u32 = 1;
+
+// Placement of the boot structures in the real-mode area
+pub const PVH_INFO_START: u64 = 0x6000;
+pub const MODLIST_START: u64 = 0x6040;
+pub const MEMMAP_START: u64 = 0x7000;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HvmStartInfo {
+    pub magic: u32,
+    pub version: u32,
+    pub flags: u32,
+    pub nr_modules: u32,
+    pub modlist_paddr: u64,
+    pub cmdline_paddr: u64,
+    pub rsdp_paddr: u64,
+    pub memmap_paddr: u64,
+    pub memmap_entries: u32,
+    pub reserved: u32,
+}
+
+// A module handed to the kernel, the initrd being the first
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HvmModlistEntry {
+    pub paddr: u64,
+    pub size: u64,
+    pub cmdline_paddr: u64,
+    pub reserved: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HvmMemmapTableEntry {
+    pub addr: u64,
+    pub size: u64,
+    // E820 type
+    pub type_: u32,
+    pub reserved: u32,
+}
+
+unsafe impl ByteValued for HvmStartInfo {}
+unsafe impl ByteValued for HvmModlistEntry {}
+unsafe impl ByteValued for HvmMemmapTableEntry {}
+
+// Write hvm_start_info to `addr`, with the command line at `cmdline_addr`,
+// the initrd as the only module at MODLIST_START and the memory map at
+// MEMMAP_START
+pub fn setup_start_info(
+    mem: &GuestMemory,
+    e820: &E820Map,
+    cmdline_addr: u64,
+    initrd: Option<&Initrd>,
+    addr: u64,
+) -> Result<()> {
+    let entries = e820.entries();
+    let entry_size = std::mem::size_of::<HvmMemmapTableEntry>();
+    if entries.len() * entry_size > PAGE_SIZE as usize {
+        return Err(LoaderError::DoesNotFit(format!(
+            "{} E820 entries, the PVH memmap page holds {}",
+            entries.len(),
+            PAGE_SIZE as usize / entry_size
+        )));
+    }
+    for (index, entry) in entries.iter().enumerate() {
+        let memmap_entry = HvmMemmapTableEntry {
+            addr: entry.addr,
+            size: entry.size,
+            type_: entry.type_.raw(),
+            reserved: 0,
+        };
+        let offset = index * entry_size;
+        mem.write_obj(memmap_entry, MEMMAP_START + offset as u64)?;
+    }
+
+    let mut start_info = HvmStartInfo {
+        magic: XEN_HVM_START_MAGIC_VALUE,
+        version: XEN_HVM_START_INFO_VERSION,
+        cmdline_paddr: cmdline_addr,
+        memmap_paddr: MEMMAP_START,
+        memmap_entries: entries.len() as u32,
+        ..Default::default()
+    };
+    if let Some(initrd) = initrd {
+        let module = HvmModlistEntry {
+            paddr: initrd.addr,
+            size: initrd.size as u64,
+            ..Default::default()
+        };
+        mem.write_obj(module, MODLIST_START)?;
+        start_info.nr_modules = 1;
+        start_info.modlist_paddr = MODLIST_START;
+    }
+    mem.write_obj(start_info, addr)?;
+    Ok(())
+}
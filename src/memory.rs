@@ -0,0 +1,664 @@
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::layout::PAGE_SIZE;
+
+// Errors from setting up or accessing guest memory
+#[derive(Debug)]
+pub enum GuestMemoryError {
+    // Mapping host memory for a region failed
+    Mmap(io::Error),
+    // Creating, opening or sizing the file backing guest RAM failed
+    Backing(io::Error),
+    // Locking guest RAM into host memory failed
+    Mlock(io::Error),
+    // The backing options don't fit the requested regions
+    InvalidBacking(String),
+    // Two regions cover the same guest physical addresses
+    Overlap(u64),
+    // Part of the access is not backed by any region
+    InvalidRange { addr: u64, len: usize },
+}
+
+impl fmt::Display for GuestMemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GuestMemoryError::Mmap(e) => write!(f, "Failed to map guest memory: {}", e),
+            GuestMemoryError::Backing(e) => {
+                write!(f, "Failed to set up guest memory backing: {}", e)
+            }
+            GuestMemoryError::Mlock(e) => write!(f, "Failed to lock guest memory: {}", e),
+            GuestMemoryError::InvalidBacking(msg) => {
+                write!(f, "Invalid guest memory backing: {}", msg)
+            }
+            GuestMemoryError::Overlap(addr) => {
+                write!(f, "Guest memory regions overlap at 0x{:x}", addr)
+            }
+            GuestMemoryError::InvalidRange { addr, len } => write!(
+                f,
+                "Guest access of {} bytes at 0x{:x} is outside guest memory",
+                len, addr
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GuestMemoryError {}
+
+pub type Result<T> = std::result::Result<T, GuestMemoryError>;
+
+// Where the host memory behind guest RAM comes from
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum MemoryBackend {
+    // Private anonymous memory, only visible to this process
+    #[default]
+    Anonymous,
+    // A memfd mapped MAP_SHARED, so the fd can be handed to vhost-user
+    // backends or used to snapshot guest RAM
+    Memfd,
+    // A file mapped MAP_SHARED, e.g. on tmpfs or hugetlbfs. It is created
+    // if missing and grown to the size of guest RAM. A reboot zeroes it
+    // like the rest of guest RAM, so it doesn't keep a guest's memory
+    // across one.
+    File(PathBuf),
+}
+
+// Huge page size to back guest RAM with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HugePageSize {
+    Size2M,
+    Size1G,
+}
+
+impl HugePageSize {
+    pub fn bytes(self) -> usize {
+        match self {
+            HugePageSize::Size2M => 2 << 20,
+            HugePageSize::Size1G => 1 << 30,
+        }
+    }
+
+    fn mmap_flags(self) -> libc::c_int {
+        libc::MAP_HUGETLB
+            | match self {
+                HugePageSize::Size2M => libc::MAP_HUGE_2MB,
+                HugePageSize::Size1G => libc::MAP_HUGE_1GB,
+            }
+    }
+
+    fn memfd_flags(self) -> libc::c_uint {
+        libc::MFD_HUGETLB
+            | match self {
+                HugePageSize::Size2M => libc::MFD_HUGE_2MB,
+                HugePageSize::Size1G => libc::MFD_HUGE_1GB,
+            }
+    }
+}
+
+// How guest RAM is backed and prefaulted
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryBacking {
+    pub backend: MemoryBackend,
+    // Use huge pages of this size. Works with anonymous and memfd backing;
+    // for huge page backed files put the file on hugetlbfs instead.
+    pub hugepages: Option<HugePageSize>,
+    // Fault all of guest RAM in up front (MAP_POPULATE)
+    pub populate: bool,
+    // Lock guest RAM into host memory so it is never swapped out
+    pub mlock: bool,
+}
+
+// The file a region is mapped from and the offset of the region within it,
+// for sharing guest RAM with other processes
+#[derive(Clone, Debug)]
+pub struct FileOffset {
+    file: Arc<File>,
+    start: u64,
+}
+
+impl FileOffset {
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+}
+
+// Create an anonymous memory file large enough for all of guest RAM
+fn create_memfd(size: usize, hugepages: Option<HugePageSize>) -> Result<File> {
+    let name = CString::new("guest-ram").unwrap();
+    let mut flags = libc::MFD_CLOEXEC;
+    if let Some(hugepages) = hugepages {
+        flags |= hugepages.memfd_flags();
+    }
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(GuestMemoryError::Backing(io::Error::last_os_error()));
+    }
+    let file = unsafe { File::from_raw_fd(fd) };
+    file.set_len(size as u64)
+        .map_err(GuestMemoryError::Backing)?;
+    Ok(file)
+}
+
+// Open (or create) a backing file and make sure it covers all of guest RAM
+fn open_backing_file(path: &PathBuf, size: usize) -> Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(GuestMemoryError::Backing)?;
+    let len = file.metadata().map_err(GuestMemoryError::Backing)?.len();
+    if len < size as u64 {
+        file.set_len(size as u64)
+            .map_err(GuestMemoryError::Backing)?;
+    }
+    Ok(file)
+}
+
+/// Types that can be safely copied to and from guest memory byte by byte:
+/// plain data without padding whose every bit pattern is a valid value.
+///
+/// # Safety
+/// Implementors must be `repr(C)` or primitive, contain no references or
+/// pointers, and accept any byte pattern.
+pub unsafe trait ByteValued: Copy + Default + Send + Sync {
+    fn as_slice(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(self as *const Self as *const u8, mem::size_of::<Self>())
+        }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe {
+            std::slice::from_raw_parts_mut(self as *mut Self as *mut u8, mem::size_of::<Self>())
+        }
+    }
+}
+
+unsafe impl ByteValued for u8 {}
+unsafe impl ByteValued for u16 {}
+unsafe impl ByteValued for u32 {}
+unsafe impl ByteValued for u64 {}
+unsafe impl ByteValued for i8 {}
+unsafe impl ByteValued for i16 {}
+unsafe impl ByteValued for i32 {}
+unsafe impl ByteValued for i64 {}
+
+// One bit per 4 KiB page of a region, set when the VMM writes to the page.
+// Complements KVM's dirty log, which only sees writes made by the guest.
+#[derive(Debug)]
+pub struct DirtyBitmap {
+    words: Vec<AtomicU64>,
+}
+
+impl DirtyBitmap {
+    fn new(size: usize) -> Self {
+        let pages = size.div_ceil(PAGE_SIZE as usize);
+        DirtyBitmap {
+            words: (0..pages.div_ceil(64)).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    // Mark every page touched by [offset, offset + len) as dirty
+    pub fn mark(&self, offset: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let first = offset / PAGE_SIZE as usize;
+        let last = (offset + len - 1) / PAGE_SIZE as usize;
+        for page in first..=last {
+            self.words[page / 64].fetch_or(1 << (page % 64), Ordering::Relaxed);
+        }
+    }
+
+    fn mark_all(&self) {
+        for word in &self.words {
+            word.store(u64::MAX, Ordering::Relaxed);
+        }
+    }
+
+    // Return the dirty bits collected so far and start over
+    pub fn take(&self) -> Vec<u64> {
+        self.words
+            .iter()
+            .map(|word| word.swap(0, Ordering::Relaxed))
+            .collect()
+    }
+}
+
+// A contiguous range of guest physical memory backed by one host mapping
+#[derive(Debug)]
+pub struct GuestRegion {
+    guest_base: u64,
+    size: usize,
+    host_addr: *mut u8,
+    file_offset: Option<FileOffset>,
+    locked: bool,
+    dirty: DirtyBitmap,
+}
+
+// The mapping is plain memory shared with the guest; synchronising guest
+// accesses is the guest's business, and the VMM only copies in and out.
+unsafe impl Send for GuestRegion {}
+unsafe impl Sync for GuestRegion {}
+
+impl GuestRegion {
+    // Map `size` bytes of anonymous memory to appear at `guest_base`
+    pub fn new(guest_base: u64, size: usize) -> Result<Self> {
+        Self::mmap(
+            guest_base,
+            size,
+            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+            None,
+        )
+    }
+
+    // Map `size` bytes at `guest_base`, from `file_offset` if given.
+    // `flags` are passed straight to mmap.
+    fn mmap(
+        guest_base: u64,
+        size: usize,
+        flags: libc::c_int,
+        file_offset: Option<FileOffset>,
+    ) -> Result<Self> {
+        let (fd, offset) = match &file_offset {
+            Some(file_offset) => (file_offset.file.as_raw_fd(), file_offset.start),
+            None => (-1, 0),
+        };
+        let host_addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                fd,
+                offset as libc::off_t,
+            )
+        };
+        if host_addr == libc::MAP_FAILED {
+            return Err(GuestMemoryError::Mmap(io::Error::last_os_error()));
+        }
+        Ok(GuestRegion {
+            guest_base,
+            size,
+            host_addr: host_addr as *mut u8,
+            file_offset,
+            locked: false,
+            dirty: DirtyBitmap::new(size),
+        })
+    }
+
+    // Pin the region into host RAM
+    fn lock(&mut self) -> Result<()> {
+        let ret = unsafe { libc::mlock(self.host_addr as *const libc::c_void, self.size) };
+        if ret != 0 {
+            return Err(GuestMemoryError::Mlock(io::Error::last_os_error()));
+        }
+        self.locked = true;
+        Ok(())
+    }
+
+    pub fn guest_base(&self) -> u64 {
+        self.guest_base
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // First guest physical address past the end of the region
+    pub fn guest_end(&self) -> u64 {
+        self.guest_base + self.size as u64
+    }
+
+    pub fn host_addr(&self) -> *mut u8 {
+        self.host_addr
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.guest_base && addr < self.guest_end()
+    }
+
+    // Pages written by the VMM, as opposed to the guest
+    pub fn dirty_bitmap(&self) -> &DirtyBitmap {
+        &self.dirty
+    }
+
+    // The file the region is mapped from, if it is shareable
+    pub fn file_offset(&self) -> Option<&FileOffset> {
+        self.file_offset.as_ref()
+    }
+
+    // Zero the region, preferably by giving the pages back to the host so
+    // that the next access reads zeroes. Locked memory and backings that
+    // can't drop pages are zeroed by hand.
+    fn discard(&self) {
+        self.dirty.mark_all();
+        if !self.locked {
+            let ret = match &self.file_offset {
+                // MADV_DONTNEED on a shared mapping would just read the
+                // file contents back in; punch them out of the file instead
+                Some(file_offset) => unsafe {
+                    libc::fallocate(
+                        file_offset.file.as_raw_fd(),
+                        libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                        file_offset.start as libc::off_t,
+                        self.size as libc::off_t,
+                    )
+                },
+                None => unsafe {
+                    libc::madvise(
+                        self.host_addr as *mut libc::c_void,
+                        self.size,
+                        libc::MADV_DONTNEED,
+                    )
+                },
+            };
+            if ret == 0 {
+                return;
+            }
+        }
+        unsafe { ptr::write_bytes(self.host_addr, 0, self.size) };
+    }
+}
+
+impl Drop for GuestRegion {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.host_addr as *mut libc::c_void, self.size);
+        }
+    }
+}
+
+// All of the guest's RAM: a set of non-overlapping regions, sorted by guest
+// physical address. Accesses are bounds checked and may span adjacent
+// regions, but never a hole between them.
+#[derive(Debug)]
+pub struct GuestMemory {
+    regions: Vec<GuestRegion>,
+}
+
+impl GuestMemory {
+    // Map one anonymous region per (guest base, size) range
+    pub fn new(ranges: &[(u64, usize)]) -> Result<Self> {
+        let regions = ranges
+            .iter()
+            .map(|&(base, size)| GuestRegion::new(base, size))
+            .collect::<Result<Vec<_>>>()?;
+        Self::from_regions(regions)
+    }
+
+    // Map one region per (guest base, size) range as described by
+    // `backing`. File backed regions are laid out back to back in a single
+    // file, in range order, so one fd describes all of guest RAM.
+    pub fn with_backing(ranges: &[(u64, usize)], backing: &MemoryBacking) -> Result<Self> {
+        let page_size = backing
+            .hugepages
+            .map_or(PAGE_SIZE as usize, |hugepages| hugepages.bytes());
+        for &(base, size) in ranges {
+            if base % page_size as u64 != 0 || size % page_size != 0 {
+                return Err(GuestMemoryError::InvalidBacking(format!(
+                    "region 0x{:x}+0x{:x} is not aligned to 0x{:x} byte pages",
+                    base, size, page_size
+                )));
+            }
+        }
+
+        let total = ranges.iter().map(|(_, size)| size).sum();
+        let file = match &backing.backend {
+            MemoryBackend::Anonymous => None,
+            MemoryBackend::Memfd => Some(create_memfd(total, backing.hugepages)?),
+            MemoryBackend::File(path) => {
+                if backing.hugepages.is_some() {
+                    return Err(GuestMemoryError::InvalidBacking(
+                        "use a file on hugetlbfs for huge page backed files".to_string(),
+                    ));
+                }
+                Some(open_backing_file(path, total)?)
+            }
+        }
+        .map(Arc::new);
+
+        let mut flags = 0;
+        if backing.populate {
+            flags |= libc::MAP_POPULATE;
+        }
+
+        let mut regions = Vec::with_capacity(ranges.len());
+        let mut file_start = 0;
+        for &(base, size) in ranges {
+            let mut region = match &file {
+                Some(file) => {
+                    let file_offset = FileOffset {
+                        file: file.clone(),
+                        start: file_start,
+                    };
+                    file_start += size as u64;
+                    GuestRegion::mmap(base, size, flags | libc::MAP_SHARED, Some(file_offset))?
+                }
+                None => {
+                    let mut flags = flags | libc::MAP_ANONYMOUS | libc::MAP_PRIVATE;
+                    if let Some(hugepages) = backing.hugepages {
+                        flags |= hugepages.mmap_flags();
+                    }
+                    GuestRegion::mmap(base, size, flags, None)?
+                }
+            };
+            if backing.mlock {
+                region.lock()?;
+            }
+            regions.push(region);
+        }
+        Self::from_regions(regions)
+    }
+
+    pub fn from_regions(mut regions: Vec<GuestRegion>) -> Result<Self> {
+        regions.sort_by_key(|region| region.guest_base());
+        for pair in regions.windows(2) {
+            if pair[1].guest_base() < pair[0].guest_end() {
+                return Err(GuestMemoryError::Overlap(pair[1].guest_base()));
+            }
+        }
+        Ok(GuestMemory { regions })
+    }
+
+    pub fn regions(&self) -> &[GuestRegion] {
+        &self.regions
+    }
+
+    // Total size of guest RAM in bytes
+    pub fn size(&self) -> usize {
+        self.regions.iter().map(|region| region.size()).sum()
+    }
+
+    // Last guest physical address backed by RAM, plus one
+    pub fn end(&self) -> u64 {
+        self.regions.last().map_or(0, |region| region.guest_end())
+    }
+
+    pub fn find_region(&self, addr: u64) -> Option<&GuestRegion> {
+        self.regions.iter().find(|region| region.contains(addr))
+    }
+
+    pub fn address_in_range(&self, addr: u64) -> bool {
+        self.find_region(addr).is_some()
+    }
+
+    // Host pointer for `len` bytes at `addr`, which must all lie in a single
+    // region. Use this only to hand memory to the kernel or device backends;
+    // prefer the read/write helpers otherwise. Writes through the pointer
+    // must be reported with mark_dirty().
+    pub fn get_host_address(&self, addr: u64, len: usize) -> Result<*mut u8> {
+        let invalid = GuestMemoryError::InvalidRange { addr, len };
+        let region = self.find_region(addr).ok_or(invalid)?;
+        let offset = (addr - region.guest_base()) as usize;
+        if offset
+            .checked_add(len)
+            .is_none_or(|end| end > region.size())
+        {
+            return Err(GuestMemoryError::InvalidRange { addr, len });
+        }
+        Ok(unsafe { region.host_addr().add(offset) })
+    }
+
+    // Run `f` for each piece of [addr, addr + len) with the region holding
+    // the piece, the piece's offset into that region, its offset into the
+    // access and its length
+    fn for_each_chunk<F>(&self, addr: u64, len: usize, mut f: F) -> Result<()>
+    where
+        F: FnMut(&GuestRegion, usize, usize, usize),
+    {
+        let invalid = GuestMemoryError::InvalidRange { addr, len };
+        if addr.checked_add(len as u64).is_none() {
+            return Err(invalid);
+        }
+        let mut done = 0;
+        while done < len {
+            let cur = addr + done as u64;
+            let region = match self.find_region(cur) {
+                Some(region) => region,
+                None => return Err(invalid),
+            };
+            let offset = (cur - region.guest_base()) as usize;
+            let count = (region.size() - offset).min(len - done);
+            f(region, offset, done, count);
+            done += count;
+        }
+        Ok(())
+    }
+
+    // Check that every byte of [addr, addr + len) is backed by RAM
+    pub fn check_range(&self, addr: u64, len: usize) -> Result<()> {
+        self.for_each_chunk(addr, len, |_, _, _, _| {})
+    }
+
+    // Record a write the VMM made through a host pointer
+    pub fn mark_dirty(&self, addr: u64, len: usize) -> Result<()> {
+        self.for_each_chunk(addr, len, |region, offset, _, count| {
+            region.dirty.mark(offset, count);
+        })
+    }
+
+    pub fn write_slice(&self, buf: &[u8], addr: u64) -> Result<()> {
+        // Validate first so a failed write leaves memory untouched
+        self.check_range(addr, buf.len())?;
+        self.for_each_chunk(addr, buf.len(), |region, offset, done, count| {
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    buf[done..].as_ptr(),
+                    region.host_addr().add(offset),
+                    count,
+                );
+            }
+            region.dirty.mark(offset, count);
+        })
+    }
+
+    pub fn read_slice(&self, buf: &mut [u8], addr: u64) -> Result<()> {
+        // Validate first so a failed read leaves `buf` untouched
+        self.check_range(addr, buf.len())?;
+        let len = buf.len();
+        self.for_each_chunk(addr, len, |region, offset, done, count| unsafe {
+            ptr::copy_nonoverlapping(
+                region.host_addr().add(offset),
+                buf[done..].as_mut_ptr(),
+                count,
+            );
+        })
+    }
+
+    pub fn write_obj<T: ByteValued>(&self, val: T, addr: u64) -> Result<()> {
+        self.write_slice(val.as_slice(), addr)
+    }
+
+    pub fn read_obj<T: ByteValued>(&self, addr: u64) -> Result<T> {
+        let mut val = T::default();
+        self.read_slice(val.as_mut_slice(), addr)?;
+        Ok(val)
+    }
+
+    // Fill [addr, addr + len) with zeroes
+    pub fn zero(&self, addr: u64, len: usize) -> Result<()> {
+        self.check_range(addr, len)?;
+        self.for_each_chunk(addr, len, |region, offset, _, count| {
+            unsafe { ptr::write_bytes(region.host_addr().add(offset), 0, count) };
+            region.dirty.mark(offset, count);
+        })
+    }
+
+    // Zero all of guest RAM, as on a cold boot
+    pub fn reset(&self) {
+        for region in &self.regions {
+            region.discard();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two adjacent regions, then a hole, then a third region
+    fn memory() -> GuestMemory {
+        GuestMemory::new(&[(0x0, 0x2000), (0x2000, 0x1000), (0x10000, 0x1000)]).unwrap()
+    }
+
+    #[test]
+    fn access_spans_adjacent_regions() {
+        let mem = memory();
+        let data: Vec<u8> = (0..0x20).collect();
+        mem.write_slice(&data, 0x1ff0).unwrap();
+        let mut buf = [0u8; 0x20];
+        mem.read_slice(&mut buf, 0x1ff0).unwrap();
+        assert_eq!(&buf[..], &data[..]);
+        mem.write_obj(0x1122_3344_5566_7788u64, 0x1ffc).unwrap();
+        assert_eq!(mem.read_obj::<u64>(0x1ffc).unwrap(), 0x1122_3344_5566_7788);
+        assert_eq!(mem.read_obj::<u32>(0x2000).unwrap(), 0x1122_3344);
+    }
+
+    #[test]
+    fn access_into_hole_fails() {
+        let mem = memory();
+        mem.write_slice(&[0xaa; 4], 0x2ffc).unwrap();
+        // Straddling the end of the second region
+        assert!(mem.write_slice(&[0x55; 8], 0x2ffc).is_err());
+        let mut buf = [0x11u8; 8];
+        assert!(mem.read_slice(&mut buf, 0x2ffc).is_err());
+        // Neither side of a failed access is touched
+        assert_eq!(buf, [0x11; 8]);
+        assert_eq!(mem.read_obj::<u32>(0x2ffc).unwrap(), 0xaaaa_aaaa);
+        // Entirely inside the hole or past the end of RAM
+        assert!(mem.read_obj::<u8>(0x8000).is_err());
+        assert!(mem.zero(0x8000, 1).is_err());
+        assert!(mem.read_obj::<u8>(mem.end()).is_err());
+        assert!(mem.get_host_address(0x1ff0, 0x20).is_err());
+    }
+
+    #[test]
+    fn access_overflowing_address_space_fails() {
+        let mem = memory();
+        let mut buf = [0u8; 4];
+        assert!(mem.read_slice(&mut buf, u64::MAX - 1).is_err());
+        assert!(mem.write_slice(&buf, u64::MAX - 1).is_err());
+        assert!(mem.check_range(0x1000, usize::MAX).is_err());
+        assert!(mem.get_host_address(0x1000, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn overlapping_regions_rejected() {
+        assert!(GuestMemory::new(&[(0x0, 0x2000), (0x1000, 0x1000)]).is_err());
+    }
+}
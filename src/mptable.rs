@@ -0,0 +1,189 @@
+// Intel MultiProcessor Specification 1.4 tables.
+//
+// Guests without ACPI discover their APs and the IOAPIC through the MP
+// floating pointer structure, which the OS scans for in the last KiB of
+// base memory.
+
+// Guest physical address the MP floating pointer is written to
+pub const MPTABLE_START: u64 = 0x9fc00;
+// Space reserved for the MP tables, up to the start of the VGA hole
+pub const MPTABLE_MAX_SIZE: usize = 0xa0000 - MPTABLE_START as usize;
+
+pub const APIC_DEFAULT_PHYS_BASE: u32 = 0xfee0_0000;
+pub const IO_APIC_DEFAULT_PHYS_BASE: u32 = 0xfec0_0000;
+
+const MP_SPEC_REV: u8 = 4;
+const APIC_VERSION: u8 = 0x14;
+const IOAPIC_VERSION: u8 = 0x11;
+const IOAPIC_PINS: u8 = 24;
+
+const MP_PROCESSOR: u8 = 0;
+const MP_BUS: u8 = 1;
+const MP_IOAPIC: u8 = 2;
+const MP_INTSRC: u8 = 3;
+const MP_LINTSRC: u8 = 4;
+
+const CPU_ENABLED: u8 = 1;
+const CPU_BOOTPROCESSOR: u8 = 2;
+const MPC_APIC_USABLE: u8 = 1;
+
+const MP_IRQ_SRC_INT: u8 = 0;
+const MP_IRQ_SRC_NMI: u8 = 1;
+const MP_IRQ_SRC_EXTINT: u8 = 3;
+// Polarity and trigger mode conform to the bus specification
+const MP_IRQ_DEFAULT: u16 = 0;
+
+const MPF_INTEL_SIZE: usize = 16;
+
+// Sum of all bytes, negated so that the structure sums to zero
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)).wrapping_neg()
+}
+
+fn push_u16(table: &mut Vec<u8>, v: u16) {
+    table.extend_from_slice(&v.to_le_bytes());
+}
+
+fn push_u32(table: &mut Vec<u8>, v: u32) {
+    table.extend_from_slice(&v.to_le_bytes());
+}
+
+// IOAPIC ids are only 4 bits wide, so they can't follow the CPUs' APIC ids.
+// 0 is what KVM's in-kernel IOAPIC reports.
+pub const IOAPIC_ID: u8 = 0;
+
+// Build the MP floating pointer followed by the MP configuration table for
+// `num_cpus` processors, laid out to be copied to MPTABLE_START.
+pub fn build(num_cpus: u8) -> Result<Vec<u8>, String> {
+    let mut table = Vec::new();
+    let config_start = MPTABLE_START as u32 + MPF_INTEL_SIZE as u32;
+
+    // MP floating pointer structure
+    table.extend_from_slice(b"_MP_");
+    push_u32(&mut table, config_start);
+    table.push(1); // length in 16-byte paragraphs
+    table.push(MP_SPEC_REV);
+    table.push(0); // checksum, filled in below
+    table.extend_from_slice(&[0; 5]); // feature bytes: config table present
+    table[10] = checksum(&table[..MPF_INTEL_SIZE]);
+
+    // Configuration table header, length and checksum filled in at the end
+    table.extend_from_slice(b"PCMP");
+    push_u16(&mut table, 0);
+    table.push(MP_SPEC_REV);
+    table.push(0);
+    table.extend_from_slice(b"VMMTHRDS");
+    table.extend_from_slice(b"000000000000");
+    push_u32(&mut table, 0); // OEM table pointer
+    push_u16(&mut table, 0); // OEM table size
+    push_u16(&mut table, 0); // entry count, filled in at the end
+    push_u32(&mut table, APIC_DEFAULT_PHYS_BASE);
+    push_u32(&mut table, 0); // extended table length, checksum, reserved
+    let mut entries: u16 = 0;
+
+    // One processor entry per vCPU; APIC ids match vCPU ids
+    for cpu in 0..num_cpus {
+        let mut flags = CPU_ENABLED;
+        if cpu == 0 {
+            flags |= CPU_BOOTPROCESSOR;
+        }
+        table.push(MP_PROCESSOR);
+        table.push(cpu);
+        table.push(APIC_VERSION);
+        table.push(flags);
+        push_u32(&mut table, 0x600); // CPU signature: family 6
+        push_u32(&mut table, 0x201); // feature flags: FPU, APIC
+        push_u32(&mut table, 0);
+        push_u32(&mut table, 0);
+        entries += 1;
+    }
+
+    // A single ISA bus
+    table.push(MP_BUS);
+    table.push(0);
+    table.extend_from_slice(b"ISA   ");
+    entries += 1;
+
+    table.push(MP_IOAPIC);
+    table.push(IOAPIC_ID);
+    table.push(IOAPIC_VERSION);
+    table.push(MPC_APIC_USABLE);
+    push_u32(&mut table, IO_APIC_DEFAULT_PHYS_BASE);
+    entries += 1;
+
+    // ISA IRQs are identity-mapped onto IOAPIC pins
+    for irq in 0..IOAPIC_PINS {
+        table.push(MP_INTSRC);
+        table.push(MP_IRQ_SRC_INT);
+        push_u16(&mut table, MP_IRQ_DEFAULT);
+        table.push(0); // source bus
+        table.push(irq);
+        table.push(IOAPIC_ID);
+        table.push(irq);
+        entries += 1;
+    }
+
+    // LINT0 is wired to the PIC, LINT1 to NMI, on every local APIC
+    for (irq_type, lint) in [(MP_IRQ_SRC_EXTINT, 0), (MP_IRQ_SRC_NMI, 1)] {
+        table.push(MP_LINTSRC);
+        table.push(irq_type);
+        push_u16(&mut table, MP_IRQ_DEFAULT);
+        table.push(0);
+        table.push(0);
+        table.push(0xff); // all local APICs
+        table.push(lint);
+        entries += 1;
+    }
+
+    if table.len() > MPTABLE_MAX_SIZE {
+        return Err(format!(
+            "MP table for {} CPUs does not fit in {} bytes",
+            num_cpus, MPTABLE_MAX_SIZE
+        ));
+    }
+
+    let config = &mut table[MPF_INTEL_SIZE..];
+    let config_len = config.len() as u16;
+    config[4..6].copy_from_slice(&config_len.to_le_bytes());
+    config[34..36].copy_from_slice(&entries.to_le_bytes());
+    config[7] = checksum(config);
+
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MAX_VCPUS;
+
+    fn sum(bytes: &[u8]) -> u8 {
+        bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+    }
+
+    #[test]
+    fn build_checksums_and_entries() {
+        for num_cpus in [1, 4, MAX_VCPUS] {
+            let table = build(num_cpus).unwrap();
+            let (mpf, config) = table.split_at(MPF_INTEL_SIZE);
+            assert_eq!(&mpf[..4], b"_MP_");
+            assert_eq!(sum(mpf), 0);
+            let config_start = u32::from_le_bytes(mpf[4..8].try_into().unwrap());
+            assert_eq!(config_start as u64, MPTABLE_START + MPF_INTEL_SIZE as u64);
+
+            assert_eq!(&config[..4], b"PCMP");
+            assert_eq!(sum(config), 0);
+            let config_len = u16::from_le_bytes([config[4], config[5]]);
+            assert_eq!(config_len as usize, config.len());
+            // CPUs, the bus, the IOAPIC, its pins and both LINTs
+            let entries = u16::from_le_bytes([config[34], config[35]]);
+            assert_eq!(entries, num_cpus as u16 + 2 + IOAPIC_PINS as u16 + 2);
+        }
+    }
+
+    #[test]
+    fn build_fits_below_vga_hole() {
+        let table = build(MAX_VCPUS).unwrap();
+        assert!(table.len() <= MPTABLE_MAX_SIZE);
+        assert!(MPTABLE_START + table.len() as u64 <= 0xa0000);
+    }
+}
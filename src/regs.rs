@@ -0,0 +1,275 @@
+// x86 register and descriptor table setup for entering a guest directly in
+// any of the CPU's operating modes, the way a boot loader would hand over to
+// a kernel.
+
+use kvm_bindings::{kvm_fpu, kvm_regs, kvm_segment, kvm_sregs};
+use kvm_ioctls::VcpuFd;
+
+use crate::layout::PAGE_SIZE;
+use crate::memory::GuestMemory;
+
+// Boot GDT and an empty IDT, in the real-mode area below the zero page
+pub const BOOT_GDT_START: u64 = 0x500;
+pub const BOOT_IDT_START: u64 = 0x520;
+// Identity-mapped page tables for the 32-bit address space, RAM and MMIO
+// alike: one PML4, one PDPT and four page directories of 2 MiB pages
+pub const PML4_START: u64 = 0x9000;
+pub const PDPT_START: u64 = 0xa000;
+pub const PD_START: u64 = 0xb000;
+const PD_COUNT: u64 = 4;
+// Initial stack, growing down from just below the page tables
+pub const BOOT_STACK_POINTER: u64 = 0x8ff0;
+const BOOT_STACK_SIZE: u64 = 0x1000;
+
+// What setup_boot_mode() and the boot stack take up, as (what, start, end).
+// Code loaded for the guest has to stay clear of it.
+pub const BOOT_AREAS: [(&str, u64, u64); 3] = [
+    ("boot GDT and IDT", BOOT_GDT_START, BOOT_IDT_START + 8),
+    ("boot stack", PML4_START - BOOT_STACK_SIZE, PML4_START),
+    (
+        "boot page tables",
+        PML4_START,
+        PD_START + PD_COUNT * PAGE_SIZE,
+    ),
+];
+
+const X86_CR0_PE: u64 = 0x1;
+const X86_CR0_NW: u64 = 0x2000_0000;
+const X86_CR0_CD: u64 = 0x4000_0000;
+const X86_CR0_PG: u64 = 0x8000_0000;
+const X86_CR4_PAE: u64 = 0x20;
+const EFER_LME: u64 = 0x100;
+const EFER_LMA: u64 = 0x400;
+
+// Initial x87 control word and SSE control/status, as after FNINIT and
+// with all SSE exceptions masked
+const FPU_FCW: u16 = 0x37f;
+const FPU_MXCSR: u32 = 0x1f80;
+
+// Page table entry bits
+const PTE_PRESENT: u64 = 0x1;
+const PTE_WRITABLE: u64 = 0x2;
+const PTE_HUGE: u64 = 0x80;
+
+// Flat 4 GiB segments: code, data, and a TSS that only exists because VMX
+// requires a usable TR. The long mode and protected mode tables differ in
+// the code segment's L/DB bits and the TSS type.
+const GDT_LONG_MODE: [u64; 4] = [
+    0,
+    gdt_entry(0xa09b, 0, 0xfffff),
+    gdt_entry(0xc093, 0, 0xfffff),
+    gdt_entry(0x808b, 0, 0xfffff),
+];
+const GDT_PROTECTED_MODE: [u64; 4] = [
+    0,
+    gdt_entry(0xc09b, 0, 0xfffff),
+    gdt_entry(0xc093, 0, 0xfffff),
+    gdt_entry(0x008b, 0, 0x67),
+];
+const GDT_PROTECTED_MODE_16: [u64; 4] = [
+    0,
+    gdt_entry(0x009b, 0, 0xffff),
+    gdt_entry(0x0093, 0, 0xffff),
+    gdt_entry(0x008b, 0, 0x67),
+];
+const GDT_CODE: u16 = 1;
+const GDT_DATA: u16 = 2;
+const GDT_TSS: u16 = 3;
+
+// CPU mode a vCPU is started in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BootMode {
+    // 16-bit real mode, code addressed through CS = entry >> 4
+    Real,
+    // 16-bit protected mode with 64 KiB segments at base 0
+    Protected16,
+    // 32-bit protected mode with flat 4 GiB segments, paging off
+    Protected32,
+    // 64-bit long mode with the low 4 GiB identity-mapped
+    #[default]
+    Long64,
+}
+
+impl BootMode {
+    // First address code in this mode can't reach from the boot segments
+    pub fn address_limit(self) -> u64 {
+        match self {
+            BootMode::Real => 0x10_0000,
+            BootMode::Protected16 => 0x1_0000,
+            BootMode::Protected32 | BootMode::Long64 => 0x1_0000_0000,
+        }
+    }
+}
+
+// Pack a segment descriptor. `flags` holds the access byte in bits 0-7 and
+// the G/DB/L/AVL nibble in bits 12-15.
+const fn gdt_entry(flags: u16, base: u32, limit: u32) -> u64 {
+    (((base as u64) & 0xff00_0000) << 32)
+        | (((flags as u64) & 0xf0ff) << 40)
+        | (((limit as u64) & 0x000f_0000) << 32)
+        | (((base as u64) & 0x00ff_ffff) << 16)
+        | ((limit as u64) & 0x0000_ffff)
+}
+
+// Unpack GDT entry `index` into the form KVM keeps segment registers in
+fn kvm_segment_from_gdt(entry: u64, index: u16) -> kvm_segment {
+    let granularity = ((entry >> 55) & 0x1) as u8;
+    let limit = ((entry >> 32) & 0x000f_0000) as u32 | (entry & 0x0000_ffff) as u32;
+    let present = ((entry >> 47) & 0x1) as u8;
+    kvm_segment {
+        base: ((entry >> 32) & 0xff00_0000) | ((entry >> 16) & 0x00ff_ffff),
+        limit: if granularity == 1 {
+            (limit << 12) | 0xfff
+        } else {
+            limit
+        },
+        selector: index * 8,
+        type_: ((entry >> 40) & 0xf) as u8,
+        present,
+        dpl: ((entry >> 45) & 0x3) as u8,
+        db: ((entry >> 54) & 0x1) as u8,
+        s: ((entry >> 44) & 0x1) as u8,
+        l: ((entry >> 53) & 0x1) as u8,
+        g: granularity,
+        avl: ((entry >> 52) & 0x1) as u8,
+        unusable: if present == 1 { 0 } else { 1 },
+        padding: 0,
+    }
+}
+
+// Write `gdt` and an empty IDT and load the flat segments from `gdt`
+fn setup_segments(
+    mem: &GuestMemory,
+    sregs: &mut kvm_sregs,
+    gdt: &[u64; 4],
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (index, entry) in gdt.iter().enumerate() {
+        mem.write_obj(*entry, BOOT_GDT_START + index as u64 * 8)?;
+    }
+    mem.write_obj(0u64, BOOT_IDT_START)?;
+
+    sregs.gdt.base = BOOT_GDT_START;
+    sregs.gdt.limit = (gdt.len() * 8 - 1) as u16;
+    sregs.idt.base = BOOT_IDT_START;
+    sregs.idt.limit = 7;
+
+    let data = kvm_segment_from_gdt(gdt[GDT_DATA as usize], GDT_DATA);
+    sregs.cs = kvm_segment_from_gdt(gdt[GDT_CODE as usize], GDT_CODE);
+    sregs.ds = data;
+    sregs.es = data;
+    sregs.fs = data;
+    sregs.gs = data;
+    sregs.ss = data;
+    sregs.tr = kvm_segment_from_gdt(gdt[GDT_TSS as usize], GDT_TSS);
+    Ok(())
+}
+
+// Identity-map the low 4 GiB with 2 MiB pages and turn on paging
+fn setup_page_tables(
+    mem: &GuestMemory,
+    sregs: &mut kvm_sregs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    mem.write_obj(PDPT_START | PTE_PRESENT | PTE_WRITABLE, PML4_START)?;
+    for dir in 0..PD_COUNT {
+        let pd = PD_START + dir * PAGE_SIZE;
+        mem.write_obj(pd | PTE_PRESENT | PTE_WRITABLE, PDPT_START + dir * 8)?;
+        for i in 0..512u64 {
+            let addr = ((dir << 9) | i) << 21;
+            mem.write_obj(addr | PTE_PRESENT | PTE_WRITABLE | PTE_HUGE, pd + i * 8)?;
+        }
+    }
+
+    sregs.cr3 = PML4_START;
+    sregs.cr4 |= X86_CR4_PAE;
+    sregs.cr0 |= X86_CR0_PE | X86_CR0_PG;
+    sregs.efer |= EFER_LME | EFER_LMA;
+    Ok(())
+}
+
+// Reset the FPU and SSE state to what a freshly initialized CPU would have
+fn setup_fpu(vcpu: &VcpuFd) -> Result<(), Box<dyn std::error::Error>> {
+    let fpu = kvm_fpu {
+        fcw: FPU_FCW,
+        mxcsr: FPU_MXCSR,
+        ..Default::default()
+    };
+    vcpu.set_fpu(&fpu)?;
+    Ok(())
+}
+
+// Switch a vCPU to 64-bit long mode: flat segments from the boot GDT,
+// identity-mapped paging, and CR0/CR3/CR4/EFER to match, with caching
+// enabled. The general registers are left to the caller, see entry_regs().
+pub fn setup_long_mode(vcpu: &VcpuFd, mem: &GuestMemory) -> Result<(), Box<dyn std::error::Error>> {
+    let mut sregs = vcpu.get_sregs()?;
+    setup_segments(mem, &mut sregs, &GDT_LONG_MODE)?;
+    setup_page_tables(mem, &mut sregs)?;
+    sregs.cr0 &= !(X86_CR0_CD | X86_CR0_NW);
+    vcpu.set_sregs(&sregs)?;
+    setup_fpu(vcpu)
+}
+
+// Switch a vCPU to 32-bit protected mode with paging off and flat
+// segments, as the PVH boot ABI requires
+pub fn setup_protected_mode(
+    vcpu: &VcpuFd,
+    mem: &GuestMemory,
+) -> Result<(), Box<dyn std::error::Error>> {
+    setup_protected_mode_with(vcpu, mem, &GDT_PROTECTED_MODE)
+}
+
+// Protected mode with paging off and the segments of `gdt`
+fn setup_protected_mode_with(
+    vcpu: &VcpuFd,
+    mem: &GuestMemory,
+    gdt: &[u64; 4],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut sregs = vcpu.get_sregs()?;
+    setup_segments(mem, &mut sregs, gdt)?;
+    sregs.cr0 = (sregs.cr0 | X86_CR0_PE) & !X86_CR0_PG;
+    sregs.cr4 = 0;
+    sregs.efer = 0;
+    vcpu.set_sregs(&sregs)?;
+    setup_fpu(vcpu)
+}
+
+// General registers for starting at `entry`: interrupts off, a boot stack,
+// everything else zero. Boot protocols add their arguments on top.
+pub fn entry_regs(entry: u64) -> kvm_regs {
+    kvm_regs {
+        rflags: 0x2,
+        rip: entry,
+        rsp: BOOT_STACK_POINTER,
+        rbp: BOOT_STACK_POINTER,
+        ..Default::default()
+    }
+}
+
+// Start a vCPU at `entry` in `mode`. The vCPU must be in its power-on
+// state, which real mode builds on.
+pub fn setup_boot_mode(
+    vcpu: &VcpuFd,
+    mem: &GuestMemory,
+    mode: BootMode,
+    entry: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut regs = entry_regs(entry);
+    match mode {
+        BootMode::Real => {
+            // Pick the highest 64 KiB aligned segment that contains entry
+            let mut sregs = vcpu.get_sregs()?;
+            let segment = (entry >> 4) as u16 & 0xf000;
+            sregs.cs.selector = segment;
+            sregs.cs.base = (segment as u64) << 4;
+            vcpu.set_sregs(&sregs)?;
+            regs.rip = entry - sregs.cs.base;
+            regs.rsp &= 0xffff;
+            regs.rbp &= 0xffff;
+        }
+        BootMode::Protected16 => setup_protected_mode_with(vcpu, mem, &GDT_PROTECTED_MODE_16)?,
+        BootMode::Protected32 => setup_protected_mode(vcpu, mem)?,
+        BootMode::Long64 => setup_long_mode(vcpu, mem)?,
+    }
+    vcpu.set_regs(&regs)?;
+    Ok(())
+}
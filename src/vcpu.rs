@@ -0,0 +1,565 @@
+use kvm_bindings::{
+    kvm_fpu, kvm_lapic_state, kvm_mp_state, kvm_msr_entry, kvm_regs, kvm_sregs, CpuId, Msrs,
+    KVM_MP_STATE_RUNNABLE, KVM_MP_STATE_UNINITIALIZED,
+};
+use kvm_ioctls::{VcpuExit, VcpuFd};
+use std::cell::Cell;
+use std::os::unix::thread::JoinHandleExt;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::bus::{MmioBus, PioBus};
+use crate::irq::IrqChip;
+
+// Why a vCPU stopped running guest code
+#[derive(Debug)]
+pub enum VcpuExitReason {
+    // The guest executed HLT. Only seen without an in-kernel local APIC,
+    // which otherwise handles HLT itself.
+    Halted,
+    // KVM_EXIT_SHUTDOWN, which is how a triple fault shows up on x86
+    Shutdown,
+    // The guest asked for a power off, reset or crash (KVM_SYSTEM_EVENT_*)
+    SystemEvent(u32),
+    // The VMM asked the vCPU to stop
+    Stopped,
+    // KVM returned an exit we don't know how to handle
+    Unexpected(String),
+    // The VMM dropped the vCPU's control channel
+    Detached,
+}
+
+// Control messages from the VMM to a vCPU thread
+#[derive(Debug)]
+pub enum VcpuEvent {
+    Pause,
+    Resume,
+    SaveState,
+    RestoreState(Box<VcpuState>),
+    // Leave the exit loop; no response is sent
+    Stop,
+}
+
+// Replies from a vCPU thread, one per VcpuEvent
+#[derive(Debug)]
+pub enum VcpuResponse {
+    Paused,
+    Resumed,
+    State(Box<VcpuState>),
+    StateRestored,
+    Error(kvm_ioctls::Error),
+}
+
+// Register state of a paused vCPU
+#[derive(Clone, Debug, Default)]
+pub struct VcpuState {
+    pub regs: kvm_regs,
+    pub sregs: kvm_sregs,
+}
+
+thread_local! {
+    // The vCPU owned by the current thread, for the kick signal handler
+    static TLS_VCPU_FD: Cell<Option<*const VcpuFd>> = const { Cell::new(None) };
+}
+
+// Real-time signal used to kick vCPU threads out of KVM_RUN
+pub fn kick_signal() -> libc::c_int {
+    libc::SIGRTMIN()
+}
+
+// Setting immediate_exit from the handler closes the race where the signal
+// lands while the thread is in userspace: the next KVM_RUN returns EINTR
+// straight away instead of going back into the guest.
+extern "C" fn handle_kick_signal(_: libc::c_int) {
+    TLS_VCPU_FD.with(|fd| {
+        if let Some(fd) = fd.get() {
+            unsafe { (*fd).set_kvm_immediate_exit(1) };
+        }
+    });
+}
+
+// Install the kick signal handler. Deliberately without SA_RESTART so the
+// signal interrupts KVM_RUN.
+pub fn register_kick_signal_handler() -> std::io::Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_kick_signal as *const () as usize;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(kick_signal(), &action, std::ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// Local APIC register offsets and LVT delivery modes
+const APIC_LVT0: usize = 0x350;
+const APIC_LVT1: usize = 0x360;
+const APIC_MODE_NMI: u32 = 0x4;
+const APIC_MODE_EXTINT: u32 = 0x7;
+
+// CPUID leaf 1: initial APIC id and logical processor count live in EBX
+const LEAF_FEATURES: u32 = 0x1;
+const EDX_HTT: u32 = 1 << 28;
+// CPUID leaves 0xb and 0x1f: one subleaf per topology level, with the
+// level's type in ECX[15:8], the x2APIC id shift to the next level in
+// EAX[4:0], the logical processor count in EBX[15:0] and the x2APIC id of
+// the current logical processor in EDX. Linux prefers 0x1f if present.
+const LEAF_EXT_TOPOLOGY: u32 = 0xb;
+const LEAF_V2_EXT_TOPOLOGY: u32 = 0x1f;
+const TOPOLOGY_LEVEL_INVALID: u32 = 0;
+const TOPOLOGY_LEVEL_SMT: u32 = 1;
+
+fn get_lapic_reg(lapic: &kvm_lapic_state, reg: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    for (dst, src) in bytes.iter_mut().zip(&lapic.regs[reg..reg + 4]) {
+        *dst = *src as u8;
+    }
+    u32::from_le_bytes(bytes)
+}
+
+fn set_lapic_reg(lapic: &mut kvm_lapic_state, reg: usize, value: u32) {
+    for (dst, src) in lapic.regs[reg..reg + 4].iter_mut().zip(value.to_le_bytes()) {
+        *dst = src as _;
+    }
+}
+
+// Outcome of a vCPU exit loop, as collected by the VMM when the thread is joined
+pub type VcpuResult = Result<VcpuExitReason, kvm_ioctls::Error>;
+
+// Tells the VMM that a vCPU thread is done, even if the exit loop panicked
+struct ExitNotifier<F: FnOnce(u64)> {
+    id: u64,
+    notify: Option<F>,
+}
+
+impl<F: FnOnce(u64)> Drop for ExitNotifier<F> {
+    fn drop(&mut self) {
+        if let Some(notify) = self.notify.take() {
+            notify(self.id);
+        }
+    }
+}
+
+// MSRs a guest reprograms while booting (EFER, syscall entry points, the
+// per-CPU GS base) plus the TSC, so a reboot starts from power-on values
+const RESET_MSRS: [u32; 10] = [
+    0x0000_0010, // IA32_TSC
+    0x0000_0174, // IA32_SYSENTER_CS
+    0x0000_0175, // IA32_SYSENTER_ESP
+    0x0000_0176, // IA32_SYSENTER_EIP
+    0xc000_0080, // EFER
+    0xc000_0081, // STAR
+    0xc000_0082, // LSTAR
+    0xc000_0083, // CSTAR
+    0xc000_0084, // SYSCALL_MASK
+    0xc000_0102, // KERNEL_GS_BASE
+];
+
+// Power-on state captured once the vCPU is configured, restored on reboot
+struct ResetState {
+    regs: kvm_regs,
+    sregs: kvm_sregs,
+    fpu: kvm_fpu,
+    msrs: Msrs,
+    lapic: Option<kvm_lapic_state>,
+}
+
+// A single virtual CPU
+pub struct Vcpu {
+    id: u64,
+    fd: VcpuFd,
+    reset_state: Option<ResetState>,
+    // Port I/O and MMIO exits go to the devices on these buses
+    pio_bus: Arc<PioBus>,
+    mmio_bus: Arc<MmioBus>,
+    // EOIs reported by KVM go to the interrupt controllers
+    irqchip: Option<IrqChip>,
+}
+
+impl Vcpu {
+    pub fn new(
+        id: u64,
+        fd: VcpuFd,
+        pio_bus: Arc<PioBus>,
+        mmio_bus: Arc<MmioBus>,
+        irqchip: Option<IrqChip>,
+    ) -> Self {
+        Vcpu {
+            id,
+            fd,
+            reset_state: None,
+            pio_bus,
+            mmio_bus,
+            irqchip,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn fd(&self) -> &VcpuFd {
+        &self.fd
+    }
+
+    // The bootstrap processor starts at the entry point, everything else is an AP
+    pub fn is_bsp(&self) -> bool {
+        self.id == 0
+    }
+
+    // Expose the host CPUID to the guest with this vCPU's APIC id and the
+    // VM's processor count patched in
+    pub fn configure_cpuid(&self, base: &CpuId, vcpu_count: u8) -> Result<(), kvm_ioctls::Error> {
+        let mut cpuid = base.clone();
+        let apic_id = self.id as u32;
+        // Bits of the x2APIC id that number the cores
+        let core_shift = (vcpu_count as u32).next_power_of_two().trailing_zeros();
+        for entry in cpuid.as_mut_slice() {
+            match entry.function {
+                LEAF_FEATURES => {
+                    entry.ebx =
+                        (entry.ebx & 0x0000_ffff) | (apic_id << 24) | ((vcpu_count as u32) << 16);
+                    if vcpu_count > 1 {
+                        entry.edx |= EDX_HTT;
+                    }
+                }
+                // One thread per core and every vCPU a core of the same
+                // package
+                LEAF_EXT_TOPOLOGY | LEAF_V2_EXT_TOPOLOGY => {
+                    entry.edx = apic_id;
+                    match (entry.ecx >> 8) & 0xff {
+                        TOPOLOGY_LEVEL_INVALID => {}
+                        TOPOLOGY_LEVEL_SMT => {
+                            entry.eax = 0;
+                            entry.ebx = 1;
+                        }
+                        _ => {
+                            entry.eax = core_shift;
+                            entry.ebx = vcpu_count as u32;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.fd.set_cpuid2(&cpuid)
+    }
+
+    // Wire LINT0 to the PIC (ExtINT) and LINT1 to NMI, as the MP table
+    // advertises. Requires the in-kernel local APIC.
+    pub fn configure_lapic(&self) -> Result<(), kvm_ioctls::Error> {
+        let mut lapic = self.fd.get_lapic()?;
+        for (reg, mode) in [(APIC_LVT0, APIC_MODE_EXTINT), (APIC_LVT1, APIC_MODE_NMI)] {
+            let value = get_lapic_reg(&lapic, reg);
+            set_lapic_reg(&mut lapic, reg, (value & !0x700) | (mode << 8));
+        }
+        self.fd.set_lapic(&lapic)
+    }
+
+    // The BSP runs straight away; APs stay parked in KVM until the guest
+    // sends them INIT/SIPI. Requires the in-kernel local APIC.
+    pub fn set_boot_mp_state(&self) -> Result<(), kvm_ioctls::Error> {
+        let mp_state = if self.is_bsp() {
+            KVM_MP_STATE_RUNNABLE
+        } else {
+            KVM_MP_STATE_UNINITIALIZED
+        };
+        self.fd.set_mp_state(kvm_mp_state { mp_state })
+    }
+
+    // Remember the current state as this vCPU's power-on state. The local
+    // APIC is only saved when it lives in the kernel.
+    pub fn capture_reset_state(&mut self, lapic_in_kernel: bool) -> Result<(), kvm_ioctls::Error> {
+        let lapic = if lapic_in_kernel {
+            Some(self.fd.get_lapic()?)
+        } else {
+            None
+        };
+        let entries: Vec<kvm_msr_entry> = RESET_MSRS
+            .iter()
+            .map(|&index| kvm_msr_entry {
+                index,
+                ..Default::default()
+            })
+            .collect();
+        let mut msrs =
+            Msrs::from_entries(&entries).map_err(|_| kvm_ioctls::Error::new(libc::ENOMEM))?;
+        if self.fd.get_msrs(&mut msrs)? != entries.len() {
+            return Err(kvm_ioctls::Error::new(libc::EIO));
+        }
+        self.reset_state = Some(ResetState {
+            regs: self.fd.get_regs()?,
+            sregs: self.fd.get_sregs()?,
+            fpu: self.fd.get_fpu()?,
+            msrs,
+            lapic,
+        });
+        Ok(())
+    }
+
+    // Put the vCPU back into its power-on state, as if the machine had been
+    // reset. APs go back to waiting for INIT/SIPI.
+    pub fn reset(&self) -> Result<(), kvm_ioctls::Error> {
+        let state = match &self.reset_state {
+            Some(state) => state,
+            None => return Err(kvm_ioctls::Error::new(libc::EINVAL)),
+        };
+        self.fd.set_sregs(&state.sregs)?;
+        self.fd.set_regs(&state.regs)?;
+        self.fd.set_fpu(&state.fpu)?;
+        if self.fd.set_msrs(&state.msrs)? != state.msrs.as_slice().len() {
+            return Err(kvm_ioctls::Error::new(libc::EIO));
+        }
+        if let Some(lapic) = &state.lapic {
+            self.fd.set_lapic(lapic)?;
+            self.set_boot_mp_state()?;
+        }
+        Ok(())
+    }
+
+    // Capture the registers needed to inspect or patch a paused vCPU
+    pub fn save_state(&self) -> Result<VcpuState, kvm_ioctls::Error> {
+        Ok(VcpuState {
+            regs: self.fd.get_regs()?,
+            sregs: self.fd.get_sregs()?,
+        })
+    }
+
+    pub fn restore_state(&self, state: &VcpuState) -> Result<(), kvm_ioctls::Error> {
+        self.fd.set_sregs(&state.sregs)?;
+        self.fd.set_regs(&state.regs)
+    }
+
+    // Enter the guest once and handle the resulting exit. Returns Some when
+    // the vCPU should stop running.
+    fn run_once(&mut self) -> Result<Option<VcpuExitReason>, kvm_ioctls::Error> {
+        match self.fd.run()? {
+            VcpuExit::Hlt => Ok(Some(VcpuExitReason::Halted)),
+            VcpuExit::IoIn(port, data) => {
+                // Nothing drives unclaimed ports, so they read as all ones
+                if !self.pio_bus.read(port, data) {
+                    data.fill(0xff);
+                }
+                Ok(None)
+            }
+            VcpuExit::IoOut(port, data) => {
+                self.pio_bus.write(port, data);
+                Ok(None)
+            }
+            VcpuExit::MmioRead(addr, data) => {
+                if !self.mmio_bus.read(addr, data) {
+                    data.fill(0xff);
+                }
+                Ok(None)
+            }
+            VcpuExit::MmioWrite(addr, data) => {
+                self.mmio_bus.write(addr, data);
+                Ok(None)
+            }
+            // Split irqchip only: the guest EOId a level-triggered vector
+            // of the userspace IOAPIC
+            VcpuExit::IoapicEoi(vector) => {
+                if let Some(irqchip) = &self.irqchip {
+                    irqchip.end_of_interrupt(vector)?;
+                }
+                Ok(None)
+            }
+            VcpuExit::Shutdown => Ok(Some(VcpuExitReason::Shutdown)),
+            VcpuExit::SystemEvent(event_type, _) => {
+                Ok(Some(VcpuExitReason::SystemEvent(event_type)))
+            }
+            unexpected => Ok(Some(VcpuExitReason::Unexpected(format!(
+                "{:?}",
+                unexpected
+            )))),
+        }
+    }
+
+    // Drain pending control events. While paused this blocks until the VMM
+    // resumes the vCPU. Returns Some when the vCPU should leave its exit loop.
+    fn handle_events(
+        &mut self,
+        events: &Receiver<VcpuEvent>,
+        responses: &Sender<VcpuResponse>,
+    ) -> Option<VcpuExitReason> {
+        let mut paused = false;
+        loop {
+            let event = if paused {
+                match events.recv() {
+                    Ok(event) => event,
+                    Err(_) => return Some(VcpuExitReason::Detached),
+                }
+            } else {
+                match events.try_recv() {
+                    Ok(event) => event,
+                    Err(TryRecvError::Empty) => return None,
+                    Err(TryRecvError::Disconnected) => return Some(VcpuExitReason::Detached),
+                }
+            };
+
+            let response = match event {
+                VcpuEvent::Pause => {
+                    paused = true;
+                    VcpuResponse::Paused
+                }
+                VcpuEvent::Resume => {
+                    paused = false;
+                    VcpuResponse::Resumed
+                }
+                VcpuEvent::SaveState => match self.save_state() {
+                    Ok(state) => VcpuResponse::State(Box::new(state)),
+                    Err(e) => VcpuResponse::Error(e),
+                },
+                VcpuEvent::RestoreState(state) => match self.restore_state(&state) {
+                    Ok(()) => VcpuResponse::StateRestored,
+                    Err(e) => VcpuResponse::Error(e),
+                },
+                VcpuEvent::Stop => return Some(VcpuExitReason::Stopped),
+            };
+            // Nobody waiting for the answer is not our problem
+            let _ = responses.send(response);
+        }
+    }
+
+    // The exit loop run on the vCPU thread: handle control events, then
+    // enter the guest, until the guest stops or the VMM goes away
+    fn run(&mut self, events: Receiver<VcpuEvent>, responses: Sender<VcpuResponse>) -> VcpuResult {
+        TLS_VCPU_FD.with(|fd| fd.set(Some(&self.fd as *const VcpuFd)));
+
+        let result = loop {
+            if let Some(reason) = self.handle_events(&events, &responses) {
+                break Ok(reason);
+            }
+            match self.run_once() {
+                Ok(None) => {}
+                Ok(Some(reason)) => break Ok(reason),
+                // Kicked out of KVM_RUN: go look at the control channel
+                Err(e) if e.errno() == libc::EINTR => self.fd.set_kvm_immediate_exit(0),
+                Err(e) => break Err(e),
+            }
+        };
+
+        TLS_VCPU_FD.with(|fd| fd.set(None));
+        result
+    }
+
+    // Move the vCPU onto its own named OS thread and start its exit loop there.
+    // The vCPU is handed back when the thread is joined so it can be run again.
+    // `on_exit` is called with its id once the exit loop is done.
+    pub fn start_threaded<F>(self, on_exit: F) -> std::io::Result<VcpuHandle>
+    where
+        F: FnOnce(u64) + Send + 'static,
+    {
+        let id = self.id;
+        let (event_sender, event_receiver) = channel();
+        let (response_sender, response_receiver) = channel();
+        let thread = thread::Builder::new()
+            .name(format!("vcpu{}", id))
+            .spawn(move || {
+                let _notifier = ExitNotifier {
+                    id,
+                    notify: Some(on_exit),
+                };
+                let mut vcpu = self;
+                let result = vcpu.run(event_receiver, response_sender);
+                (vcpu, result)
+            })?;
+
+        Ok(VcpuHandle {
+            id,
+            event_sender,
+            response_receiver,
+            thread,
+        })
+    }
+}
+
+// Handle to a vCPU running on its own thread
+pub struct VcpuHandle {
+    id: u64,
+    event_sender: Sender<VcpuEvent>,
+    response_receiver: Receiver<VcpuResponse>,
+    thread: JoinHandle<(Vcpu, VcpuResult)>,
+}
+
+impl VcpuHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // Force the vCPU out of KVM_RUN so it looks at its control channel
+    pub fn kick(&self) -> std::io::Result<()> {
+        let ret = unsafe { libc::pthread_kill(self.thread.as_pthread_t(), kick_signal()) };
+        if ret != 0 {
+            return Err(std::io::Error::from_raw_os_error(ret));
+        }
+        Ok(())
+    }
+
+    // Ask the vCPU to leave its exit loop without waiting for it; join()
+    // collects the result. Stopping a vCPU that already exited is a no-op.
+    pub fn stop(&self) {
+        if self.event_sender.send(VcpuEvent::Stop).is_ok() {
+            let _ = self.kick();
+        }
+    }
+
+    // Send an event, kick the vCPU and wait for its answer
+    fn request(&self, event: VcpuEvent) -> Result<VcpuResponse, String> {
+        self.event_sender
+            .send(event)
+            .map_err(|_| format!("vCPU {} is not running", self.id))?;
+        self.kick()
+            .map_err(|e| format!("Failed to kick vCPU {}: {}", self.id, e))?;
+        self.response_receiver
+            .recv()
+            .map_err(|_| format!("vCPU {} exited", self.id))
+    }
+
+    fn unexpected(&self, response: VcpuResponse) -> String {
+        match response {
+            VcpuResponse::Error(e) => format!("vCPU {}: {}", self.id, e),
+            response => format!("vCPU {}: unexpected response {:?}", self.id, response),
+        }
+    }
+
+    // Stop running guest code; returns once the vCPU is parked
+    pub fn pause(&self) -> Result<(), String> {
+        match self.request(VcpuEvent::Pause)? {
+            VcpuResponse::Paused => Ok(()),
+            response => Err(self.unexpected(response)),
+        }
+    }
+
+    pub fn resume(&self) -> Result<(), String> {
+        match self.request(VcpuEvent::Resume)? {
+            VcpuResponse::Resumed => Ok(()),
+            response => Err(self.unexpected(response)),
+        }
+    }
+
+    // Fetch the register state of a paused vCPU
+    pub fn save_state(&self) -> Result<VcpuState, String> {
+        match self.request(VcpuEvent::SaveState)? {
+            VcpuResponse::State(state) => Ok(*state),
+            response => Err(self.unexpected(response)),
+        }
+    }
+
+    // Overwrite the register state of a paused vCPU
+    pub fn restore_state(&self, state: VcpuState) -> Result<(), String> {
+        match self.request(VcpuEvent::RestoreState(Box::new(state)))? {
+            VcpuResponse::StateRestored => Ok(()),
+            response => Err(self.unexpected(response)),
+        }
+    }
+
+    // Wait for the vCPU thread to finish. Fails only if the thread panicked.
+    pub fn join(self) -> Result<(Vcpu, VcpuResult), String> {
+        self.thread
+            .join()
+            .map_err(|_| format!("vCPU {} thread panicked", self.id))
+    }
+}
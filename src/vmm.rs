@@ -0,0 +1,858 @@
+use kvm_bindings::{
+    kvm_enable_cap, kvm_pit_config, kvm_userspace_memory_region, KVM_CAP_SPLIT_IRQCHIP,
+    KVM_MAX_CPUID_ENTRIES, KVM_MEM_LOG_DIRTY_PAGES, KVM_PIT_SPEAKER_DUMMY, KVM_SYSTEM_EVENT_CRASH,
+    KVM_SYSTEM_EVENT_RESET, KVM_SYSTEM_EVENT_SHUTDOWN,
+};
+use kvm_ioctls::{Cap, Kvm, VmFd};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use crate::bus::{MmioBus, PioBus};
+use crate::config::{SerialOutput, VmConfig};
+use crate::devices::ioapic::{Ioapic, IOAPIC_NUM_PINS, IOAPIC_SIZE, IOAPIC_START};
+use crate::devices::serial::{Serial, StdinForwarder, COM1_IRQ, COM1_PORT, SERIAL_PORT_LEN};
+use crate::devices::virtio::block::Block;
+use crate::devices::virtio::mmio::{
+    self, MmioTransport, QueueNotifier, REG_QUEUE_NOTIFY, VIRTIO_MMIO_SIZE,
+};
+use crate::devices::virtio::{VirtioDevice, VirtioInterrupt};
+use crate::devices::Interrupt;
+use crate::dirty::DirtyTracker;
+use crate::e820::E820Map;
+use crate::eventfd::{IoEventFd, IoEventSpace, IrqFd};
+use crate::gsi::GsiRouter;
+use crate::irq::{IrqChip, IrqChipMode, IrqLine, KernelIrqChipState};
+use crate::layout::{MemoryLayout, MemoryLayoutBuilder};
+use crate::loader::{self, pvh, BootProtocol};
+use crate::memory::GuestMemory;
+use crate::mptable;
+use crate::regs;
+use crate::vcpu::{self, Vcpu, VcpuExitReason, VcpuHandle, VcpuResult};
+
+// Three pages below the 4 GiB boundary, out of the way of guest RAM and MMIO
+const KVM_TSS_ADDRESS: usize = 0xfffb_d000;
+
+// ISA IRQs no emulated device uses, for virtio-mmio devices. They reach
+// the guest through the PIC as well as the IOAPIC.
+const VIRTIO_MMIO_IRQS: [u32; 10] = [5, 6, 7, 9, 10, 11, 12, 13, 14, 15];
+
+// Lifecycle of a VM:
+//
+//   Created --setup_vcpu--> Configured --start--> Running <--pause/resume--> Paused
+//      ^                                             |                         |
+//      +----------------reboot----------------- Stopped <--------wait---------+
+//
+// While wait() blocks, other threads pause, resume and stop the VM through
+// a VmController.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmState {
+    // vCPUs and memory exist, but no guest is loaded
+    Created,
+    // The guest is loaded and the BSP points at its entry
+    Configured,
+    // vCPU threads are executing guest code
+    Running,
+    // vCPU threads exist but are parked outside the guest
+    Paused,
+    // All vCPU threads have exited
+    Stopped,
+}
+
+impl fmt::Display for VmState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+// Why the VM stopped running
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ShutdownReason {
+    // Every vCPU executed HLT. Only without an in-kernel irqchip: with KVM's
+    // local APICs a halted vCPU waits in the kernel for an interrupt instead
+    // of exiting, see IrqChipMode.
+    AllHalted,
+    // A vCPU triple faulted (KVM_EXIT_SHUTDOWN). This is also how an x86
+    // guest resets itself, so it can be rebooted like Reset.
+    TripleFault(u64),
+    // The guest asked to be powered off. x86 KVM never reports this: without
+    // ACPI a guest powers off by halting its vCPUs, which with an irqchip
+    // never exit, so such a VM hangs until stop() instead.
+    PowerOff,
+    // The guest asked to be reset; the VM can be rebooted
+    Reset,
+    // The guest reported a crash
+    Crash,
+    // The VMM stopped the VM
+    Stopped,
+    // A vCPU failed or hit an exit we can't handle
+    Error(u64, String),
+}
+
+// How a run of the VM ended, with the exit result of every vCPU
+#[derive(Debug)]
+pub struct VmExit {
+    pub reason: ShutdownReason,
+    pub vcpu_results: Vec<(u64, VcpuResult)>,
+}
+
+// What a VmController can ask of the VM
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum VmRequest {
+    Pause,
+    Resume,
+    Stop,
+}
+
+// Everything wait() reacts to, in the order it happened
+enum VmEvent {
+    // A vCPU thread's exit loop ended
+    VcpuExited(u64),
+    // A VmController request, answered on the sender
+    Request(VmRequest, Sender<Result<(), String>>),
+}
+
+// Pauses, resumes or stops the VM from any thread. Requests are carried out
+// by wait() and fail while nothing waits for the VM.
+#[derive(Clone)]
+pub struct VmController {
+    events: Sender<VmEvent>,
+    // Whether wait() is running to serve requests
+    serving: Arc<Mutex<bool>>,
+}
+
+impl VmController {
+    pub fn pause(&self) -> Result<(), String> {
+        self.request(VmRequest::Pause)
+    }
+
+    pub fn resume(&self) -> Result<(), String> {
+        self.request(VmRequest::Resume)
+    }
+
+    pub fn stop(&self) -> Result<(), String> {
+        self.request(VmRequest::Stop)
+    }
+
+    fn request(&self, request: VmRequest) -> Result<(), String> {
+        let (reply_sender, reply_receiver) = channel();
+        {
+            // Held while sending so wait() can't stop serving in between
+            let serving = self.serving.lock().unwrap();
+            if !*serving {
+                return Err("Nothing is waiting for the VM".to_string());
+            }
+            self.events
+                .send(VmEvent::Request(request, reply_sender))
+                .map_err(|_| "The VMM is gone".to_string())?;
+        }
+        reply_receiver
+            .recv()
+            .map_err(|_| "The VMM is gone".to_string())?
+    }
+}
+
+// Map a single vCPU's exit onto a VM-wide shutdown. Halted vCPUs only
+// shut the VM down once all of them have halted, so they map to None.
+fn shutdown_reason(id: u64, result: &VcpuResult) -> Option<ShutdownReason> {
+    match result {
+        Ok(VcpuExitReason::Halted) => None,
+        Ok(VcpuExitReason::Shutdown) => Some(ShutdownReason::TripleFault(id)),
+        Ok(VcpuExitReason::SystemEvent(KVM_SYSTEM_EVENT_SHUTDOWN)) => {
+            Some(ShutdownReason::PowerOff)
+        }
+        Ok(VcpuExitReason::SystemEvent(KVM_SYSTEM_EVENT_RESET)) => Some(ShutdownReason::Reset),
+        Ok(VcpuExitReason::SystemEvent(KVM_SYSTEM_EVENT_CRASH)) => Some(ShutdownReason::Crash),
+        Ok(VcpuExitReason::SystemEvent(event_type)) => Some(ShutdownReason::Error(
+            id,
+            format!("Unknown system event {}", event_type),
+        )),
+        Ok(VcpuExitReason::Stopped) | Ok(VcpuExitReason::Detached) => Some(ShutdownReason::Stopped),
+        Ok(VcpuExitReason::Unexpected(exit)) => Some(ShutdownReason::Error(
+            id,
+            format!("Unexpected exit: {}", exit),
+        )),
+        Err(e) => Some(ShutdownReason::Error(id, format!("vCPU run failed: {}", e))),
+    }
+}
+
+// Struct to encapsulate VMM state
+pub struct Vmm {
+    // Shared with devices that raise interrupts through it
+    vm: Arc<VmFd>,
+    // Present unless the VM runs without interrupt controllers
+    irqchip: Option<IrqChip>,
+    // Power-on state of the in-kernel PICs, IOAPIC and PIT, for reboot()
+    irqchip_reset_state: Option<KernelIrqChipState>,
+    // KVM's GSI routing table; present with an irqchip
+    gsi_router: Option<Arc<GsiRouter>>,
+    config: VmConfig,
+    state: VmState,
+    // vCPUs live here while stopped and move onto their threads in start()
+    vcpus: Vec<Vcpu>,
+    vcpu_handles: Vec<VcpuHandle>,
+    // vCPU exits and controller requests, for wait()
+    event_sender: Sender<VmEvent>,
+    event_receiver: Receiver<VmEvent>,
+    // Shared with VmControllers: whether wait() takes requests
+    serving: Arc<Mutex<bool>>,
+    memory_layout: MemoryLayout,
+    e820: E820Map,
+    // Shared with the devices that access guest memory
+    guest_memory: Arc<GuestMemory>,
+    dirty_tracker: DirtyTracker,
+    // Device buses, shared with every vCPU thread
+    pio_bus: Arc<PioBus>,
+    mmio_bus: Arc<MmioBus>,
+    // Feeds stdin to the serial console; stopped when the VMM is dropped
+    stdin_forwarder: Option<StdinForwarder>,
+    // (MMIO base, IRQ) of every virtio-mmio device, in the order added
+    virtio_mmio_devices: Vec<(u64, u32)>,
+    // Threads serving the virtio-mmio queue doorbells, one per device
+    queue_notifiers: Vec<QueueNotifier>,
+}
+
+impl Vmm {
+    // Initialize the VMM
+    pub fn new(config: VmConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        config.validate()?;
+
+        // Open KVM
+        let kvm = Kvm::new()?;
+        if !kvm.check_extension(Cap::ImmediateExit) {
+            return Err("KVM_CAP_IMMEDIATE_EXIT is required to kick vCPUs".into());
+        }
+        vcpu::register_kick_signal_handler()?;
+
+        // Create a VM
+        let vm = Arc::new(kvm.create_vm()?);
+        if config.vcpu_count as usize > kvm.get_max_vcpus() {
+            return Err(format!(
+                "KVM supports at most {} vCPUs, {} requested",
+                kvm.get_max_vcpus(),
+                config.vcpu_count
+            )
+            .into());
+        }
+        vm.set_tss_address(KVM_TSS_ADDRESS)?;
+
+        // APs can only be started by INIT/SIPI from another vCPU, which needs
+        // the in-kernel local APIC, and a kernel needs interrupt controllers
+        // and a timer. They have to exist before any vCPU does.
+        let irqchip_mode = config.irqchip_mode();
+        match irqchip_mode {
+            IrqChipMode::Kernel => {
+                vm.create_irq_chip()?;
+                // The PC speaker port reads as silent instead of exiting to us
+                let pit_config = kvm_pit_config {
+                    flags: KVM_PIT_SPEAKER_DUMMY,
+                    ..Default::default()
+                };
+                vm.create_pit2(pit_config)?;
+            }
+            IrqChipMode::Split => {
+                if !vm.check_extension(Cap::SplitIrqchip) {
+                    return Err("KVM does not support a split irqchip".into());
+                }
+                // Reserve a GSI route for each IOAPIC pin
+                let mut cap = kvm_enable_cap {
+                    cap: KVM_CAP_SPLIT_IRQCHIP,
+                    ..Default::default()
+                };
+                cap.args[0] = IOAPIC_NUM_PINS as u64;
+                vm.enable_cap(&cap)?;
+            }
+            IrqChipMode::Auto | IrqChipMode::None => {}
+        }
+
+        // Allocate guest memory around the 32-bit MMIO gap
+        let memory_layout = MemoryLayoutBuilder::new(config.mem_size).build()?;
+        let e820 = E820Map::from_layout(&memory_layout)?;
+        let guest_memory = Arc::new(GuestMemory::with_backing(
+            memory_layout.ram_regions(),
+            &config.memory_backing,
+        )?);
+
+        // Register memory with KVM, one slot per region
+        for (slot, region) in guest_memory.regions().iter().enumerate() {
+            let mem_region = kvm_userspace_memory_region {
+                slot: slot as u32,
+                flags: KVM_MEM_LOG_DIRTY_PAGES,
+                guest_phys_addr: region.guest_base(),
+                memory_size: region.size() as u64,
+                userspace_addr: region.host_addr() as u64,
+            };
+            unsafe {
+                vm.set_user_memory_region(mem_region)?;
+            }
+        }
+
+        let pio_bus = Arc::new(PioBus::new());
+        let mmio_bus = Arc::new(MmioBus::new(&memory_layout));
+        let (irqchip, gsi_router) = match irqchip_mode {
+            IrqChipMode::Kernel => {
+                let gsi_router = Arc::new(GsiRouter::new_kernel(vm.clone()));
+                (Some(IrqChip::new(vm.clone())), Some(gsi_router))
+            }
+            IrqChipMode::Split => {
+                let gsi_router = Arc::new(GsiRouter::new_split(vm.clone()));
+                let ioapic = Arc::new(Mutex::new(Ioapic::new(vm.clone(), gsi_router.clone())));
+                mmio_bus.insert(ioapic.clone(), IOAPIC_START, IOAPIC_SIZE)?;
+                (
+                    Some(IrqChip::new_split(vm.clone(), ioapic)),
+                    Some(gsi_router),
+                )
+            }
+            IrqChipMode::Auto | IrqChipMode::None => (None, None),
+        };
+        let irqchip_reset_state = match &irqchip {
+            Some(irqchip) => irqchip.save_state()?,
+            None => None,
+        };
+
+        // Create the vCPUs; APIC ids follow the vCPU ids and each vCPU gets
+        // its own thread in run()
+        let base_cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)?;
+        let mut vcpus = Vec::with_capacity(config.vcpu_count as usize);
+        for id in 0..config.vcpu_count as u64 {
+            let mut vcpu = Vcpu::new(
+                id,
+                vm.create_vcpu(id)?,
+                pio_bus.clone(),
+                mmio_bus.clone(),
+                irqchip.clone(),
+            );
+            vcpu.configure_cpuid(&base_cpuid, config.vcpu_count)?;
+            if irqchip.is_some() {
+                vcpu.configure_lapic()?;
+                vcpu.set_boot_mp_state()?;
+            }
+            vcpu.capture_reset_state(irqchip.is_some())?;
+            vcpus.push(vcpu);
+        }
+
+        let (event_sender, event_receiver) = channel();
+        let dirty_tracker = DirtyTracker::new(&guest_memory);
+        let mut vmm = Vmm {
+            irqchip,
+            irqchip_reset_state,
+            gsi_router,
+            vm,
+            config,
+            state: VmState::Created,
+            vcpus,
+            vcpu_handles: Vec::new(),
+            event_sender,
+            event_receiver,
+            serving: Arc::new(Mutex::new(false)),
+            memory_layout,
+            e820,
+            guest_memory,
+            dirty_tracker,
+            pio_bus,
+            mmio_bus,
+            stdin_forwarder: None,
+            virtio_mmio_devices: Vec::new(),
+            queue_notifiers: Vec::new(),
+        };
+        vmm.write_platform_tables()?;
+        vmm.setup_serial()?;
+        vmm.setup_block_devices()?;
+
+        Ok(vmm)
+    }
+
+    // Put a 16550A on COM1, writing to the configured output and reading
+    // from stdin if asked to
+    fn setup_serial(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let out: Box<dyn Write + Send> = match &self.config.serial.output {
+            SerialOutput::Stdout => Box::new(io::stdout()),
+            SerialOutput::File(path) => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?,
+            ),
+        };
+        let interrupt = match self.irqchip {
+            Some(_) => Some(self.register_interrupt(COM1_IRQ)?),
+            None => None,
+        };
+        let serial = Arc::new(Mutex::new(Serial::new(interrupt, out)));
+        self.pio_bus
+            .insert(serial.clone(), COM1_PORT, SERIAL_PORT_LEN)?;
+        if self.config.serial.stdin {
+            self.stdin_forwarder = Some(StdinForwarder::spawn(serial)?);
+        }
+        Ok(())
+    }
+
+    // Attach a virtio-blk device for each configured disk
+    fn setup_block_devices(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for config in self.config.block_devices.clone() {
+            let block = Block::new(&config)
+                .map_err(|e| format!("Failed to open {}: {}", config.path.display(), e))?;
+            self.add_virtio_mmio_device(Box::new(block))?;
+        }
+        Ok(())
+    }
+
+    pub fn state(&self) -> VmState {
+        self.state
+    }
+
+    // Move to state `to`, provided the VM is currently in one of `from`
+    fn transition(
+        &mut self,
+        from: &[VmState],
+        to: VmState,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !from.contains(&self.state) {
+            return Err(format!("Cannot go from {} to {}", self.state, to).into());
+        }
+        self.state = to;
+        Ok(())
+    }
+
+    // Pull the pages dirtied since the last sync, by the guest or by the VMM,
+    // into the dirty tracker's current round
+    pub fn sync_dirty_log(&mut self) -> Result<&DirtyTracker, Box<dyn std::error::Error>> {
+        self.dirty_tracker.sync(&self.vm, &self.guest_memory)?;
+        Ok(&self.dirty_tracker)
+    }
+
+    pub fn dirty_tracker(&self) -> &DirtyTracker {
+        &self.dirty_tracker
+    }
+
+    // Start a new dirty tracking round, e.g. after a migration pass
+    pub fn reset_dirty_log(&mut self) {
+        self.dirty_tracker.reset();
+    }
+
+    // Firmware-style tables the guest expects to find in memory
+    fn write_platform_tables(&self) -> Result<(), Box<dyn std::error::Error>> {
+        // Let the guest find its APs and the IOAPIC. In split mode there
+        // is no PIC to fall back on, so the IOAPIC has to be found.
+        if self.config.vcpu_count > 1 || self.config.irqchip_mode() == IrqChipMode::Split {
+            let table = mptable::build(self.config.vcpu_count)?;
+            self.guest_memory
+                .write_slice(&table, mptable::MPTABLE_START)?;
+        }
+        Ok(())
+    }
+
+    // Where RAM and the MMIO gap are, for boot loaders and the E820 map
+    pub fn memory_layout(&self) -> &MemoryLayout {
+        &self.memory_layout
+    }
+
+    // The memory map handed to the guest
+    pub fn e820(&self) -> &E820Map {
+        &self.e820
+    }
+
+    pub fn guest_memory(&self) -> &GuestMemory {
+        &self.guest_memory
+    }
+
+    // Interrupt controllers, to raise interrupts with; None when the VM
+    // runs without
+    pub fn irqchip(&self) -> Option<&IrqChip> {
+        self.irqchip.as_ref()
+    }
+
+    // GSI routing, to allocate GSIs for devices' MSIs; None without an
+    // irqchip
+    pub fn gsi_router(&self) -> Option<&Arc<GsiRouter>> {
+        self.gsi_router.as_ref()
+    }
+
+    // Give a device an EventFd that raises edge-triggered `gsi` when
+    // written, from any thread. In split mode this bypasses the userspace
+    // IOAPIC: writes while the guest has the pin masked are lost.
+    pub fn register_irqfd(&self, gsi: u32) -> Result<IrqFd, Box<dyn std::error::Error>> {
+        if self.irqchip.is_none() {
+            return Err("irqfds need an irqchip".into());
+        }
+        Ok(IrqFd::register(self.vm.clone(), gsi)?)
+    }
+
+    // An edge-triggered interrupt on `gsi` for a device: an irqfd with KVM's
+    // IOAPIC, a line into the userspace one in split mode
+    pub fn register_interrupt(
+        &self,
+        gsi: u32,
+    ) -> Result<Box<dyn Interrupt>, Box<dyn std::error::Error>> {
+        match &self.irqchip {
+            Some(irqchip) if irqchip.is_split() => Ok(Box::new(IrqLine::new(irqchip.clone(), gsi))),
+            _ => Ok(Box::new(self.register_irqfd(gsi)?)),
+        }
+    }
+
+    // Give a device an EventFd signalled by guest writes to `addr`, or
+    // only by writes of `datamatch` if given, instead of a bus exit
+    pub fn register_ioevent(
+        &self,
+        space: IoEventSpace,
+        addr: u64,
+        datamatch: Option<u32>,
+    ) -> Result<IoEventFd, Box<dyn std::error::Error>> {
+        Ok(IoEventFd::register(
+            self.vm.clone(),
+            space,
+            addr,
+            datamatch,
+        )?)
+    }
+
+    // The I/O port bus, to attach devices to
+    pub fn pio_bus(&self) -> &Arc<PioBus> {
+        &self.pio_bus
+    }
+
+    // The MMIO bus, to attach devices to. Device ranges must stay clear of
+    // guest RAM, KVM never exits for accesses there.
+    pub fn mmio_bus(&self) -> &Arc<MmioBus> {
+        &self.mmio_bus
+    }
+
+    // Attach `device` through a virtio-mmio transport in the next free page
+    // of the MMIO gap, interrupting on the next free IRQ. Returns the
+    // device's MMIO base and IRQ; kernels find it on their command line.
+    pub fn add_virtio_mmio_device(
+        &mut self,
+        device: Box<dyn VirtioDevice>,
+    ) -> Result<(u64, u32), Box<dyn std::error::Error>> {
+        let index = self.virtio_mmio_devices.len();
+        let irq = *VIRTIO_MMIO_IRQS
+            .get(index)
+            .ok_or("No IRQ left for another virtio-mmio device")?;
+        let (gap_start, _) = self.memory_layout.mmio_gap();
+        let base = gap_start + index as u64 * VIRTIO_MMIO_SIZE;
+
+        let interrupt = Arc::new(VirtioInterrupt::new(self.register_interrupt(irq)?));
+        let transport = MmioTransport::new(self.guest_memory.clone(), device, interrupt);
+        // Queue notifications skip the bus, one doorbell per queue
+        let doorbells = (0..transport.queue_count() as u32)
+            .map(|queue| {
+                self.register_ioevent(IoEventSpace::Mmio, base + REG_QUEUE_NOTIFY, Some(queue))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let transport = Arc::new(Mutex::new(transport));
+        self.mmio_bus
+            .insert(transport.clone(), base, VIRTIO_MMIO_SIZE)?;
+        self.queue_notifiers
+            .push(QueueNotifier::spawn(transport, doorbells)?);
+        self.virtio_mmio_devices.push((base, irq));
+        Ok((base, irq))
+    }
+
+    // The configured command line plus what the kernel needs to find the
+    // virtio-mmio devices
+    pub fn kernel_cmdline(&self) -> String {
+        let mut cmdline = self.config.cmdline.clone();
+        for (base, irq) in &self.virtio_mmio_devices {
+            cmdline.push(' ');
+            cmdline.push_str(&mmio::cmdline_param(*base, *irq));
+        }
+        cmdline
+    }
+
+    // Load the guest and point the BSP at its entry point: the configured
+    // kernel if there is one, the test payload otherwise. APs are
+    // left alone: the guest starts them with INIT/SIPI.
+    pub fn setup_vcpu(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.state != VmState::Created {
+            return Err(format!("Cannot set up vCPUs while VM is {}", self.state).into());
+        }
+        match self.config.kernel.clone() {
+            Some(path) => self.load_kernel(&path)?,
+            None => self.load_payload()?,
+        }
+        self.transition(&[VmState::Created], VmState::Configured)
+    }
+
+    // Load a bzImage or ELF kernel with its initrd, command line and boot
+    // structures, and start the BSP at the kernel's entry point
+    fn load_kernel(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let mut image = File::open(path)
+            .map_err(|e| format!("Failed to open kernel {}: {}", path.display(), e))?;
+        let kernel = loader::load_kernel(&self.guest_memory, &self.memory_layout, &mut image)?;
+        let initrd = match &self.config.initrd {
+            Some(path) => {
+                let mut image = File::open(path)
+                    .map_err(|e| format!("Failed to open initrd {}: {}", path.display(), e))?;
+                Some(loader::load_initrd(
+                    &self.guest_memory,
+                    &self.memory_layout,
+                    &kernel,
+                    &mut image,
+                )?)
+            }
+            None => None,
+        };
+        loader::load_cmdline(
+            &self.guest_memory,
+            loader::CMDLINE_START,
+            &self.kernel_cmdline(),
+            kernel.cmdline_max_size(),
+        )?;
+
+        let bsp = self
+            .vcpus
+            .iter()
+            .find(|vcpu| vcpu.is_bsp())
+            .ok_or("No BSP to boot the kernel on")?;
+        match kernel.protocol {
+            BootProtocol::Linux => {
+                loader::setup_zero_page(
+                    &self.guest_memory,
+                    &self.e820,
+                    &kernel,
+                    loader::CMDLINE_START,
+                    initrd.as_ref(),
+                    loader::ZERO_PAGE_START,
+                )?;
+                regs::setup_long_mode(bsp.fd(), &self.guest_memory)?;
+                let mut regs = regs::entry_regs(kernel.entry_addr);
+                regs.rsi = loader::ZERO_PAGE_START;
+                bsp.fd().set_regs(&regs)?;
+            }
+            BootProtocol::Pvh => {
+                pvh::setup_start_info(
+                    &self.guest_memory,
+                    &self.e820,
+                    loader::CMDLINE_START,
+                    initrd.as_ref(),
+                    pvh::PVH_INFO_START,
+                )?;
+                regs::setup_protected_mode(bsp.fd(), &self.guest_memory)?;
+                let mut regs = regs::entry_regs(kernel.entry_addr);
+                regs.rbx = pvh::PVH_INFO_START;
+                bsp.fd().set_regs(&regs)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Load the configured payload and start the BSP on it in the
+    // payload's boot mode
+    fn load_payload(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let payload = &self.config.payload;
+        for vcpu in self.vcpus.iter().filter(|vcpu| vcpu.is_bsp()) {
+            regs::setup_boot_mode(
+                vcpu.fd(),
+                &self.guest_memory,
+                payload.boot_mode,
+                payload.load_addr,
+            )?;
+        }
+        self.guest_memory
+            .write_slice(&payload.code, payload.load_addr)?;
+        Ok(())
+    }
+
+    // Spawn one thread per vCPU, wait for the VM to shut down and collect
+    // each vCPU's exit result
+    pub fn run(&mut self) -> Result<VmExit, Box<dyn std::error::Error>> {
+        self.start()?;
+        self.wait()
+    }
+
+    // Spawn one thread per vCPU and return while they run
+    pub fn start(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.transition(&[VmState::Configured], VmState::Running)?;
+        for vcpu in self.vcpus.drain(..) {
+            let events = self.event_sender.clone();
+            let handle = vcpu.start_threaded(move |id| {
+                // The VMM not listening any more is fine
+                let _ = events.send(VmEvent::VcpuExited(id));
+            })?;
+            self.vcpu_handles.push(handle);
+        }
+        Ok(())
+    }
+
+    // Wait for the VM to shut down, serving VmController requests meanwhile.
+    // The first vCPU to exit for any reason other than HLT brings the whole
+    // VM down: the remaining vCPUs are stopped and every thread is joined.
+    pub fn wait(&mut self) -> Result<VmExit, Box<dyn std::error::Error>> {
+        if !matches!(self.state, VmState::Running | VmState::Paused) {
+            return Err(format!("Cannot wait for a VM that is {}", self.state).into());
+        }
+        *self.serving.lock().unwrap() = true;
+        let exit = self.collect_vcpus();
+        self.stop_serving();
+        exit
+    }
+
+    // Join the vCPU threads as they exit, until none is left
+    fn collect_vcpus(&mut self) -> Result<VmExit, Box<dyn std::error::Error>> {
+        let mut reason = None;
+        let mut vcpu_results = Vec::with_capacity(self.vcpu_handles.len());
+        while !self.vcpu_handles.is_empty() {
+            let id = match self.event_receiver.recv()? {
+                VmEvent::VcpuExited(id) => id,
+                VmEvent::Request(request, reply) => {
+                    let _ = reply.send(self.handle_request(request));
+                    continue;
+                }
+            };
+            let index = match self.vcpu_handles.iter().position(|h| h.id() == id) {
+                Some(index) => index,
+                // Left over from an earlier run
+                None => continue,
+            };
+            let (vcpu, result) = self.vcpu_handles.swap_remove(index).join()?;
+
+            if reason.is_none() {
+                reason = shutdown_reason(id, &result);
+                if reason.is_some() {
+                    for handle in &self.vcpu_handles {
+                        handle.stop();
+                    }
+                }
+            }
+            self.vcpus.push(vcpu);
+            vcpu_results.push((id, result));
+        }
+
+        self.vcpus.sort_by_key(|vcpu| vcpu.id());
+        vcpu_results.sort_by_key(|(id, _)| *id);
+        self.state = VmState::Stopped;
+        Ok(VmExit {
+            reason: reason.unwrap_or(ShutdownReason::AllHalted),
+            vcpu_results,
+        })
+    }
+
+    // Turn other threads' requests into calls, for wait()
+    fn handle_request(&mut self, request: VmRequest) -> Result<(), String> {
+        match request {
+            VmRequest::Pause => self.pause(),
+            VmRequest::Resume => self.resume(),
+            VmRequest::Stop => self.stop(),
+        }
+        .map_err(|e| e.to_string())
+    }
+
+    // Turn new requests away and fail the ones that came in while the VM
+    // stopped, so nobody waits for an answer forever
+    fn stop_serving(&self) {
+        let mut serving = self.serving.lock().unwrap();
+        *serving = false;
+        let mut exited = Vec::new();
+        while let Ok(event) = self.event_receiver.try_recv() {
+            match event {
+                VmEvent::VcpuExited(id) => exited.push(id),
+                VmEvent::Request(_, reply) => {
+                    let _ = reply.send(Err(format!("The VM is {}", self.state)));
+                }
+            }
+        }
+        // Still needed if wait() failed with vCPUs left
+        for id in exited {
+            let _ = self.event_sender.send(VmEvent::VcpuExited(id));
+        }
+    }
+
+    // A handle to pause, resume or stop the VM from other threads
+    pub fn controller(&self) -> VmController {
+        VmController {
+            events: self.event_sender.clone(),
+            serving: self.serving.clone(),
+        }
+    }
+
+    // Ask every vCPU to leave the guest; wait() then reports
+    // ShutdownReason::Stopped
+    pub fn stop(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if !matches!(self.state, VmState::Running | VmState::Paused) {
+            return Err(format!("Cannot stop a VM that is {}", self.state).into());
+        }
+        for handle in &self.vcpu_handles {
+            handle.stop();
+        }
+        Ok(())
+    }
+
+    // Kick every running vCPU out of the guest and park it. Once this
+    // returns no vCPU executes guest code until resume(). If a vCPU doesn't
+    // park, the others are let go again and the VM keeps running.
+    pub fn pause(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.state != VmState::Running {
+            return Err(format!("Cannot pause a VM that is {}", self.state).into());
+        }
+        for (index, handle) in self.vcpu_handles.iter().enumerate() {
+            if let Err(e) = handle.pause() {
+                for handle in &self.vcpu_handles[..index] {
+                    let _ = handle.resume();
+                }
+                return Err(e.into());
+            }
+        }
+        self.transition(&[VmState::Running], VmState::Paused)
+    }
+
+    pub fn resume(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.state != VmState::Paused {
+            return Err(format!("Cannot resume a VM that is {}", self.state).into());
+        }
+        for handle in &self.vcpu_handles {
+            handle.resume()?;
+        }
+        self.transition(&[VmState::Paused], VmState::Running)
+    }
+
+    // Control handle of a running vCPU, to inspect or modify its state
+    // while the VM is paused
+    pub fn vcpu_handle(&self, id: u64) -> Option<&VcpuHandle> {
+        self.vcpu_handles.iter().find(|handle| handle.id() == id)
+    }
+
+    // Reset a stopped VM to its power-on state, reusing the KVM VM, vCPU
+    // file descriptors and guest memory mapping, and load the guest again.
+    // Interrupt controllers and the devices on both buses are reset too.
+    pub fn reboot(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.state != VmState::Stopped {
+            return Err(format!("Cannot reboot a VM that is {}", self.state).into());
+        }
+
+        for vcpu in &self.vcpus {
+            vcpu.reset()?;
+        }
+        if let (Some(irqchip), Some(state)) = (&self.irqchip, &self.irqchip_reset_state) {
+            irqchip.restore_state(state)?;
+        }
+        self.pio_bus.reset();
+        self.mmio_bus.reset();
+        self.guest_memory.reset();
+        self.write_platform_tables()?;
+
+        self.state = VmState::Created;
+        self.setup_vcpu()
+    }
+
+    // Clean up resources
+    fn cleanup(&mut self) {
+        // vCPU threads must be gone before the memory they run on
+        for handle in &self.vcpu_handles {
+            handle.stop();
+        }
+        for handle in self.vcpu_handles.drain(..) {
+            let _ = handle.join();
+        }
+        self.queue_notifiers.clear();
+    }
+}
+
+impl Drop for Vmm {
+    fn drop(&mut self) {
+        self.cleanup();
+    }
+}